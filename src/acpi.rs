@@ -0,0 +1,103 @@
+/* Just enough ACPI to find the tables we need. The firmware leaves a Root System Description Pointer (RSDP) in the
+ * first KiB of the EBDA or in the BIOS area at 0xE0000-0xFFFFF; it points to the RSDT (32-bit entries) or, from
+ * ACPI 2.0 on, the XSDT (64-bit entries), which lists the physical address of every other table. Every table starts
+ * with the same 36-byte header and is valid if its bytes sum to zero.
+ *
+ * Tables are read in place through the physical memory mapping, so this only works after memory::init.
+ * https://wiki.osdev.org/RSDP, https://wiki.osdev.org/RSDT
+ */
+pub mod madt; // Multiple APIC Description Table: interrupt controllers and how ISA IRQs are wired to them
+
+use core::ptr;
+use spin::Once;
+use x86_64::PhysAddr;
+use crate::memory::phys_to_virt;
+
+pub use madt::Madt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+static_assert_layout!(SdtHeader, 36, 1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    // No valid RSDP in the EBDA or the BIOS area
+    NoRsdp,
+    BadChecksum(PhysAddr),
+    TableNotFound([u8; 4]),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RootTable {
+    addr: PhysAddr,
+    // 4 for the RSDT, 8 for the XSDT
+    entry_size: u64,
+}
+
+static ROOT: Once<Result<RootTable, AcpiError>> = Once::new();
+
+// The physical address of the first table with the given signature (e.g. b"APIC" for the MADT)
+pub fn find_table(signature: &[u8; 4]) -> Result<PhysAddr, AcpiError> {
+    let root = (*ROOT.call_once(find_root))?;
+    let header: SdtHeader = read(root.addr);
+    let entries = (header.length as u64 - HEADER_SIZE) / root.entry_size;
+    for i in 0..entries {
+        let entry = root.addr + HEADER_SIZE + i * root.entry_size;
+        let table = PhysAddr::new(if root.entry_size == 8 { read::<u64>(entry) } else { read::<u32>(entry) as u64 });
+        if &read::<SdtHeader>(table).signature == signature {
+            let length = read::<SdtHeader>(table).length as u64;
+            if !checksum_ok(table, length) {
+                return Err(AcpiError::BadChecksum(table));
+            }
+            return Ok(table);
+        }
+    }
+    Err(AcpiError::TableNotFound(*signature))
+}
+
+pub(crate) const HEADER_SIZE: u64 = core::mem::size_of::<SdtHeader>() as u64;
+
+fn find_root() -> Result<RootTable, AcpiError> {
+    let rsdp = find_rsdp().ok_or(AcpiError::NoRsdp)?;
+    let revision: u8 = read(rsdp + 15u64);
+    let (addr, entry_size) = if revision >= 2 {
+        (PhysAddr::new(read::<u64>(rsdp + 24u64)), 8)
+    } else {
+        (PhysAddr::new(read::<u32>(rsdp + 16u64) as u64), 4)
+    };
+    let length = read::<SdtHeader>(addr).length as u64;
+    if !checksum_ok(addr, length) {
+        return Err(AcpiError::BadChecksum(addr));
+    }
+    Ok(RootTable { addr, entry_size })
+}
+
+// The RSDP is 16-byte aligned, starts with "RSD PTR " and its first 20 bytes sum to zero
+fn find_rsdp() -> Option<PhysAddr> {
+    // The BIOS data area stores the EBDA's segment at 0x40E
+    let ebda = (read::<u16>(PhysAddr::new(0x40e)) as u64) << 4;
+    let ebda_range = if ebda != 0 { ebda..ebda + 1024 } else { 0..0 };
+    ebda_range.chain(0xe0000..0x100000).step_by(16)
+        .map(PhysAddr::new)
+        .find(|&addr| &read::<[u8; 8]>(addr) == b"RSD PTR " && checksum_ok(addr, 20))
+}
+
+pub(crate) fn checksum_ok(addr: PhysAddr, len: u64) -> bool {
+    (0..len).fold(0u8, |sum, i| sum.wrapping_add(read::<u8>(addr + i))) == 0
+}
+
+// Reads a (possibly unaligned) value from physical memory
+pub(crate) fn read<T: Copy>(addr: PhysAddr) -> T {
+    unsafe { ptr::read_unaligned(phys_to_virt(addr).as_ptr::<T>()) }
+}
@@ -0,0 +1,161 @@
+/* The MADT (signature "APIC") describes the interrupt controllers: the local APIC of every CPU, the IOAPICs, and
+ * interrupt source overrides for ISA IRQs that aren't wired to the IOAPIC input with the same number (on QEMU and
+ * most PCs the PIT's IRQ 0 arrives on GSI 2, for example).
+ *
+ * Entries are read from the table in place whenever they're iterated, so there is no limit on how many CPUs or IOAPICs
+ * the firmware can describe, and nothing here needs the heap (this runs before, and independently of, it).
+ * https://wiki.osdev.org/MADT
+ */
+use spin::Once;
+use x86_64::PhysAddr;
+use super::{find_table, read, AcpiError, SdtHeader, HEADER_SIZE};
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicEntry {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    // Bit 0: the CPU is enabled. Bit 1: it can be brought online.
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: PhysAddr,
+    // The first global system interrupt (GSI) this IOAPIC handles
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    // The legacy 8259 PICs are present too and have to be masked before using the APICs
+    pub has_8259: bool,
+    table: PhysAddr,
+    length: u64,
+}
+
+impl Madt {
+    pub fn local_apics(&self) -> impl Iterator<Item = LocalApicEntry> + '_ {
+        self.entries(ENTRY_LOCAL_APIC).map(|entry| LocalApicEntry {
+            processor_id: read(entry + 2u64),
+            apic_id: read(entry + 3u64),
+            flags: read(entry + 4u64),
+        })
+    }
+
+    pub fn io_apics(&self) -> impl Iterator<Item = IoApicEntry> + '_ {
+        self.entries(ENTRY_IO_APIC).map(|entry| IoApicEntry {
+            id: read(entry + 2u64),
+            address: PhysAddr::new(read::<u32>(entry + 4u64) as u64),
+            gsi_base: read(entry + 8u64),
+        })
+    }
+
+    pub fn overrides(&self) -> impl Iterator<Item = InterruptOverride> + '_ {
+        self.entries(ENTRY_OVERRIDE).map(|entry| {
+            let flags: u16 = read(entry + 8u64);
+            InterruptOverride {
+                irq: read(entry + 3u64),
+                gsi: read(entry + 4u64),
+                // 0b00 means "conforms to the bus", which for ISA is active high and edge triggered
+                polarity: if flags & 0b11 == 0b11 { Polarity::ActiveLow } else { Polarity::ActiveHigh },
+                trigger: if (flags >> 2) & 0b11 == 0b11 { TriggerMode::Level } else { TriggerMode::Edge },
+            }
+        })
+    }
+
+    // The addresses of the entries of type `kind`
+    fn entries(&self, kind: u8) -> impl Iterator<Item = PhysAddr> + '_ {
+        Entries { table: self.table, offset: FIRST_ENTRY, length: self.length }
+            .filter(move |&entry| read::<u8>(entry) == kind)
+    }
+
+    /* Where ISA IRQ `irq` arrives: its GSI, polarity and trigger mode. Without an override, ISA interrupts are
+     * identity mapped, active high and edge triggered.
+     */
+    pub fn isa_irq(&self, irq: u8) -> InterruptOverride {
+        self.overrides().find(|o| o.irq == irq).unwrap_or(InterruptOverride {
+            irq,
+            gsi: irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger: TriggerMode::Edge,
+        })
+    }
+}
+
+static MADT: Once<Result<Madt, AcpiError>> = Once::new();
+
+// Finds and parses the MADT the first time it's called
+pub fn madt() -> Result<&'static Madt, AcpiError> {
+    MADT.call_once(parse).as_ref().map_err(|&e| e)
+}
+
+// Variable length entries follow the header and two 32-bit fields (local APIC address, flags)
+const FIRST_ENTRY: u64 = HEADER_SIZE + 8;
+
+fn parse() -> Result<Madt, AcpiError> {
+    let table = find_table(b"APIC")?;
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(read::<u32>(table + HEADER_SIZE) as u64),
+        has_8259: read::<u32>(table + HEADER_SIZE + 4u64) & 1 != 0,
+        table,
+        length: read::<SdtHeader>(table).length as u64,
+    };
+    // 64-bit local APIC address override
+    if let Some(entry) = madt.entries(ENTRY_LOCAL_APIC_ADDRESS).next() {
+        madt.local_apic_address = PhysAddr::new(read(entry + 4u64));
+    }
+    Ok(madt)
+}
+
+// Walks the entries of the table, each starting with (type, length)
+struct Entries {
+    table: PhysAddr,
+    offset: u64,
+    length: u64,
+}
+
+impl Iterator for Entries {
+    type Item = PhysAddr;
+
+    fn next(&mut self) -> Option<PhysAddr> {
+        if self.offset + 2 > self.length {
+            return None;
+        }
+        let entry = self.table + self.offset;
+        let entry_length = read::<u8>(entry + 1u64) as u64;
+        // A zero length entry would have us loop forever; treat it as the end of the table
+        if entry_length < 2 {
+            self.offset = self.length;
+            return None;
+        }
+        self.offset += entry_length;
+        Some(entry)
+    }
+}
@@ -0,0 +1,267 @@
+/* The kernel heap. We map a small range of virtual memory at boot and hand it to our own linked list allocator
+ * (see linked_list.rs), which `alloc` uses for Box, Vec, and friends via the #[global_allocator] attribute. The heap
+ * grows on demand, in steps of at least HEAP_GROW_STEP, up to a limit that defaults to HEAP_MAX_SIZE (set_heap_limit).
+ *
+ * Out of memory policy: when an allocation doesn't fit, we first merge adjacent free blocks and retry. If that fails
+ * and growing is enabled (set_grow_on_oom, on by default), we map more pages right after the end of the heap and
+ * retry once more.
+ * Only then does the allocation fail, and the alloc_error_handler in lib.rs reports the failed layout together with
+ * the state of the heap (report_oom) on both VGA and serial before panicking.
+ */
+pub mod linked_list;
+#[cfg(feature = "leak-detect")]
+pub mod leak; // Records live allocations so tests can check that they free everything
+
+#[cfg(feature = "leak-detect")]
+pub use leak::{report_leaks, LeakReport};
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{fmt, ptr};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use linked_list::{AllocateError, FragmentationStats, LinkedListAllocator};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::structures::paging::mapper::MapToError;
+use crate::memory::{self, Permissions, Region, RegionKind};
+use crate::units::fmt_bytes;
+
+// Far away from the kernel, the physical memory window and kmap, so it's easy to recognise in page faults
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+// Mapped at boot
+pub const HEAP_SIZE: usize = 32 * 1024; // 32 KiB
+// Default upper bound for growth
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
+// Growing a page at a time would mean a trip through the page tables for almost every large allocation
+const HEAP_GROW_STEP: usize = 64 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+
+// End of the mapped heap; grows when grow_heap maps more pages
+static HEAP_END: AtomicUsize = AtomicUsize::new(HEAP_START);
+static GROW_ON_OOM: AtomicBool = AtomicBool::new(true);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+// Maps the heap's pages and hands them to the allocator
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    let page_range = {
+        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start_page = Page::<Size4KiB>::containing_address(heap_start);
+        let heap_end_page = Page::<Size4KiB>::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+
+    memory::with_page_tables(|mapper, frame_allocator| -> Result<(), MapToError<Size4KiB>> {
+        for page in page_range {
+            let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        }
+        Ok(())
+    })?;
+    memory::VMM.lock().insert(Region::new("kernel heap", VirtAddr::new(HEAP_START as u64), HEAP_SIZE as u64,
+        RegionKind::Heap, Permissions::READ_WRITE))
+        .expect("failed to register the heap region");
+
+    // Unsafe because the range must be unused, which we just made sure of by mapping it
+    unsafe { ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE) };
+    HEAP_END.store(HEAP_START + HEAP_SIZE, Ordering::SeqCst);
+    Ok(())
+}
+
+// Whether allocations that don't fit should map more heap pages before failing
+pub fn set_grow_on_oom(enabled: bool) {
+    GROW_ON_OOM.store(enabled, Ordering::SeqCst);
+}
+
+/* Caps how large the heap may grow, in bytes (rounded up to whole pages). The heap never shrinks, so a limit below
+ * the current size just stops further growth.
+ */
+pub fn set_heap_limit(bytes: usize) {
+    HEAP_LIMIT.store(align_up(bytes, 4096), Ordering::SeqCst);
+}
+
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::SeqCst)
+}
+
+// Number of bytes currently mapped for the heap
+pub fn heap_size() -> usize {
+    HEAP_END.load(Ordering::SeqCst) - HEAP_START
+}
+
+#[derive(Debug)]
+pub enum GrowError {
+    // The page tables were locked (we're inside a mapping operation) or memory::init hasn't run
+    PageTablesBusy,
+    // Growing would take the heap past heap_limit()
+    LimitReached,
+    // Something else is registered right after the heap
+    OutOfVirtualSpace,
+    MapTo(MapToError<Size4KiB>),
+}
+
+/* Maps at least `min_bytes` more memory at the end of the heap and gives it to the allocator. Returns the number of
+ * bytes added, which is rounded up to HEAP_GROW_STEP where the limit allows. Never blocks on the page table locks, so
+ * it's safe to call from the allocation path.
+ */
+pub fn grow_heap(min_bytes: usize) -> Result<usize, GrowError> {
+    let old_end = HEAP_END.load(Ordering::SeqCst);
+    let room = heap_limit().saturating_sub(old_end - HEAP_START);
+    let needed = align_up(min_bytes.max(1), 4096);
+    if needed > room {
+        return Err(GrowError::LimitReached);
+    }
+    let bytes = align_up(needed, HEAP_GROW_STEP).min(room);
+
+    // Take the new range in the VMA registry first, so nothing else can be mapped there meanwhile
+    {
+        let mut vmm = memory::VMM.try_lock().ok_or(GrowError::PageTablesBusy)?;
+        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let region = vmm.remove(heap_start).expect("the heap region is missing");
+        let size = (old_end + bytes - HEAP_START) as u64;
+        let grown = Region::new(region.name, heap_start, size, region.kind, region.permissions);
+        if vmm.insert(grown).is_err() {
+            vmm.insert(region).expect("failed to restore the heap region");
+            return Err(GrowError::OutOfVirtualSpace);
+        }
+    }
+
+    let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(old_end as u64));
+    let end_page = Page::<Size4KiB>::containing_address(VirtAddr::new((old_end + bytes - 1) as u64));
+    let mapped = memory::try_with_page_tables(|mapper, frame_allocator| -> Result<(), MapToError<Size4KiB>> {
+        for page in Page::range_inclusive(start_page, end_page) {
+            // Left over from an earlier attempt that ran out of frames half way
+            if mapper.translate_page(page).is_ok() {
+                continue;
+            }
+            let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        }
+        Ok(())
+    });
+    match mapped {
+        Some(Ok(())) => {}
+        // Pages mapped before a failure stay mapped and registered; they'll be reused by the next attempt
+        Some(Err(err)) => return Err(GrowError::MapTo(err)),
+        None => return Err(GrowError::PageTablesBusy),
+    }
+
+    unsafe { ALLOCATOR.lock().extend(old_end, bytes) };
+    HEAP_END.store(old_end + bytes, Ordering::SeqCst);
+    Ok(bytes)
+}
+
+// Prints the failed allocation and the state of the heap (to the screen and serial, like everything printed)
+pub fn report_oom(layout: Layout) {
+    let report = OomReport { layout, heap_size: heap_size(), heap_limit: heap_limit(), stats: stats() };
+    crate::println!("{}", report);
+}
+
+struct OomReport {
+    layout: Layout,
+    heap_size: usize,
+    heap_limit: usize,
+    stats: FragmentationStats,
+}
+
+impl fmt::Display for OomReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "out of memory: failed to allocate {} bytes (align {}); heap {} of {} mapped, {} free in {} blocks, \
+            largest {} ({}% fragmented)",
+            self.layout.size(), self.layout.align(), fmt_bytes(self.heap_size as u64), fmt_bytes(self.heap_limit as u64),
+            fmt_bytes(self.stats.free_bytes as u64), self.stats.free_blocks,
+            fmt_bytes(self.stats.largest_free_block as u64), self.stats.fragmentation_percent())
+    }
+}
+
+// Current fragmentation of the heap's free list
+pub fn stats() -> FragmentationStats {
+    ALLOCATOR.lock().stats()
+}
+
+// Merges adjacent free blocks. Returns the fragmentation (before, after).
+pub fn defragment() -> (FragmentationStats, FragmentationStats) {
+    ALLOCATOR.lock().defragment()
+}
+
+// A spin::Mutex wrapper that lets us implement GlobalAlloc (whose methods take &self) for our allocators
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked { inner: spin::Mutex::new(inner) }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<A> {
+        self.inner.lock()
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc_with_policy(layout);
+        #[cfg(feature = "leak-detect")]
+        {
+            if !ptr.is_null() {
+                leak::track(ptr as usize, layout.size());
+            }
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "leak-detect")]
+        leak::untrack(ptr as usize);
+        let (size, _) = LinkedListAllocator::size_align(layout);
+        self.lock().deallocate(ptr as usize, size)
+    }
+}
+
+impl Locked<LinkedListAllocator> {
+    // The allocation path of GlobalAlloc::alloc, including the out of memory policy
+    unsafe fn alloc_with_policy(&self, layout: Layout) -> *mut u8 {
+        // try_alloc has released the lock by now, so the panic path can allocate (and report the heap) without
+        // deadlocking on it
+        match self.try_alloc(layout) {
+            Ok(addr) => addr as *mut u8,
+            Err(AllocateError::NoFit) => ptr::null_mut(),
+            Err(AllocateError::UseAfterFree { addr, found }) => {
+                panic!("heap use-after-free: byte at {:#x} was modified after being freed (found {:#x})", addr, found)
+            }
+        }
+    }
+
+    fn try_alloc(&self, layout: Layout) -> Result<usize, AllocateError> {
+        let (size, align) = LinkedListAllocator::size_align(layout);
+        let mut allocator = self.lock();
+        match allocator.allocate(size, align) {
+            Err(AllocateError::NoFit) => {}
+            result => return result,
+        }
+        // Memory pressure: the request may still fit once neighbouring free blocks are merged
+        allocator.defragment();
+        match allocator.allocate(size, align) {
+            Err(AllocateError::NoFit) => {}
+            result => return result,
+        }
+        if !GROW_ON_OOM.load(Ordering::SeqCst) {
+            return Err(AllocateError::NoFit);
+        }
+        // grow_heap takes the allocator lock itself; the padding covers the worst case alignment
+        drop(allocator);
+        match grow_heap(size + align) {
+            Ok(_) => self.lock().allocate(size, align),
+            Err(_) => Err(AllocateError::NoFit),
+        }
+    }
+}
+
+// Aligns `addr` upwards to `align`, which must be a power of two
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
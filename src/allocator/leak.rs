@@ -0,0 +1,101 @@
+/* Heap leak detector, compiled in with the `leak-detect` feature. Every live allocation is recorded in a fixed side
+ * table together with its size and the return addresses of the frames that requested it, and forgotten again when
+ * it's freed. Whatever is still in the table at the end of a test was leaked (or is meant to live forever).
+ *
+ * The table can't live on the heap (we're inside the allocator), so it has a fixed capacity; allocations that don't
+ * fit are only counted. Return addresses come from walking the frame pointer chain, which the target spec keeps
+ * intact ("eliminate-frame-pointer": false). Resolve them with `addr2line -e <kernel binary>`.
+ */
+use spin::Mutex;
+
+const MAX_TRACKED: usize = 1024;
+// Return addresses recorded per allocation
+const CALLERS: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+struct Allocation {
+    addr: usize,
+    size: usize,
+    callers: [usize; CALLERS],
+}
+
+struct LeakTable {
+    entries: [Option<Allocation>; MAX_TRACKED],
+    // Allocations that happened while the table was full
+    untracked: usize,
+}
+
+static TABLE: Mutex<LeakTable> = Mutex::new(LeakTable { entries: [None; MAX_TRACKED], untracked: 0 });
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LeakReport {
+    // Live allocations in the table
+    pub allocations: usize,
+    pub bytes: usize,
+    // Live allocations that didn't fit in the table, so they can't be listed
+    pub untracked: usize,
+}
+
+impl LeakReport {
+    pub fn is_balanced(&self) -> bool {
+        self.allocations == 0 && self.untracked == 0
+    }
+}
+
+pub(super) fn track(addr: usize, size: usize) {
+    let callers = callers();
+    let mut table = TABLE.lock();
+    match table.entries.iter_mut().find(|entry| entry.is_none()) {
+        Some(slot) => *slot = Some(Allocation { addr, size, callers }),
+        None => table.untracked += 1,
+    }
+}
+
+pub(super) fn untrack(addr: usize) {
+    let mut table = TABLE.lock();
+    match table.entries.iter_mut().find(|entry| entry.map_or(false, |a| a.addr == addr)) {
+        Some(slot) => *slot = None,
+        // Must have been one of the untracked ones
+        None => table.untracked = table.untracked.saturating_sub(1),
+    }
+}
+
+// Prints every live allocation to serial and returns the totals
+pub fn report_leaks() -> LeakReport {
+    let table = TABLE.lock();
+    let mut report = LeakReport { untracked: table.untracked, ..LeakReport::default() };
+    for allocation in table.entries.iter().flatten() {
+        report.allocations += 1;
+        report.bytes += allocation.size;
+        crate::serial_println!("leak: {} bytes at {:#x}, allocated from {:x?}",
+            allocation.size, allocation.addr, allocation.callers);
+    }
+    if report.untracked != 0 {
+        crate::serial_println!("leak: {} more allocations were not tracked (table full)", report.untracked);
+    }
+    report
+}
+
+// Return addresses of our callers, innermost first, by following the saved frame pointers
+#[inline(always)]
+fn callers() -> [usize; CALLERS] {
+    let mut callers = [0; CALLERS];
+    let mut rbp: usize;
+    unsafe { llvm_asm!("mov %rbp, $0" : "=r"(rbp)) };
+    for caller in callers.iter_mut() {
+        // Each frame starts with the caller's rbp followed by the return address. Stop at anything that doesn't
+        // look like a frame on our stack instead of chasing garbage.
+        if rbp == 0 || rbp % 8 != 0 {
+            break;
+        }
+        let frame = rbp as *const usize;
+        let (next, return_address) = unsafe { (*frame, *frame.add(1)) };
+        *caller = return_address;
+        // The stack grows down, so the caller's frame must be above ours
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    callers
+}
@@ -0,0 +1,256 @@
+/* A linked list allocator: free regions of the heap form a singly linked list, with each list node stored at the
+ * start of the free region it describes (so the list needs no memory of its own).
+ *
+ * Freeing pushes the region onto the front of the list, which is O(1) but means neighbouring free regions stay
+ * separate. Over a long session the heap degrades into many small blocks, and larger allocations start failing
+ * even though enough memory is free in total. `defragment` fixes that: it re-inserts every free region in address
+ * order and merges (coalesces) regions that touch. It runs automatically when an allocation fails, before we give
+ * up, and can be called explicitly.
+ *
+ * With the `page-poison` feature, the payload of every free region (everything after its ListNode) is filled with
+ * POISON, and allocations check that the pattern is intact before handing memory out. A mismatch means somebody
+ * wrote to the memory after freeing it, so `allocate` reports it instead of handing the block to a new owner, and the
+ * global allocator panics once it has released its lock.
+ */
+use core::mem;
+use super::align_up;
+
+// The byte freed memory is filled with (the same value Linux uses)
+#[cfg(feature = "page-poison")]
+pub const POISON: u8 = 0x6b;
+
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        ListNode { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+// A snapshot of how fragmented the free list is
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentationStats {
+    pub free_bytes: usize,
+    pub free_blocks: usize,
+    pub largest_free_block: usize,
+}
+
+impl FragmentationStats {
+    // Percentage of free memory that is *not* in the largest block: 0 means everything is one block
+    pub fn fragmentation_percent(&self) -> usize {
+        if self.free_bytes == 0 { 0 } else { 100 - self.largest_free_block * 100 / self.free_bytes }
+    }
+}
+
+// Why `allocate` couldn't hand out a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocateError {
+    // No free region is large enough
+    NoFit,
+    // With `page-poison`: the byte at `addr` was modified after the block was freed. The block is taken off the list.
+    UseAfterFree { addr: usize, found: u8 },
+}
+
+pub struct LinkedListAllocator {
+    // Dummy node whose `next` is the first free region
+    head: ListNode,
+}
+
+impl LinkedListAllocator {
+    pub const fn new() -> Self {
+        LinkedListAllocator { head: ListNode::new(0) }
+    }
+
+    // Unsafe because the caller must guarantee that the given heap range is unused and mapped
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.add_free_region(heap_start, heap_size);
+    }
+
+    /* Adds more memory to the heap, merging it with a free block that ends right where it starts.
+     * Unsafe for the same reason as init: the range must be unused.
+     */
+    pub unsafe fn extend(&mut self, addr: usize, size: usize) {
+        self.insert_sorted(addr, size);
+    }
+
+    // Pushes the given region onto the front of the list
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        let mut node = ListNode::new(size);
+        node.next = self.head.next.take();
+        let node_ptr = addr as *mut ListNode;
+        node_ptr.write(node);
+        self.head.next = Some(&mut *node_ptr);
+        poison(addr + mem::size_of::<ListNode>(), addr + size);
+    }
+
+    // Inserts the given region in address order, merging it with its neighbours if they touch
+    unsafe fn insert_sorted(&mut self, addr: usize, size: usize) {
+        let head: *mut ListNode = &mut self.head;
+        // Find the last node that starts before `addr` (or the head)
+        let mut prev = head;
+        while let Some(next) = (*prev).next.as_mut() {
+            if next.start_addr() > addr {
+                break;
+            }
+            prev = &mut **next;
+        }
+
+        let mut size = size;
+        // Absorb the following region if it starts right where this one ends
+        if let Some(next) = (*prev).next.as_mut() {
+            if addr + size == next.start_addr() {
+                size += next.size;
+                (*prev).next = next.next.take();
+            }
+        }
+        // Or grow the previous region if this one starts right where it ends
+        if prev != head && (*prev).end_addr() == addr {
+            (*prev).size += size;
+            // The headers of the merged regions are now part of prev's payload
+            poison(addr, addr + size);
+            return;
+        }
+        let node_ptr = addr as *mut ListNode;
+        node_ptr.write(ListNode { size, next: (*prev).next.take() });
+        (*prev).next = Some(&mut *node_ptr);
+        poison(addr + mem::size_of::<ListNode>(), addr + size);
+    }
+
+    /* Looks for a free region with the given size and alignment and removes it from the list.
+     * Returns the region and the start address of the allocation within it.
+     */
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        let mut current = &mut self.head;
+        while let Some(ref mut region) = current.next {
+            if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) {
+                // Unlink the region
+                let next = region.next.take();
+                let ret = Some((current.next.take().unwrap(), alloc_start));
+                current.next = next;
+                return ret;
+            } else {
+                current = current.next.as_mut().unwrap();
+            }
+        }
+        None
+    }
+
+    // Tries to fit an allocation into `region`, returning its start address
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
+        let alloc_start = align_up(region.start_addr(), align);
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+        if alloc_end > region.end_addr() {
+            return Err(());
+        }
+        // Whatever is left over has to be able to hold a ListNode, or it would be lost
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            return Err(());
+        }
+        Ok(alloc_start)
+    }
+
+    // Adjusts a layout so that the freed block can hold a ListNode
+    pub(super) fn size_align(layout: core::alloc::Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+
+    /* Returns the start of a block of `size` bytes aligned to `align`. A use-after-free is returned rather than
+     * panicked on, so the caller can release the allocator's lock first: the panic path allocates too.
+     */
+    pub fn allocate(&mut self, size: usize, align: usize) -> Result<usize, AllocateError> {
+        let (region, alloc_start) = self.find_region(size, align).ok_or(AllocateError::NoFit)?;
+        let (region_start, region_end) = (region.start_addr(), region.end_addr());
+        let alloc_end = alloc_start + size;
+        unsafe {
+            // Alignment padding in front of the allocation goes back on the list if it's big enough to track
+            let padding = alloc_start - region_start;
+            if padding >= mem::size_of::<ListNode>() {
+                self.add_free_region(region_start, padding);
+            }
+            if region_end > alloc_end {
+                self.add_free_region(alloc_end, region_end - alloc_end);
+            }
+        }
+        // The region's own header was never poisoned
+        check_poison(alloc_start.max(region_start + mem::size_of::<ListNode>()), alloc_end)?;
+        Ok(alloc_start)
+    }
+
+    // Unsafe because the caller must guarantee the block was allocated from this allocator and isn't used anymore
+    pub unsafe fn deallocate(&mut self, addr: usize, size: usize) {
+        self.add_free_region(addr, size);
+    }
+
+    pub fn stats(&self) -> FragmentationStats {
+        let mut stats = FragmentationStats::default();
+        let mut current = self.head.next.as_ref();
+        while let Some(region) = current {
+            stats.free_bytes += region.size;
+            stats.free_blocks += 1;
+            stats.largest_free_block = stats.largest_free_block.max(region.size);
+            current = region.next.as_ref();
+        }
+        stats
+    }
+
+    // Sorts the free list by address and merges adjacent regions. Returns the stats (before, after).
+    pub fn defragment(&mut self) -> (FragmentationStats, FragmentationStats) {
+        let before = self.stats();
+        let mut remaining = self.head.next.take();
+        while let Some(region) = remaining {
+            remaining = region.next.take();
+            let (addr, size) = (region.start_addr(), region.size);
+            // Safe because the region was on the free list, and we've already read everything we need from it
+            unsafe { self.insert_sorted(addr, size) };
+        }
+        (before, self.stats())
+    }
+}
+
+// Fills [start, end) with POISON
+#[cfg(feature = "page-poison")]
+unsafe fn poison(start: usize, end: usize) {
+    if end > start {
+        core::ptr::write_bytes(start as *mut u8, POISON, end - start);
+    }
+}
+
+#[cfg(not(feature = "page-poison"))]
+unsafe fn poison(_start: usize, _end: usize) {}
+
+// Fails on the first byte in [start, end) that is no longer POISON
+#[cfg(feature = "page-poison")]
+fn check_poison(start: usize, end: usize) -> Result<(), AllocateError> {
+    for addr in start..end {
+        let found = unsafe { *(addr as *const u8) };
+        if found != POISON {
+            return Err(AllocateError::UseAfterFree { addr, found });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "page-poison"))]
+fn check_poison(_start: usize, _end: usize) -> Result<(), AllocateError> {
+    Ok(())
+}
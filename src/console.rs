@@ -0,0 +1,504 @@
+/* The console: lines typed on the keyboard, for whatever reads input (the shell, once there is one). The console lives
+ * on tty2 (the kernel log has tty1). It subscribes to the input subsystem and, while tty2 is shown, edits the line
+ * being typed, echoing it there: Backspace erases the last character and Ctrl+U the whole line. Enter completes the
+ * line and queues it for `read_line`, which blocks until a line is available, or for `read_line_async`, a future that
+ * an executor can poll.
+ *
+ * Lines typed while nobody is reading are kept (up to MAX_PENDING_LINES), like a terminal does. Completed lines are
+ * Strings, so `init` must come after the heap is set up.
+ *
+ * The console also sets up the keys for the virtual consoles: Alt+F1..F4 show tty1..tty4, and Shift+PageUp and
+ * Shift+PageDown page through the scrollback of the one that's shown.
+ *
+ * Output goes the other way: print! and println! hand what they print to the console, which passes it on to every
+ * sink: the kernel log on the screen (tty1), serial and the kernel message buffer (see klog.rs) to begin with, so
+ * nothing printed is lost while another console is shown or when nobody is watching the screen. Sinks can be removed
+ * (e.g. the screen, on a headless machine) and added (e.g. debugcon::sink), without changing any of the code that
+ * prints. The sinks are there from boot; only line editing waits for `init`.
+ */
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+use crate::input::{self, InputError, InputEvent};
+use crate::keyboard::{self, ChordError, KeyPress, Modifiers};
+use crate::sync::InterruptGuard;
+use crate::vga_buffer::{self, Color, VTS};
+use crate::{deferred, klog, serial};
+
+// Longest line, in bytes; keys typed past it are ignored
+pub const MAX_LINE: usize = 256;
+// Completed lines kept for readers; lines completed while it's full are dropped
+pub const MAX_PENDING_LINES: usize = 16;
+// tty2
+pub const CONSOLE_VT: usize = 1;
+
+// What a key did to the line being edited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Insert(char),
+    // Erased this many characters from the end
+    Erase(usize),
+    // The line is complete
+    Submit,
+    Ignore,
+}
+
+// The line being typed, in a fixed buffer so editing never allocates
+struct LineEditor {
+    buf: [u8; MAX_LINE],
+    len: usize,
+}
+
+impl LineEditor {
+    const fn new() -> LineEditor {
+        LineEditor { buf: [0; MAX_LINE], len: 0 }
+    }
+
+    fn line(&self) -> &str {
+        // Only whole chars are ever inserted or erased
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    // `ctrl` is whether Ctrl was held, since the decoder hands us Ctrl+U as a plain 'u'
+    fn key(&mut self, c: char, ctrl: bool) -> Edit {
+        match c {
+            '\n' => Edit::Submit,
+            // Backspace
+            '\x08' => {
+                let last = self.line().chars().next_back();
+                match last {
+                    Some(last) => {
+                        self.len -= last.len_utf8();
+                        Edit::Erase(1)
+                    },
+                    None => Edit::Ignore,
+                }
+            },
+            'u' | 'U' if ctrl => {
+                let erased = self.line().chars().count();
+                self.clear();
+                Edit::Erase(erased)
+            },
+            _ if ctrl || c.is_control() => Edit::Ignore,
+            c if self.len + c.len_utf8() <= MAX_LINE => {
+                self.len += c.encode_utf8(&mut self.buf[self.len..]).len();
+                Edit::Insert(c)
+            },
+            _ => Edit::Ignore,
+        }
+    }
+}
+
+// Only used from input subscribers and normal code, which run with interrupts enabled, never from a handler
+static EDITOR: Mutex<LineEditor> = Mutex::new(LineEditor::new());
+static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+// The read_line_async future waiting for a line, if one is
+static WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleError {
+    Input(InputError),
+    // One of the console's keys (Alt+F1..F4, Shift+PageUp/PageDown) is taken
+    Chord(ChordError),
+    // Every sink slot is taken
+    Full,
+}
+
+/* Somewhere printed output goes. `color` is the foreground and background asked for with print_color!, for sinks
+ * that can show colors; None is the sink's usual colors.
+ */
+pub type Sink = fn(args: fmt::Arguments, color: Option<(Color, Color)>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SinkId(usize);
+
+// The sinks that are there from boot
+pub const VGA_SINK: SinkId = SinkId(0);
+pub const SERIAL_SINK: SinkId = SinkId(1);
+pub const LOG_BUFFER_SINK: SinkId = SinkId(2);
+
+const MAX_SINKS: usize = 8;
+
+static SINKS: Mutex<[Option<Sink>; MAX_SINKS]> = Mutex::new([
+    Some(vga_sink as Sink), Some(serial_sink as Sink), Some(log_buffer_sink as Sink), None, None, None, None, None,
+]);
+
+fn vga_sink(args: fmt::Arguments, color: Option<(Color, Color)>) {
+    match color {
+        Some((foreground, background)) => vga_buffer::_print_color(foreground, background, args),
+        None => vga_buffer::_print(args),
+    }
+}
+
+fn serial_sink(args: fmt::Arguments, _color: Option<(Color, Color)>) {
+    serial::_print(args);
+}
+
+fn log_buffer_sink(args: fmt::Arguments, _color: Option<(Color, Color)>) {
+    klog::record(args);
+}
+
+pub fn add_sink(sink: Sink) -> Result<SinkId, ConsoleError> {
+    let _guard = InterruptGuard::new();
+    let mut sinks = SINKS.lock();
+    let slot = sinks.iter().position(|slot| slot.is_none()).ok_or(ConsoleError::Full)?;
+    sinks[slot] = Some(sink);
+    Ok(SinkId(slot))
+}
+
+pub fn remove_sink(id: SinkId) {
+    let _guard = InterruptGuard::new();
+    SINKS.lock()[id.0] = None;
+}
+
+fn write(args: fmt::Arguments, color: Option<(Color, Color)>) {
+    // Copied out, so a sink can print (or add and remove sinks) without deadlocking
+    let sinks = {
+        let _guard = InterruptGuard::new();
+        *SINKS.lock()
+    };
+    for sink in sinks.iter().flatten() {
+        sink(args, color);
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    write(args, None);
+}
+
+#[doc(hidden)]
+pub fn _print_color(foreground: Color, background: Color, args: fmt::Arguments) {
+    write(args, Some((foreground, background)));
+}
+
+/* The colors kwarn! and kerror! print in, which stand out from the yellow on black everything else is printed in.
+ * The backgrounds are dark colors (brown is VGA's dark yellow), since bright backgrounds blink.
+ */
+pub const WARNING_COLORS: (Color, Color) = (Color::Yellow, Color::Brown);
+pub const ERROR_COLORS: (Color, Color) = (Color::White, Color::Red);
+
+// println! for warnings: kwarn!("no HPET, timing with the PIT") prints "warning: no HPET, timing with the PIT"
+#[macro_export]
+macro_rules! kwarn {
+    ($($arg:tt)*) => (
+        $crate::console::_print_color($crate::console::WARNING_COLORS.0, $crate::console::WARNING_COLORS.1,
+            format_args!("warning: {}\n", format_args!($($arg)*)))
+    );
+}
+
+// println! for errors, as "error: ..."
+#[macro_export]
+macro_rules! kerror {
+    ($($arg:tt)*) => (
+        $crate::console::_print_color($crate::console::ERROR_COLORS.0, $crate::console::ERROR_COLORS.1,
+            format_args!("error: {}\n", format_args!($($arg)*)))
+    );
+}
+
+// Starts editing lines from the keyboard, switching consoles and keeping scrollback. Needs the heap.
+pub fn init() -> Result<(), ConsoleError> {
+    vga_buffer::enable_scrollback();
+    let chords: [(Modifiers, KeyCode, keyboard::ChordHandler); 6] = [
+        (Modifiers::ALT, KeyCode::F1, || vga_buffer::switch_to(0)),
+        (Modifiers::ALT, KeyCode::F2, || vga_buffer::switch_to(1)),
+        (Modifiers::ALT, KeyCode::F3, || vga_buffer::switch_to(2)),
+        (Modifiers::ALT, KeyCode::F4, || vga_buffer::switch_to(3)),
+        (Modifiers::SHIFT, KeyCode::PageUp, page_up),
+        (Modifiers::SHIFT, KeyCode::PageDown, page_down),
+    ];
+    for &(modifiers, code, handler) in chords.iter() {
+        keyboard::register_chord(modifiers, code, handler).map_err(ConsoleError::Chord)?;
+    }
+    input::subscribe(on_input).map_err(ConsoleError::Input)?;
+    Ok(())
+}
+
+fn page_up() {
+    let _guard = InterruptGuard::new();
+    vga_buffer::active().lock().page_up();
+}
+
+fn page_down() {
+    let _guard = InterruptGuard::new();
+    vga_buffer::active().lock().page_down();
+}
+
+/* Waits for a complete line and returns it, without the newline. Runs deferred work while it waits (that's where
+ * keys are decoded), so it must not be called from deferred work or an input subscriber.
+ */
+pub fn read_line() -> String {
+    loop {
+        if let Some(line) = next_line() {
+            return line;
+        }
+        deferred::run_pending();
+        deferred::wait_for_work();
+    }
+}
+
+// Like read_line, as a future
+pub fn read_line_async() -> ReadLine {
+    ReadLine { _private: () }
+}
+
+pub struct ReadLine {
+    _private: (),
+}
+
+impl Future for ReadLine {
+    type Output = String;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<String> {
+        if let Some(line) = next_line() {
+            return Poll::Ready(line);
+        }
+        *WAKER.lock() = Some(cx.waker().clone());
+        // A line may have arrived before the waker was registered
+        match next_line() {
+            Some(line) => Poll::Ready(line),
+            None => Poll::Pending,
+        }
+    }
+}
+
+fn next_line() -> Option<String> {
+    let mut lines = LINES.lock();
+    if lines.is_empty() { None } else { Some(lines.remove(0)) }
+}
+
+fn on_input(event: InputEvent) {
+    // Keys typed on the other consoles aren't for us
+    if vga_buffer::active_vt() != CONSOLE_VT {
+        return;
+    }
+    let (c, ctrl) = match event {
+        InputEvent::KeyPress(KeyPress { key: Some(DecodedKey::Unicode(c)), modifiers, .. }) => (c, modifiers.ctrl),
+        _ => return,
+    };
+    let mut editor = EDITOR.lock();
+    let edit = editor.key(c, ctrl);
+    {
+        let _guard = InterruptGuard::new();
+        let mut writer = VTS[CONSOLE_VT].lock();
+        match edit {
+            Edit::Insert(c) => writer.write_string(c.encode_utf8(&mut [0; 4])),
+            Edit::Erase(count) => (0..count).for_each(|_| writer.backspace()),
+            Edit::Submit => writer.write_byte(b'\n'),
+            Edit::Ignore => {},
+        }
+    }
+    if edit == Edit::Submit {
+        {
+            let mut lines = LINES.lock();
+            if lines.len() < MAX_PENDING_LINES {
+                lines.push(String::from(editor.line()));
+            }
+        }
+        editor.clear();
+        if let Some(waker) = WAKER.lock().take() {
+            waker.wake();
+        }
+    }
+}
+
+/* A progress line for a long boot-time operation (scrubbing memory, scanning a disk). `progress` prints the label on
+ * a row of its own (cut to the width of the screen, so it can't wrap), and `update` and `spin` then redraw that row of
+ * tty1 in place: a bar and a percentage, or a spinner when there's no
+ * telling how far along it is. The redraws only go to the screen; the other sinks just get the label. Anything else
+ * printed meanwhile scrolls the line up and the redraws land on whatever took its place, so it's for operations that
+ * print nothing else until they `finish`.
+ */
+pub struct Progress {
+    label: &'static str,
+    // The row of tty1 the line is on
+    row: usize,
+    spinner: usize,
+}
+
+const PROGRESS_BAR_WIDTH: usize = 20;
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+pub fn progress(label: &'static str) -> Progress {
+    if cursor().1 != 0 {
+        crate::println!();
+    }
+    // The label starts at the beginning of this row, and fits on it
+    let row = cursor().0;
+    let mut line = Line::new();
+    let _ = fmt::Write::write_str(&mut line, label);
+    crate::println!("{}", line.as_str());
+    // Ending the label's row on the bottom row scrolls it up one; the scrolling region starts below row 0
+    let row = if cursor().0 > row { row } else { row - 1 };
+    Progress { label, row, spinner: 0 }
+}
+
+// Where tty1 prints next, as (row, column)
+fn cursor() -> (usize, usize) {
+    let _guard = InterruptGuard::new();
+    vga_buffer::WRITER.lock().cursor()
+}
+
+impl Progress {
+    // Shows `done` out of `total` (of whatever the operation counts: bytes, sectors, ...)
+    pub fn update(&mut self, done: u64, total: u64) {
+        let percent = if total == 0 { 100 } else { done.min(total) * 100 / total };
+        let filled = percent as usize * PROGRESS_BAR_WIDTH / 100;
+        self.draw(format_args!("{} [{:#<filled$}{:<empty$}] {:>3}%", self.label, "", "", percent,
+            filled = filled, empty = PROGRESS_BAR_WIDTH - filled));
+    }
+
+    // Turns the spinner a step, to show the operation is still going
+    pub fn spin(&mut self) {
+        self.spinner = (self.spinner + 1) % SPINNER.len();
+        self.draw(format_args!("{} {}", self.label, SPINNER[self.spinner]));
+    }
+
+    // Replaces the bar or spinner with how it went, e.g. "done" or "failed"
+    pub fn finish(self, result: &str) {
+        self.draw(format_args!("{} {}", self.label, result));
+    }
+
+    fn draw(&self, args: fmt::Arguments) {
+        use core::fmt::Write;
+        let mut line = Line::new();
+        let _ = line.write_fmt(args);
+        // All of it, so the spaces after the text clear whatever was longer before
+        vga_buffer::write_at(self.row, 0, core::str::from_utf8(&line.bytes).unwrap_or(""));
+    }
+}
+
+// A row of text, cut off at whole characters when it's too long
+struct Line {
+    bytes: [u8; vga_buffer::BUFFER_WIDTH],
+    len: usize,
+}
+
+impl Line {
+    fn new() -> Line {
+        Line { bytes: [b' '; vga_buffer::BUFFER_WIDTH], len: 0 }
+    }
+
+    // What was written, without the padding
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.len + c.len_utf8() > vga_buffer::BUFFER_WIDTH {
+                break;
+            }
+            self.len += c.encode_utf8(&mut self.bytes[self.len..]).len();
+        }
+        Ok(())
+    }
+}
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_output_reaches_every_sink() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static PRINTED: AtomicUsize = AtomicUsize::new(0);
+    fn count(_args: fmt::Arguments, color: Option<(Color, Color)>) {
+        if color == Some((Color::Green, Color::Black)) {
+            PRINTED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    let id = add_sink(count).expect("no free sink slot");
+    crate::println_color!(Color::Green, Color::Black, "test_output_reaches_every_sink");
+    assert_eq!(PRINTED.load(Ordering::SeqCst), 1);
+    remove_sink(id);
+    crate::println_color!(Color::Green, Color::Black, "test_output_reaches_every_sink");
+    assert_eq!(PRINTED.load(Ordering::SeqCst), 1);
+}
+
+#[test_case]
+fn test_warnings_and_errors_reach_the_log_prefixed() {
+    // The newest line in the kernel message buffer, without its timestamp
+    fn last_logged(out: &mut [u8]) -> &[u8] {
+        let count = klog::read(out);
+        let lines = &out[..count.saturating_sub(1)];
+        let start = lines.iter().rposition(|&byte| byte == b'\n').map_or(0, |newline| newline + 1);
+        let line = &lines[start..];
+        let text = line.windows(2).position(|window| window == b"] ").map_or(0, |end| end + 2);
+        &line[text..]
+    }
+    let mut out = [0u8; 1024];
+    crate::kwarn!("test_warnings_and_errors_reach_the_log_prefixed {}", 1);
+    assert_eq!(last_logged(&mut out), &b"warning: test_warnings_and_errors_reach_the_log_prefixed 1"[..]);
+    crate::kerror!("test_warnings_and_errors_reach_the_log_prefixed {}", 2);
+    assert_eq!(last_logged(&mut out), &b"error: test_warnings_and_errors_reach_the_log_prefixed 2"[..]);
+}
+
+#[test_case]
+fn test_line_editing() {
+    let mut editor = LineEditor::new();
+    for c in "ls -l".chars() {
+        assert_eq!(editor.key(c, false), Edit::Insert(c));
+    }
+    assert_eq!(editor.key('\x08', false), Edit::Erase(1));
+    assert_eq!(editor.line(), "ls -");
+    // Ctrl+letters other than U don't type anything
+    assert_eq!(editor.key('c', true), Edit::Ignore);
+    assert_eq!(editor.key('u', true), Edit::Erase(4));
+    assert_eq!(editor.line(), "");
+    assert_eq!(editor.key('\x08', false), Edit::Ignore);
+    // Multi-byte characters are erased whole
+    editor.key('é', false);
+    assert_eq!(editor.key('\x08', false), Edit::Erase(1));
+    assert_eq!(editor.line(), "");
+    assert_eq!(editor.key('\n', false), Edit::Submit);
+}
+
+#[test_case]
+fn test_line_length_is_bounded() {
+    let mut editor = LineEditor::new();
+    for _ in 0..MAX_LINE {
+        assert_eq!(editor.key('x', false), Edit::Insert('x'));
+    }
+    assert_eq!(editor.key('x', false), Edit::Ignore);
+    assert_eq!(editor.line().len(), MAX_LINE);
+}
+
+#[test_case]
+fn test_progress_redraws_its_line() {
+    let shows = |row: usize, text: &str| {
+        text.chars().enumerate().all(|(col, c)| vga_buffer::read_char_at(row, col) == c)
+    };
+    let mut progress = progress("test_progress");
+    let row = progress.row;
+    assert!(shows(row, "test_progress"));
+    progress.update(1, 4);
+    assert!(shows(row, "test_progress [#####               ]  25%"));
+    progress.spin();
+    // The longer line that was there before is gone
+    assert!(shows(row, "test_progress /                             "));
+    progress.finish("done");
+    assert!(shows(row, "test_progress done "));
+}
+
+#[test_case]
+fn test_progress_label_gets_a_row_of_its_own() {
+    // Starting mid-row, with a label too long for a whole one
+    crate::print!("test_progress_label");
+    let label = "test_progress_label_gets_a_row_of_its_own, with a label longer than the screen is wide";
+    let progress = progress(label);
+    let shown = label.chars().take(vga_buffer::BUFFER_WIDTH);
+    assert!(shown.enumerate().all(|(col, c)| vga_buffer::read_char_at(progress.row, col) == c));
+    assert_eq!(cursor(), (progress.row + 1, 0));
+    progress.finish("done");
+}
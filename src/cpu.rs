@@ -0,0 +1,202 @@
+/* CPU topology. The MADT lists every CPU's local APIC ID, and CPUID tells us how those IDs are laid out: the low bits
+ * number the hardware threads (SMT) of a core, the next bits the cores of a package, and the rest is the package
+ * (socket). Splitting each APIC ID with those shifts gives the package/core/thread of every CPU without starting it.
+ *
+ * Per-CPU structures should be sized with `count()` rather than a fixed maximum. The topology is built on first use
+ * and is heap allocated, so this needs memory::init and the heap; without an MADT only the boot CPU is reported.
+ * https://wiki.osdev.org/Detecting_CPU_Topology_(80x86)
+ */
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::fmt;
+use spin::Once;
+use crate::acpi;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuInfo {
+    // The ACPI processor ID (None for a CPU we only know about from CPUID)
+    pub processor_id: Option<u8>,
+    pub apic_id: u32,
+    pub package: u32,
+    pub core: u32,
+    pub thread: u32,
+    pub is_boot: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Topology {
+    pub cpus: Vec<CpuInfo>,
+    pub packages: usize,
+    // Physical cores, over all packages
+    pub cores: usize,
+    pub vendor: String,
+    pub model_name: String,
+}
+
+// How many low APIC ID bits number the threads of a core, and how many the threads of a package
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Shifts {
+    smt: u32,
+    core: u32,
+}
+
+static TOPOLOGY: Once<Topology> = Once::new();
+
+// The detected topology; built on the first call, which must come after the heap is up
+pub fn topology() -> &'static Topology {
+    TOPOLOGY.call_once(detect)
+}
+
+// Number of usable CPUs, for sizing per-CPU structures
+pub fn count() -> usize {
+    topology().cpus.len()
+}
+
+// The APIC ID of the CPU we're running on, as CPUID reports it
+pub fn current_apic_id() -> u32 {
+    if max_leaf() >= 0xb && unsafe { __cpuid_count(0xb, 0) }.ebx != 0 {
+        // The full 32-bit x2APIC ID
+        unsafe { __cpuid_count(0xb, 0) }.edx
+    } else {
+        unsafe { __cpuid(1) }.ebx >> 24
+    }
+}
+
+fn detect() -> Topology {
+    let shifts = apic_id_shifts();
+    let boot_id = current_apic_id();
+    let mut cpus: Vec<CpuInfo> = match acpi::madt::madt() {
+        // Bit 0: enabled, bit 1: can be brought online. Entries with neither are absent sockets.
+        Ok(madt) => madt.local_apics()
+            .filter(|entry| entry.flags & 0b11 != 0)
+            .map(|entry| cpu_info(Some(entry.processor_id), entry.apic_id as u32, boot_id, shifts))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    if !cpus.iter().any(|cpu| cpu.is_boot) {
+        cpus.push(cpu_info(None, boot_id, boot_id, shifts));
+    }
+    let mut packages: Vec<u32> = cpus.iter().map(|cpu| cpu.package).collect();
+    packages.sort_unstable();
+    packages.dedup();
+    let mut cores: Vec<(u32, u32)> = cpus.iter().map(|cpu| (cpu.package, cpu.core)).collect();
+    cores.sort_unstable();
+    cores.dedup();
+    Topology { packages: packages.len(), cores: cores.len(), cpus, vendor: vendor(), model_name: model_name() }
+}
+
+fn cpu_info(processor_id: Option<u8>, apic_id: u32, boot_id: u32, shifts: Shifts) -> CpuInfo {
+    let (package, core, thread) = decompose(apic_id, shifts);
+    CpuInfo { processor_id, apic_id, package, core, thread, is_boot: apic_id == boot_id }
+}
+
+fn decompose(apic_id: u32, shifts: Shifts) -> (u32, u32, u32) {
+    let thread = low_bits(apic_id, shifts.smt);
+    let core = low_bits(apic_id, shifts.core).checked_shr(shifts.smt).unwrap_or(0);
+    let package = apic_id.checked_shr(shifts.core).unwrap_or(0);
+    (package, core, thread)
+}
+
+fn low_bits(value: u32, bits: u32) -> u32 {
+    value & 1u32.checked_shl(bits).map_or(u32::MAX, |bit| bit - 1)
+}
+
+fn apic_id_shifts() -> Shifts {
+    // Leaf 0xB enumerates the levels (SMT, core) with the shift to the next level's ID
+    if max_leaf() >= 0xb && unsafe { __cpuid_count(0xb, 0) }.ebx != 0 {
+        let mut shifts = Shifts { smt: 0, core: 0 };
+        for level in 0..8 {
+            let leaf = unsafe { __cpuid_count(0xb, level) };
+            let shift = leaf.eax & 0x1f;
+            match (leaf.ecx >> 8) & 0xff {
+                0 => break,
+                1 => shifts.smt = shift,
+                2 => shifts.core = shift,
+                _ => {},
+            }
+        }
+        shifts.core = shifts.core.max(shifts.smt);
+        return shifts;
+    }
+    /* Older CPUs: leaf 1 has the number of logical CPUs per package (if HTT is set), and Intel's leaf 4 the number of
+     * cores. Without leaf 4 (e.g. older AMD), every logical CPU of a package is counted as a thread of one core.
+     */
+    let leaf1 = unsafe { __cpuid(1) };
+    let logical = if leaf1.edx & (1 << 28) != 0 { (leaf1.ebx >> 16) & 0xff } else { 1 };
+    let cores = if max_leaf() >= 4 { (unsafe { __cpuid_count(4, 0) }.eax >> 26) + 1 } else { 1 };
+    Shifts { smt: bits_for(logical.max(1) / cores), core: bits_for(logical) }
+}
+
+// Number of bits needed to number `count` things
+fn bits_for(count: u32) -> u32 {
+    count.max(1).next_power_of_two().trailing_zeros()
+}
+
+fn max_leaf() -> u32 {
+    unsafe { __cpuid(0) }.eax
+}
+
+fn vendor() -> String {
+    let leaf = unsafe { __cpuid(0) };
+    let mut bytes = [0u8; 12];
+    for (chunk, register) in bytes.chunks_exact_mut(4).zip(&[leaf.ebx, leaf.edx, leaf.ecx]) {
+        chunk.copy_from_slice(&register.to_le_bytes());
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// The brand string from leaves 0x80000002-0x80000004, or an empty string if the CPU doesn't have one
+fn model_name() -> String {
+    if unsafe { __cpuid(0x8000_0000) }.eax < 0x8000_0004 {
+        return String::new();
+    }
+    let mut bytes = Vec::with_capacity(48);
+    for leaf in 0x8000_0002..=0x8000_0004 {
+        let leaf = unsafe { __cpuid(leaf) };
+        for register in &[leaf.eax, leaf.ebx, leaf.ecx, leaf.edx] {
+            bytes.extend_from_slice(&register.to_le_bytes());
+        }
+    }
+    String::from_utf8_lossy(&bytes).trim_matches(|c: char| c == '\0' || c == ' ').into()
+}
+
+// One block per CPU, in the layout of Linux's /proc/cpuinfo
+impl fmt::Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, cpu) in self.cpus.iter().enumerate() {
+            writeln!(f, "processor\t: {}", i)?;
+            writeln!(f, "vendor_id\t: {}", self.vendor)?;
+            writeln!(f, "model name\t: {}", self.model_name)?;
+            writeln!(f, "physical id\t: {}", cpu.package)?;
+            writeln!(f, "core id\t\t: {}", cpu.core)?;
+            writeln!(f, "thread id\t: {}", cpu.thread)?;
+            writeln!(f, "apicid\t\t: {}", cpu.apic_id)?;
+            writeln!(f, "boot cpu\t: {}", if cpu.is_boot { "yes" } else { "no" })?;
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_decompose_apic_id() {
+    // 2 threads per core, 4 cores per package
+    let shifts = Shifts { smt: 1, core: 3 };
+    assert_eq!(decompose(0, shifts), (0, 0, 0));
+    assert_eq!(decompose(5, shifts), (0, 2, 1));
+    assert_eq!(decompose(9, shifts), (1, 0, 1));
+    // No SMT, a single package
+    assert_eq!(decompose(3, Shifts { smt: 0, core: 32 }), (0, 3, 0));
+}
+
+#[test_case]
+fn test_bits_for() {
+    assert_eq!(bits_for(0), 0);
+    assert_eq!(bits_for(1), 0);
+    assert_eq!(bits_for(2), 1);
+    assert_eq!(bits_for(6), 3);
+}
@@ -0,0 +1,195 @@
+/* Crash deduplication. Every panic gets a signature, a hash of the top frames of the code that crashed, and the
+ * signature is looked up in KNOWN_ISSUES, a table of crashes we've already triaged. The panic handlers print the
+ * signature, and "known issue #N" when it matches, so the same QEMU crash doesn't get investigated twice.
+ *
+ * For a CPU fault, the exception handler records the fault's vector, the faulting instruction pointer and the return
+ * addresses above it before it panics. For any other panic, the frames are the return addresses on the stack when the
+ * report is made: the panic handler's, then the panic machinery's, then those of the code that panicked. Frames are
+ * found by following the frame pointer chain (like the leak detector does). The image has no symbol table, so
+ * addresses are hashed as offsets from the start of the kernel image (`__ehdr_start`), which don't depend on where the
+ * image is loaded.
+ *
+ * Neither the panic's source location nor its message is part of the signature: the location moves whenever the code
+ * around it is edited, and the message is full of values (addresses, sizes, counters) that differ from one run of the
+ * same crash to the next.
+ */
+use core::fmt;
+use spin::Mutex;
+use x86_64::structures::idt::InterruptStackFrame;
+
+// Frames hashed into a signature, innermost first
+pub const FRAMES: usize = 8;
+// How far above the stack pointer frames are looked for
+const MAX_STACK_WALK: u64 = 64 * 1024;
+// Stands in for the vector of a panic that wasn't raised by a fault; faults only use vectors 0-31
+const PANIC_VECTOR: u8 = 0xff;
+
+extern "C" {
+    static __ehdr_start: u8;
+}
+
+pub struct KnownIssue {
+    pub signature: u64,
+    pub issue: u32,
+    pub summary: &'static str,
+}
+
+/* Triaged crashes. To add one, copy the signature the panic handler printed, along with the number of the issue that
+ * tracks it. Signatures are made of offsets into the kernel image, so they belong to one build of the kernel.
+ */
+pub const KNOWN_ISSUES: &[KnownIssue] = &[];
+
+// Where a crash happened, as offsets into the kernel image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crash {
+    // The exception's vector, or None for a panic that wasn't raised by a fault
+    pub vector: Option<u8>,
+    // For a fault, the faulting instruction and then the return addresses above it. Zero past the end of the chain.
+    pub frames: [u64; FRAMES],
+}
+
+// The fault being reported; set by the exception handlers right before they panic
+static FAULT: Mutex<Option<Crash>> = Mutex::new(None);
+
+/* Records the fault that `stack_frame` describes, for the signature of the panic the handler is about to raise.
+ * Always inlined into the handler, so the frame pointer it reads is the handler's, whose frame starts with the frame
+ * pointer of the code that faulted.
+ */
+#[inline(always)]
+pub fn record_fault(vector: u8, stack_frame: &InterruptStackFrame) {
+    let rbp: u64;
+    unsafe { llvm_asm!("mov %rbp, $0" : "=r"(rbp)) };
+    let mut crash = Crash { vector: Some(vector), frames: [0; FRAMES] };
+    crash.frames[0] = image_offset(stack_frame.instruction_pointer.as_u64());
+    let frame = if rbp != 0 && rbp % 8 == 0 { unsafe { *(rbp as *const u64) } } else { 0 };
+    walk_frames(frame, stack_frame.stack_pointer.as_u64(), &mut crash.frames[1..]);
+    store(crash);
+}
+
+/* Records a fault without following the stack it happened on. For double faults: their stack is the likely culprit
+ * (an overflow into the guard page), and a bad frame pointer there would turn the double fault into a triple fault.
+ */
+pub fn record_fault_without_stack(vector: u8, stack_frame: &InterruptStackFrame) {
+    let mut crash = Crash { vector: Some(vector), frames: [0; FRAMES] };
+    crash.frames[0] = image_offset(stack_frame.instruction_pointer.as_u64());
+    store(crash);
+}
+
+fn store(crash: Crash) {
+    // Only held here and in `report`, so this can't fail unless a fault hit one of them
+    if let Some(mut recorded) = FAULT.try_lock() {
+        *recorded = Some(crash);
+    }
+}
+
+// Follows the frame pointer chain from `frame`, filling `frames` with return addresses
+fn walk_frames(mut frame: u64, stack_pointer: u64, frames: &mut [u64]) {
+    for slot in frames.iter_mut() {
+        // Only follow frames on the stack in use, instead of chasing garbage
+        if frame % 8 != 0 || frame < stack_pointer || frame >= stack_pointer.saturating_add(MAX_STACK_WALK) {
+            break;
+        }
+        let (next, return_address) = unsafe { (*(frame as *const u64), *(frame as *const u64).add(1)) };
+        *slot = image_offset(return_address);
+        // The stack grows down, so the caller's frame must be above this one
+        if next <= frame {
+            break;
+        }
+        frame = next;
+    }
+}
+
+fn image_offset(address: u64) -> u64 {
+    address.wrapping_sub(unsafe { &__ehdr_start as *const u8 as u64 })
+}
+
+// What the panic handlers print: the signature, and the known issue it matches (if any)
+pub struct CrashReport {
+    pub signature: u64,
+    pub known_issue: Option<&'static KnownIssue>,
+}
+
+/* The report for the panic being handled: the fault an exception handler recorded, or else the frames above this
+ * call. Never inlined, so the same call site always sees the same frames.
+ */
+#[inline(never)]
+pub fn report() -> CrashReport {
+    let fault = FAULT.try_lock().and_then(|fault| *fault);
+    let crash = match fault {
+        Some(fault) => fault,
+        None => {
+            let (rbp, rsp): (u64, u64);
+            unsafe { llvm_asm!("mov %rbp, $0; mov %rsp, $1" : "=r"(rbp), "=r"(rsp)) };
+            let mut crash = Crash { vector: None, frames: [0; FRAMES] };
+            walk_frames(rbp, rsp, &mut crash.frames);
+            crash
+        }
+    };
+    let signature = signature(&crash);
+    CrashReport { signature, known_issue: KNOWN_ISSUES.iter().find(|known| known.signature == signature) }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "crash signature {:#018x}", self.signature)?;
+        match self.known_issue {
+            Some(known) => write!(f, ": known issue #{} ({})", known.issue, known.summary),
+            None => write!(f, " (not a known issue)"),
+        }
+    }
+}
+
+// FNV-1a of the vector, followed by the frames (little endian)
+pub fn signature(crash: &Crash) -> u64 {
+    let mut hasher = Fnv1a(FNV_OFFSET);
+    hasher.push(&[crash.vector.unwrap_or(PANIC_VECTOR)]);
+    for frame in &crash.frames {
+        hasher.push(&frame.to_le_bytes());
+    }
+    hasher.0
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_frames_change_the_signature() {
+    let at = |vector: Option<u8>, rip: u64, caller: u64| {
+        signature(&Crash { vector, frames: [rip, caller, 0, 0, 0, 0, 0, 0] })
+    };
+    assert_eq!(at(Some(14), 0x1_2340, 0x2_0000), at(Some(14), 0x1_2340, 0x2_0000));
+    // The same instruction, but a different fault, or a panic
+    assert_ne!(at(Some(14), 0x1_2340, 0x2_0000), at(Some(13), 0x1_2340, 0x2_0000));
+    assert_ne!(at(Some(14), 0x1_2340, 0x2_0000), at(None, 0x1_2340, 0x2_0000));
+    // The same fault at a different instruction, or at the same one reached from a different caller
+    assert_ne!(at(Some(14), 0x1_2340, 0x2_0000), at(Some(14), 0x1_5678, 0x2_0000));
+    assert_ne!(at(Some(14), 0x1_2340, 0x2_0000), at(Some(14), 0x1_2340, 0x3_0000));
+}
+
+#[test_case]
+fn test_report_signs_the_call_path() {
+    #[inline(never)]
+    fn signature_here() -> u64 {
+        report().signature
+    }
+    let mut signatures = [0; 2];
+    for signature in signatures.iter_mut() {
+        *signature = signature_here();
+    }
+    // The same call path every time, so the same signature; a different call site changes it
+    assert_eq!(signatures[0], signatures[1]);
+    assert_ne!(signatures[0], signature_here());
+}
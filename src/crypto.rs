@@ -0,0 +1,42 @@
+/* Small crypto primitives for the kernel: SHA-256 (FIPS 180-4), HMAC-SHA-256 (RFC 2104) and the ChaCha20 stream
+ * cipher (RFC 8439). They're meant for the RNG, verifying signed images and network experiments, not for speed.
+ *
+ * None of them branch on or index memory with secret data, so their timing doesn't depend on keys or messages.
+ * Comparing MACs has to be constant time too, which is what `ct_eq` is for; never compare them with `==`.
+ */
+pub mod sha256;
+pub mod hmac;
+pub mod chacha20;
+
+pub use sha256::{sha256, Sha256};
+pub use hmac::{hmac_sha256, HmacSha256};
+pub use chacha20::ChaCha20;
+
+// Compares two byte strings in time that depends only on their lengths
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y));
+    // Read through a volatile so the compiler can't turn this into an early-exit comparison
+    unsafe { core::ptr::read_volatile(&diff) == 0 }
+}
+
+// * TESTS *
+
+// Checks `actual` against a hex string without allocating (the test kernel has no heap)
+#[cfg(test)]
+fn assert_hex(actual: &[u8], expected: &str) {
+    assert_eq!(actual.len() * 2, expected.len(), "length mismatch");
+    for (i, &byte) in actual.iter().enumerate() {
+        let expected_byte = u8::from_str_radix(&expected[2 * i..2 * i + 2], 16).unwrap();
+        assert_eq!(byte, expected_byte, "mismatch at byte {}", i);
+    }
+}
+
+#[test_case]
+fn test_ct_eq() {
+    assert!(ct_eq(b"abc", b"abc"));
+    assert!(!ct_eq(b"abc", b"abd"));
+    assert!(!ct_eq(b"abc", b"ab"));
+}
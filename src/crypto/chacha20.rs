@@ -0,0 +1,98 @@
+/* The ChaCha20 stream cipher as specified in RFC 8439: a 256-bit key, a 96-bit nonce and a 32-bit block counter.
+ * Encryption and decryption are the same operation (XOR with the keystream), so there's only `apply_keystream`.
+ * Never reuse a (key, nonce) pair for two messages.
+ */
+
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]; // "expand 32-byte k"
+
+pub struct ChaCha20 {
+    state: [u32; 16],
+    // Keystream of the current block and how much of it is used up
+    keystream: [u8; 64],
+    used: usize,
+}
+
+impl ChaCha20 {
+    pub fn new(key: &[u8; 32], nonce: &[u8; 12], counter: u32) -> ChaCha20 {
+        let mut state = [0u32; 16];
+        state[..4].copy_from_slice(&CONSTANTS);
+        for (word, bytes) in state[4..12].iter_mut().zip(key.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        state[12] = counter;
+        for (word, bytes) in state[13..].iter_mut().zip(nonce.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        ChaCha20 { state, keystream: [0; 64], used: 64 }
+    }
+
+    // XORs `data` with the keystream, continuing where the previous call left off
+    pub fn apply_keystream(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            if self.used == 64 {
+                self.keystream = block(&self.state);
+                self.state[12] = self.state[12].wrapping_add(1);
+                self.used = 0;
+            }
+            *byte ^= self.keystream[self.used];
+            self.used += 1;
+        }
+    }
+}
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(7);
+}
+
+// The ChaCha20 block function: 20 rounds (10 column + diagonal double rounds), then add the input state
+fn block(input: &[u32; 16]) -> [u8; 64] {
+    let mut s = *input;
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+    let mut out = [0u8; 64];
+    for ((bytes, word), initial) in out.chunks_exact_mut(4).zip(s.iter()).zip(input.iter()) {
+        bytes.copy_from_slice(&word.wrapping_add(*initial).to_le_bytes());
+    }
+    out
+}
+
+// * TESTS *
+
+// RFC 8439 section 2.4.2
+#[test_case]
+fn test_chacha20_encryption_vector() {
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let nonce = [0, 0, 0, 0, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+    let mut data = *b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, \
+sunscreen would be it.";
+    ChaCha20::new(&key, &nonce, 1).apply_keystream(&mut data);
+    super::assert_hex(&data, "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0bf91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d807ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab77937365af90bbf74a35be6b40b8eedf2785e42874d");
+}
+
+#[test_case]
+fn test_chacha20_round_trips_across_calls() {
+    let key = [7u8; 32];
+    let nonce = [9u8; 12];
+    let mut data = [0x42u8; 150];
+    let mut cipher = ChaCha20::new(&key, &nonce, 0);
+    let (first, second) = data.split_at_mut(70);
+    cipher.apply_keystream(first);
+    cipher.apply_keystream(second);
+    assert!(data.iter().any(|&b| b != 0x42));
+    ChaCha20::new(&key, &nonce, 0).apply_keystream(&mut data);
+    assert!(data.iter().all(|&b| b == 0x42));
+}
@@ -0,0 +1,81 @@
+/* HMAC-SHA-256 (RFC 2104): H((K ^ opad) || H((K ^ ipad) || message)), with keys longer than a block hashed first.
+ * Check tags with `verify`, which compares in constant time.
+ */
+use super::sha256::{sha256, Sha256, BLOCK_LEN, DIGEST_LEN};
+
+#[derive(Clone)]
+pub struct HmacSha256 {
+    inner: Sha256,
+    // The outer hash's input before the inner digest: K ^ opad
+    outer_key: [u8; BLOCK_LEN],
+}
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> HmacSha256 {
+        let mut block = [0u8; BLOCK_LEN];
+        if key.len() > BLOCK_LEN {
+            block[..DIGEST_LEN].copy_from_slice(&sha256(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        let mut inner_key = [0u8; BLOCK_LEN];
+        let mut outer_key = [0u8; BLOCK_LEN];
+        for ((inner, outer), byte) in inner_key.iter_mut().zip(outer_key.iter_mut()).zip(block.iter()) {
+            *inner = byte ^ 0x36;
+            *outer = byte ^ 0x5c;
+        }
+        let mut inner = Sha256::new();
+        inner.update(&inner_key);
+        HmacSha256 { inner, outer_key }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finalize(self) -> [u8; DIGEST_LEN] {
+        let inner_digest = self.inner.finalize();
+        let mut outer = Sha256::new();
+        outer.update(&self.outer_key);
+        outer.update(&inner_digest);
+        outer.finalize()
+    }
+
+    // Whether `tag` is the MAC of everything passed to update, compared in constant time
+    pub fn verify(self, tag: &[u8]) -> bool {
+        super::ct_eq(&self.finalize(), tag)
+    }
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; DIGEST_LEN] {
+    let mut mac = HmacSha256::new(key);
+    mac.update(data);
+    mac.finalize()
+}
+
+// * TESTS *
+
+// RFC 4231 test cases 1, 2 and 6
+#[test_case]
+fn test_hmac_sha256_vectors() {
+    use super::assert_hex;
+    assert_hex(&hmac_sha256(&[0x0b; 20], b"Hi There"),
+        "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+    assert_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?"),
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    assert_hex(&hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First"),
+        "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+}
+
+#[test_case]
+fn test_hmac_verify_rejects_a_modified_tag() {
+    let mut tag = hmac_sha256(b"key", b"message");
+    let mac = HmacSha256::new(b"key");
+    let mut other = mac.clone();
+    other.update(b"message");
+    assert!(other.verify(&tag));
+    tag[31] ^= 1;
+    let mut mac = mac;
+    mac.update(b"message");
+    assert!(!mac.verify(&tag));
+}
@@ -0,0 +1,65 @@
+/* QEMU's isa-debugcon device: every byte written to I/O port 0xE9 ends up in a host-side chardev, e.g. with
+ * `-debugcon file:debugcon.log`. It needs no initialization and never blocks, which makes it a good place for heavy
+ * trace output that would otherwise clutter the screen or get mixed into the serial test protocol.
+ * On real hardware the port is unused and writes are simply ignored.
+ *
+ * Everything printed can be mirrored here with `console::add_sink(debugcon::sink)`, and log messages alone sent here
+ * instead of the screen and serial with `klog::set_route(Route::Debugcon)`.
+ */
+use core::fmt;
+use spin::Mutex;
+use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+use crate::vga_buffer::Color;
+
+const DEBUGCON_PORT: u16 = 0xE9;
+
+pub struct DebugCon {
+  port: Port<u8>,
+}
+
+impl DebugCon {
+  pub fn write_byte(&mut self, byte: u8) {
+    // Unsafe because writing to an arbitrary port could have side effects; 0xE9 is only used by debugcon
+    unsafe { self.port.write(byte) };
+  }
+}
+
+impl fmt::Write for DebugCon {
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    for byte in s.bytes() {
+      self.write_byte(byte);
+    }
+    Ok(())
+  }
+}
+
+lazy_static! {
+  // The lock only keeps messages from interleaving; the device itself has no state
+  pub static ref DEBUGCON: Mutex<DebugCon> = Mutex::new(DebugCon { port: Port::new(DEBUGCON_PORT) });
+}
+
+#[macro_export]
+macro_rules! debugcon_print {
+  ($($arg:tt)*) => {
+    $crate::debugcon::_print(format_args!($($arg)*))
+  };
+}
+
+#[macro_export]
+macro_rules! debugcon_println {
+  () => ($crate::debugcon_print!("\n"));
+  ($($arg:tt)*) => ($crate::debugcon_print!("{}\n", format_args!($($arg)*)));
+}
+
+// A console sink (see console.rs); debugcon has no colors
+pub fn sink(args: fmt::Arguments, _color: Option<(Color, Color)>) {
+  _print(args);
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+  use core::fmt::Write;
+  let _guard = crate::sync::InterruptGuard::new();
+  DEBUGCON.lock().write_fmt(args).unwrap();
+}
@@ -0,0 +1,118 @@
+/* Deferred interrupt work (a "bottom half"). Interrupt handlers run with interrupts disabled, so anything slow they do
+ * (decoding, printing, taking locks that normal code also takes) delays every other interrupt and risks deadlocking
+ * against the code they interrupted. Instead, a handler does the minimum (acknowledge the device, grab its data) and
+ * calls `schedule` with a function and an argument; the idle loop runs the queued work later, with interrupts
+ * enabled, through `run_pending`.
+ *
+ * The queue is a fixed-size ring, so scheduling never allocates and is safe from any interrupt handler. Work that
+ * doesn't fit is dropped and counted.
+ */
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::sync::InterruptGuard;
+
+pub type WorkFn = fn(usize);
+
+const QUEUE_SIZE: usize = 64;
+
+#[derive(Clone, Copy)]
+struct WorkItem {
+    func: WorkFn,
+    arg: usize,
+}
+
+struct Queue {
+    items: [Option<WorkItem>; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+// Only locked with interrupts disabled, so a handler can never spin on a lock its own CPU holds
+static QUEUE: Mutex<Queue> = Mutex::new(Queue { items: [None; QUEUE_SIZE], head: 0, len: 0 });
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/* Queues `func(arg)` to run outside interrupt context. Returns false (and counts the item as dropped) if the queue
+ * is full.
+ */
+pub fn schedule(func: WorkFn, arg: usize) -> bool {
+    let _guard = InterruptGuard::new();
+    let mut queue = QUEUE.lock();
+    if queue.len == QUEUE_SIZE {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    let tail = (queue.head + queue.len) % QUEUE_SIZE;
+    queue.items[tail] = Some(WorkItem { func, arg });
+    queue.len += 1;
+    true
+}
+
+// Runs queued work, oldest first, until the queue is empty (including work queued meanwhile); returns how many ran
+pub fn run_pending() -> usize {
+    let mut ran = 0;
+    while let Some(item) = pop() {
+        // The queue lock isn't held here, so the work can be interrupted and can schedule more work
+        (item.func)(item.arg);
+        ran += 1;
+    }
+    ran
+}
+
+pub fn is_pending() -> bool {
+    let _guard = InterruptGuard::new();
+    let queue = QUEUE.lock();
+    queue.len != 0
+}
+
+// Number of work items dropped because the queue was full
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/* Halts until the next interrupt, unless work is already pending. Checking and halting with interrupts disabled
+ * (sti only takes effect after the following hlt) means work scheduled just before we go to sleep can't be missed.
+ */
+pub fn wait_for_work() {
+    interrupts::disable();
+    if is_pending() {
+        interrupts::enable();
+    } else {
+        interrupts::enable_and_hlt();
+    }
+}
+
+fn pop() -> Option<WorkItem> {
+    let _guard = InterruptGuard::new();
+    let mut queue = QUEUE.lock();
+    if queue.len == 0 {
+        return None;
+    }
+    let head = queue.head;
+    let item = queue.items[head].take();
+    queue.head = (head + 1) % QUEUE_SIZE;
+    queue.len -= 1;
+    item
+}
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_work_runs_in_order() {
+    use core::sync::atomic::AtomicUsize;
+    static LAST: AtomicUsize = AtomicUsize::new(0);
+    fn record(arg: usize) {
+        // Each item must see the one before it
+        assert_eq!(LAST.swap(arg, Ordering::SeqCst), arg - 1);
+    }
+    run_pending();
+    LAST.store(0, Ordering::SeqCst);
+    for arg in 1..=3 {
+        assert!(schedule(record, arg));
+    }
+    assert!(is_pending());
+    assert_eq!(run_pending(), 3);
+    assert_eq!(LAST.load(Ordering::SeqCst), 3);
+    assert!(!is_pending());
+}
@@ -1,10 +1,31 @@
+use core::cell::UnsafeCell;
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
 use x86_64::structures::gdt::SegmentSelector;
 use lazy_static::lazy_static;
+use crate::memory::{alloc_kernel_stack, KmapError};
+use crate::sync::InterruptGuard;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0; // Use the first stack for Double Faults
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+/* NMIs can't be masked, so one can arrive at any point, including while the current stack is nearly (or completely)
+ * used up; a stack of its own means the handler can still report it.
+ */
+pub const NMI_IST_INDEX: u16 = 1;
+const NMI_STACK_SIZE: usize = 4096 * 2;
+/* Page faults and general protection faults get stacks of their own too, so a fault caused by a corrupted or
+ * overflowed kernel stack is still reported (by the right handler, instead of as a double fault). The CPU resets
+ * the stack pointer to the top of the IST stack on every entry, so these handlers must not fault themselves: a nested
+ * fault of the same kind would overwrite the outer one's frame.
+ *
+ * The stacks come from memory::alloc_kernel_stack (with guard pages), which needs memory::init; until
+ * init_fault_stacks runs, both entries share a small static stack.
+ */
+pub const PAGE_FAULT_IST_INDEX: u16 = 2;
+pub const GENERAL_PROTECTION_FAULT_IST_INDEX: u16 = 3;
+const FAULT_STACK_PAGES: u64 = 4;
+const EARLY_FAULT_STACK_SIZE: usize = 4096 * 2;
 
 pub fn init() {
     use x86_64::instructions::segmentation::set_cs;
@@ -21,12 +42,12 @@ lazy_static! {
     /* On x86_64, the TSS doesn't really hold any task information. However, it does hold the Interrupt Stack Table (IST)
      * and the Privilege Stack Table (used for privilege level changes).
      */
-    static ref TSS: TaskStateSegment = {
+    static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
         // Set the Double Fault IST entry
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
             // TODO: There is no guard page underneath this stack, so don't do anything that could overflow it.
-            const STACK_SIZE: usize = 4096 * 5;
+            const STACK_SIZE: usize = DOUBLE_FAULT_STACK_SIZE;
             // Populate it with all zeroes
             // Why `mut`? Well, if we make it immutable then the bootloader will map this stack to a read-only page.
             // TODO: Why does that matter?
@@ -38,10 +59,69 @@ lazy_static! {
             // Since stacks grow downwards, return the low address (stack_end) 
             stack_end
         };
-        tss
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = {
+            static mut STACK: [u8; NMI_STACK_SIZE] = [0; NMI_STACK_SIZE];
+            VirtAddr::from_ptr(unsafe { &STACK }) + NMI_STACK_SIZE
+        };
+        let (early_fault_stack, size) = early_fault_stack();
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = early_fault_stack + size;
+        tss.interrupt_stack_table[GENERAL_PROTECTION_FAULT_IST_INDEX as usize] = early_fault_stack + size;
+        Tss(UnsafeCell::new(tss))
     };
 }
 
+// The CPU reads IST entries from the TSS in memory on every interrupt, so they can be changed after it's loaded
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// Only init_fault_stacks writes to it, once, with interrupts disabled
+unsafe impl Sync for Tss {}
+
+impl Tss {
+    fn get(&self) -> &TaskStateSegment {
+        unsafe { &*self.0.get() }
+    }
+}
+
+/* Replaces the early page fault and general protection fault stacks with guarded ones from the kernel stack
+ * allocator. Called by memory::init.
+ */
+pub fn init_fault_stacks() -> Result<(), KmapError> {
+    let page_fault = alloc_kernel_stack(FAULT_STACK_PAGES)?;
+    let general_protection_fault = alloc_kernel_stack(FAULT_STACK_PAGES)?;
+    let _guard = InterruptGuard::new();
+    // Interrupts are off, and nothing else writes to the TSS; the CPU only reads these entries on a fault
+    let tss = unsafe { &mut *TSS.0.get() };
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = page_fault.top();
+    tss.interrupt_stack_table[GENERAL_PROTECTION_FAULT_IST_INDEX as usize] = general_protection_fault.top();
+    Ok(())
+}
+
+// The top of IST stack `index`
+pub fn ist_stack_top(index: u16) -> VirtAddr {
+    TSS.get().interrupt_stack_table[index as usize]
+}
+
+// Returns the (lowest address, size) of the double fault stack, so it can be recorded as a memory region
+pub fn double_fault_stack() -> (VirtAddr, u64) {
+    let stack_end = ist_stack_top(DOUBLE_FAULT_IST_INDEX);
+    let size = DOUBLE_FAULT_STACK_SIZE as u64;
+    (stack_end - size, size)
+}
+
+static mut EARLY_FAULT_STACK: [u8; EARLY_FAULT_STACK_SIZE] = [0; EARLY_FAULT_STACK_SIZE];
+
+// Returns the (lowest address, size) of the stack page faults and GPFs use until init_fault_stacks
+pub fn early_fault_stack() -> (VirtAddr, u64) {
+    (VirtAddr::from_ptr(unsafe { &EARLY_FAULT_STACK }), EARLY_FAULT_STACK_SIZE as u64)
+}
+
+// Returns the (lowest address, size) of the NMI stack
+pub fn nmi_stack() -> (VirtAddr, u64) {
+    let stack_end = ist_stack_top(NMI_IST_INDEX);
+    let size = NMI_STACK_SIZE as u64;
+    (stack_end - size, size)
+}
+
 /* What is the GDT?
  * The Global Descriptor Table is a construct used by x86 to configure `segmented virtual memory.`
  * Segmented Virtual Memory is a memory management technique (like paging) that divides physical memory into 
@@ -58,7 +138,7 @@ lazy_static! {
         let mut gdt = GlobalDescriptorTable::new();
         // Create code and tss selector entries in the GDT, then return them as part of the static
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(TSS.get()));
         (gdt, Selectors { code_selector, tss_selector })
     };
 }
@@ -0,0 +1,248 @@
+/* The 8042 PS/2 controller, with the keyboard on its first port and (if it has one) the mouse on its second, the
+ * auxiliary port. Many UEFI and USB-only machines don't have one; there, port 0x64 usually reads as 0xFF (nothing
+ * drives the bus) and port 0x60 returns noise. If we unmasked IRQ 1 anyway, we'd either never hear from the keyboard
+ * or feed that noise to the scancode decoder.
+ *
+ * So init doesn't assume anything the firmware left behind. It disables both ports, flushes the output buffer, turns
+ * off the port IRQs in the configuration byte and runs the controller's self-test. It then finds out whether there's a
+ * second port, tests each port's interface and resets whatever device is attached. Only ports that pass the test and
+ * have a device that answers the reset are enabled again, with their IRQs; a port the controller won't enable counts
+ * as absent. Translation to scan code set 1 is turned on in the same configuration byte, since that's what the keyboard
+ * decoder expects and not all firmware leaves it on. Every wait has a timeout; a controller that doesn't answer is
+ * treated as absent, and keyboard input is then only available through the serial port.
+ * https://wiki.osdev.org/%228042%22_PS/2_Controller
+ */
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64; // Reads give the status register, writes send a controller command
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+// Set when the byte in the output buffer came from the second (auxiliary, i.e. mouse) port
+const STATUS_AUX_DATA: u8 = 1 << 5;
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_AUX: u8 = 0xa7;
+const COMMAND_ENABLE_AUX: u8 = 0xa8;
+const COMMAND_TEST_AUX: u8 = 0xa9;
+const COMMAND_SELF_TEST: u8 = 0xaa;
+const COMMAND_TEST_KEYBOARD: u8 = 0xab;
+const COMMAND_DISABLE_KEYBOARD: u8 = 0xad;
+const COMMAND_ENABLE_KEYBOARD: u8 = 0xae;
+// Sends the next byte written to port 0x60 to the auxiliary device instead of the keyboard
+pub const COMMAND_WRITE_AUX: u8 = 0xd4;
+
+const CONFIG_KEYBOARD_IRQ: u8 = 1 << 0;
+const CONFIG_AUX_IRQ: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+// The controller translates the keyboard's scancodes to set 1
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+const DEVICE_RESET: u8 = 0xff;
+const DEVICE_ACK: u8 = 0xfa;
+
+// How many status polls to wait for the controller; a present controller answers within a few
+const TIMEOUT_POLLS: u32 = 100_000;
+// Devices take much longer to reset (their self-test can take hundreds of milliseconds)
+const RESET_TIMEOUT_POLLS: u32 = 20 * TIMEOUT_POLLS;
+
+static PRESENT: AtomicBool = AtomicBool::new(false);
+static KEYBOARD_PORT: AtomicBool = AtomicBool::new(false);
+static AUX_PORT: AtomicBool = AtomicBool::new(false);
+
+// Which ports work and have a device attached
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Ports {
+    pub keyboard: bool,
+    pub aux: bool,
+}
+
+/* Initializes the controller and remembers what it found; None if there is no (working) controller. Call with
+ * interrupts disabled (or IRQs 1 and 12 masked), so the interrupt handlers can't consume the replies.
+ */
+pub fn init() -> Option<Ports> {
+    let ports = unsafe { initialize() };
+    PRESENT.store(ports.is_some(), Ordering::Relaxed);
+    KEYBOARD_PORT.store(ports.map_or(false, |ports| ports.keyboard), Ordering::Relaxed);
+    AUX_PORT.store(ports.map_or(false, |ports| ports.aux), Ordering::Relaxed);
+    ports
+}
+
+// Whether init found a PS/2 controller
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::Relaxed)
+}
+
+// Whether init found a keyboard on the first port
+pub fn has_keyboard() -> bool {
+    KEYBOARD_PORT.load(Ordering::Relaxed)
+}
+
+// Whether init found a device (normally a mouse) on the auxiliary port
+pub fn has_aux() -> bool {
+    AUX_PORT.load(Ordering::Relaxed)
+}
+
+/* Whether the controller has a byte waiting in its output buffer. The keyboard handler checks this before reading
+ * port 0x60, so a spurious IRQ 1 isn't decoded as a keypress.
+ */
+pub fn output_full() -> bool {
+    status() & STATUS_OUTPUT_FULL != 0
+}
+
+// Reads and discards whatever the controller has buffered (e.g. keys pressed while IRQ 1 was masked)
+pub fn drain_output() {
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    // The controller buffers at most a few bytes; a floating bus would read as "full" forever
+    for _ in 0..16 {
+        if !output_full() {
+            break;
+        }
+        unsafe { data.read() };
+    }
+}
+
+// Whether the byte waiting in the output buffer is from the mouse rather than the keyboard
+pub fn output_from_aux() -> bool {
+    status() & (STATUS_OUTPUT_FULL | STATUS_AUX_DATA) == STATUS_OUTPUT_FULL | STATUS_AUX_DATA
+}
+
+/* Sends a command to the controller itself (port 0x64). Returns false if the controller didn't take it in time.
+ * Unsafe because commands reconfigure the controller, which can disable the keyboard.
+ */
+pub unsafe fn send_command(command: u8) -> bool {
+    if !wait_for(|status| status & STATUS_INPUT_FULL == 0) {
+        return false;
+    }
+    Port::<u8>::new(STATUS_PORT).write(command);
+    true
+}
+
+// Writes a byte to port 0x60: a command's argument, or a byte for the keyboard. Unsafe like send_command.
+pub unsafe fn write_data(byte: u8) -> bool {
+    if !wait_for(|status| status & STATUS_INPUT_FULL == 0) {
+        return false;
+    }
+    Port::<u8>::new(DATA_PORT).write(byte);
+    true
+}
+
+// Waits for the next byte in the output buffer (a reply); None if nothing arrives in time
+pub fn read_data_timeout() -> Option<u8> {
+    if wait_for(|status| status & STATUS_OUTPUT_FULL != 0) {
+        Some(read_data())
+    } else {
+        None
+    }
+}
+
+pub fn read_config() -> Option<u8> {
+    // Unsafe because controller commands have side effects; reading the configuration byte doesn't change anything
+    if !unsafe { send_command(COMMAND_READ_CONFIG) } {
+        return None;
+    }
+    read_data_timeout()
+}
+
+// Unsafe because the configuration byte enables and disables the ports and their IRQs
+pub unsafe fn write_config(config: u8) -> bool {
+    send_command(COMMAND_WRITE_CONFIG) && write_data(config)
+}
+
+// Unsafe because it reconfigures the controller, which the keyboard and mouse drivers rely on
+unsafe fn initialize() -> Option<Ports> {
+    // A floating bus reads as all ones
+    if status() == 0xff {
+        return None;
+    }
+    // Nothing may send us bytes while we talk to the controller (sending to a missing second port is harmless)
+    if !send_command(COMMAND_DISABLE_KEYBOARD) || !send_command(COMMAND_DISABLE_AUX) {
+        return None;
+    }
+    // Drop whatever a key press left in the output buffer, so we don't mistake it for a reply
+    drain_output();
+    let mut config = read_config()?;
+    config &= !(CONFIG_KEYBOARD_IRQ | CONFIG_AUX_IRQ);
+    config |= CONFIG_TRANSLATION;
+    // With the second port disabled, its clock reads as disabled; if it doesn't, there is no second port
+    let maybe_dual = config & CONFIG_AUX_CLOCK_DISABLED != 0;
+    if !write_config(config) {
+        return None;
+    }
+    if command_reply(COMMAND_SELF_TEST)? != SELF_TEST_PASSED {
+        return None;
+    }
+    // The self-test resets some controllers
+    if !write_config(config) {
+        return None;
+    }
+    let has_aux_port = maybe_dual && send_command(COMMAND_ENABLE_AUX) && {
+        let enabled = read_config().map_or(false, |config| config & CONFIG_AUX_CLOCK_DISABLED == 0);
+        // A second port we can't disable again would send bytes while we test the first one
+        send_command(COMMAND_DISABLE_AUX) && enabled
+    };
+    let mut ports = Ports {
+        keyboard: command_reply(COMMAND_TEST_KEYBOARD) == Some(PORT_TEST_PASSED),
+        aux: has_aux_port && command_reply(COMMAND_TEST_AUX) == Some(PORT_TEST_PASSED),
+    };
+    if ports.keyboard {
+        ports.keyboard = send_command(COMMAND_ENABLE_KEYBOARD) && reset_device(false);
+    }
+    if ports.aux {
+        ports.aux = send_command(COMMAND_ENABLE_AUX) && reset_device(true);
+    }
+    if ports.keyboard {
+        config |= CONFIG_KEYBOARD_IRQ;
+    }
+    if ports.aux {
+        config = (config | CONFIG_AUX_IRQ) & !CONFIG_AUX_CLOCK_DISABLED;
+    }
+    if !write_config(config) {
+        return None;
+    }
+    Some(ports)
+}
+
+// Sends a controller command that answers with one byte
+unsafe fn command_reply(command: u8) -> Option<u8> {
+    if !send_command(command) {
+        return None;
+    }
+    read_data_timeout()
+}
+
+/* Resets the device on the keyboard or the auxiliary port. It ACKs, runs its self-test and reports the result (and a
+ * mouse its ID after that, which we drop). Returns whether a device answered.
+ */
+unsafe fn reset_device(aux: bool) -> bool {
+    if aux && !send_command(COMMAND_WRITE_AUX) {
+        return false;
+    }
+    if !write_data(DEVICE_RESET) || read_data_timeout() != Some(DEVICE_ACK) {
+        return false;
+    }
+    // The self-test result (0xAA if it passed); a device that fails it is still there
+    if !(0..RESET_TIMEOUT_POLLS).any(|_| status() & STATUS_OUTPUT_FULL != 0) {
+        return false;
+    }
+    read_data();
+    if aux {
+        read_data_timeout();
+    }
+    true
+}
+
+fn wait_for(condition: impl Fn(u8) -> bool) -> bool {
+    (0..TIMEOUT_POLLS).any(|_| condition(status()))
+}
+
+fn status() -> u8 {
+    unsafe { Port::<u8>::new(STATUS_PORT).read() }
+}
+
+fn read_data() -> u8 {
+    unsafe { Port::<u8>::new(DATA_PORT).read() }
+}
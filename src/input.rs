@@ -0,0 +1,241 @@
+/* Input events from every device, in one place. Drivers decode their own hardware (the keyboard its scancodes, a mouse
+ * its packets) and `publish` the result as an `InputEvent`; consumers (the console, a future shell, user programs)
+ * `subscribe` and never touch a device port.
+ *
+ * Events are published from deferred work, not from interrupt handlers, so subscribers run with interrupts enabled
+ * and may take locks (e.g. print). Every subscriber sees every event, in the order they were published.
+ *
+ * Events can still be lost before they're published: the keyboard handler drops scancodes when its queue is full, and
+ * the mouse handler drops packets when the deferred work queue is. Both are counted (see `stats`), and the drivers call
+ * `report_overflow` when they next run, which acts on new losses according to the overflow policy: by default it logs
+ * a warning, and it can beep instead, or stay quiet.
+ */
+use core::sync::atomic::{AtomicU8, Ordering};
+use pc_keyboard::KeyCode;
+use spin::Mutex;
+use crate::keyboard::{self, KeyPress, Modifiers};
+use crate::{mouse, time};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    KeyPress(KeyPress),
+    KeyRelease { code: KeyCode, modifiers: Modifiers },
+    // Relative movement; positive dy is up
+    MouseMove { dx: i16, dy: i16 },
+    MouseButton { button: MouseButton, pressed: bool },
+}
+
+pub type Subscriber = fn(InputEvent);
+
+/* A subscriber's slot, and the slot's generation when it subscribed. Slots are reused, so the generation is what
+ * tells an id from an earlier subscription (already unsubscribed) apart from the current one.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionId {
+    slot: usize,
+    generation: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputError {
+    // Every subscriber slot is taken
+    Full,
+}
+
+const MAX_SUBSCRIBERS: usize = 8;
+
+#[derive(Clone, Copy)]
+struct Slot {
+    subscriber: Option<Subscriber>,
+    // Bumped every time the slot is taken
+    generation: u32,
+}
+
+static SUBSCRIBERS: Mutex<[Slot; MAX_SUBSCRIBERS]> =
+    Mutex::new([Slot { subscriber: None, generation: 0 }; MAX_SUBSCRIBERS]);
+
+pub fn subscribe(subscriber: Subscriber) -> Result<SubscriptionId, InputError> {
+    let mut subscribers = SUBSCRIBERS.lock();
+    let slot = subscribers.iter().position(|slot| slot.subscriber.is_none()).ok_or(InputError::Full)?;
+    let entry = &mut subscribers[slot];
+    entry.generation = entry.generation.wrapping_add(1);
+    entry.subscriber = Some(subscriber);
+    Ok(SubscriptionId { slot, generation: entry.generation })
+}
+
+// Returns false if `id` was already unsubscribed, in which case whoever holds its slot now stays subscribed
+pub fn unsubscribe(id: SubscriptionId) -> bool {
+    let mut subscribers = SUBSCRIBERS.lock();
+    let entry = &mut subscribers[id.slot];
+    if entry.generation != id.generation || entry.subscriber.is_none() {
+        return false;
+    }
+    entry.subscriber = None;
+    true
+}
+
+// Delivers `event` to every subscriber. Called by drivers, outside interrupt context.
+pub fn publish(event: InputEvent) {
+    // Copied out, so subscribers can (un)subscribe while handling the event
+    let subscribers = *SUBSCRIBERS.lock();
+    for subscriber in subscribers.iter().filter_map(|slot| slot.subscriber) {
+        subscriber(event);
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InputStats {
+    // Scancodes dropped because the keyboard's queue was full
+    pub scancodes_dropped: u64,
+    // Mouse packets dropped because the deferred work queue was full
+    pub mouse_packets_dropped: u64,
+}
+
+impl InputStats {
+    pub fn total_dropped(&self) -> u64 {
+        self.scancodes_dropped + self.mouse_packets_dropped
+    }
+}
+
+pub fn stats() -> InputStats {
+    InputStats { scancodes_dropped: keyboard::dropped_scancodes(), mouse_packets_dropped: mouse::dropped_packets() }
+}
+
+// What report_overflow does about newly dropped input; the losses are counted either way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OverflowPolicy {
+    Count = 0,
+    Warn = 1,
+    Beep = 2,
+}
+
+static OVERFLOW_POLICY: AtomicU8 = AtomicU8::new(OverflowPolicy::Warn as u8);
+// The stats as of the last report, so each loss is only reported once
+static REPORTED: Mutex<InputStats> = Mutex::new(InputStats { scancodes_dropped: 0, mouse_packets_dropped: 0 });
+
+pub fn set_overflow_policy(policy: OverflowPolicy) {
+    OVERFLOW_POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+pub fn overflow_policy() -> OverflowPolicy {
+    match OVERFLOW_POLICY.load(Ordering::Relaxed) {
+        0 => OverflowPolicy::Count,
+        2 => OverflowPolicy::Beep,
+        _ => OverflowPolicy::Warn,
+    }
+}
+
+// Acts on input dropped since the last call. Called by the drivers from deferred work, not from interrupt handlers.
+pub(crate) fn report_overflow() {
+    report_dropped(stats());
+}
+
+// report_overflow, given the current stats
+fn report_dropped(stats: InputStats) {
+    let new = {
+        let mut reported = REPORTED.lock();
+        if stats.total_dropped() == reported.total_dropped() {
+            return;
+        }
+        let new = InputStats {
+            scancodes_dropped: stats.scancodes_dropped - reported.scancodes_dropped,
+            mouse_packets_dropped: stats.mouse_packets_dropped - reported.mouse_packets_dropped,
+        };
+        *reported = stats;
+        new
+    };
+    match overflow_policy() {
+        OverflowPolicy::Count => {},
+        OverflowPolicy::Warn => log::warn!("consumer too slow, dropped {} scancodes and {} mouse packets ({} in total)",
+            new.scancodes_dropped, new.mouse_packets_dropped, stats.total_dropped()),
+        OverflowPolicy::Beep => {
+            time::speaker::beep(880, 50);
+        },
+    }
+}
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_subscribers_see_published_events() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static MOVES: AtomicUsize = AtomicUsize::new(0);
+    fn count_moves(event: InputEvent) {
+        if let InputEvent::MouseMove { .. } = event {
+            MOVES.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    let id = subscribe(count_moves).expect("no free subscriber slot");
+    publish(InputEvent::MouseMove { dx: 1, dy: -1 });
+    publish(InputEvent::MouseButton { button: MouseButton::Left, pressed: true });
+    assert_eq!(MOVES.load(Ordering::SeqCst), 1);
+    assert!(unsubscribe(id));
+    publish(InputEvent::MouseMove { dx: 1, dy: -1 });
+    assert_eq!(MOVES.load(Ordering::SeqCst), 1);
+}
+
+#[test_case]
+fn test_stale_id_does_not_unsubscribe_the_slots_new_owner() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static EVENTS: AtomicUsize = AtomicUsize::new(0);
+    fn ignore(_: InputEvent) {}
+    fn count(_: InputEvent) {
+        EVENTS.fetch_add(1, Ordering::SeqCst);
+    }
+    let stale = subscribe(ignore).expect("no free subscriber slot");
+    assert!(unsubscribe(stale));
+    // Takes the slot the first subscriber just gave up
+    let id = subscribe(count).expect("no free subscriber slot");
+    assert_eq!(id.slot, stale.slot);
+    assert!(!unsubscribe(stale));
+    publish(InputEvent::MouseMove { dx: 1, dy: 0 });
+    assert_eq!(EVENTS.load(Ordering::SeqCst), 1);
+    assert!(unsubscribe(id));
+}
+
+#[test_case]
+fn test_overflow_policy() {
+    assert_eq!(overflow_policy(), OverflowPolicy::Warn);
+    set_overflow_policy(OverflowPolicy::Count);
+    assert_eq!(overflow_policy(), OverflowPolicy::Count);
+    set_overflow_policy(OverflowPolicy::Warn);
+}
+
+#[test_case]
+fn test_only_new_drops_are_reported() {
+    // Whether the newest line in the kernel message buffer contains `text`
+    fn last_logged(text: &[u8]) -> bool {
+        let mut out = [0u8; 1024];
+        let count = crate::klog::read(&mut out);
+        let lines = &out[..count.saturating_sub(1)];
+        let start = lines.iter().rposition(|&byte| byte == b'\n').map_or(0, |newline| newline + 1);
+        lines[start..].windows(text.len()).any(|window| window == text)
+    }
+    let before = *REPORTED.lock();
+    let dropped = |scancodes: u64, packets: u64| InputStats {
+        scancodes_dropped: before.scancodes_dropped + scancodes,
+        mouse_packets_dropped: before.mouse_packets_dropped + packets,
+    };
+    report_dropped(dropped(3, 1));
+    assert_eq!(*REPORTED.lock(), dropped(3, 1));
+    assert!(last_logged(b"dropped 3 scancodes and 1 mouse packets"));
+    // Only what was dropped since
+    report_dropped(dropped(5, 1));
+    assert_eq!(*REPORTED.lock(), dropped(5, 1));
+    assert!(last_logged(b"dropped 2 scancodes and 0 mouse packets"));
+    // Nothing new, nothing reported
+    crate::println!("test_only_new_drops_are_reported");
+    report_dropped(dropped(5, 1));
+    assert!(last_logged(b"test_only_new_drops_are_reported"));
+    // Back to the real counts, or the next real report would count from the made up ones
+    *REPORTED.lock() = before;
+}
@@ -0,0 +1,212 @@
+/* Kernel image self-check. Wild pointer writes and flaky RAM both show up as bytes of the kernel's code or read-only
+ * data changing underneath us, usually long before anything crashes in an obvious way. We checksum every read-only
+ * loadable segment of the kernel ELF (.text, .rodata, ...) and panic with the address range that no longer matches.
+ *
+ * The reference checksums come from the build: once the kernel is linked, tools/embed_checksum.py (run by
+ * tools/runner.sh, Cargo's runner for the kernel) checksums the read-only segments in the ELF file and writes them
+ * into IMAGE_CHECKSUMS. That table is in .data, which isn't checksummed itself, so patching it doesn't change what
+ * it describes. verify_build_checksums compares the loaded image against it at boot, which catches corruption that
+ * happened before the kernel got to run (a bad load, bad RAM under the image) as well as after.
+ * After that, init() takes finer checksums in 4 KiB chunks, and verify() re-checks those periodically, so a later
+ * corruption is reported with the range of the chunk it hit.
+ *
+ * The segments are found through the ELF program headers, which the linker places at `__ehdr_start` at the start of
+ * the first loaded segment.
+ */
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Once;
+
+const CHUNK_SIZE: usize = 4096;
+// Verify roughly every 10 seconds when driven from the idle loop
+pub const CHECK_INTERVAL_MILLIS: u64 = 10_000;
+
+const PT_LOAD: u32 = 1;
+const PF_W: u32 = 2;
+
+extern "C" {
+    static __ehdr_start: u8;
+}
+
+struct Chunk {
+    start: usize,
+    len: usize,
+    checksum: u64,
+}
+
+static CHUNKS: Once<Vec<Chunk>> = Once::new();
+
+// Read-only segments the embedded table has room for; tools/embed_checksum.py fails the build on more
+const MAX_SEGMENTS: usize = 8;
+// Marks the table for tools/embed_checksum.py, which looks for it in the image's writable segments
+const EMBEDDED_MAGIC: u64 = 0x314d_5553_474d_494b; // "KIMGSUM1"
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SegmentChecksum {
+    start: u64,
+    len: u64,
+    checksum: u64,
+}
+
+#[repr(C)]
+struct EmbeddedChecksums {
+    magic: u64,
+    // How many entries of `segments` the build filled in; 0 if the image never went through the build step
+    count: u64,
+    segments: [SegmentChecksum; MAX_SEGMENTS],
+}
+
+// Filled in after linking, so it's only ever read through volatile reads (see build_checksums)
+#[used]
+#[link_section = ".data.integrity"]
+static mut IMAGE_CHECKSUMS: EmbeddedChecksums = EmbeddedChecksums {
+    magic: EMBEDDED_MAGIC,
+    count: 0,
+    segments: [SegmentChecksum { start: 0, len: 0, checksum: 0 }; MAX_SEGMENTS],
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityError {
+    // init() hasn't run, or the ELF header isn't mapped where we expect it
+    NotInitialized,
+    // The image was booted without going through tools/embed_checksum.py
+    NotEmbedded,
+    // The bytes in [start, end) no longer match their checksum
+    Corrupted { start: usize, end: usize },
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IntegrityError::NotInitialized => write!(f, "kernel image checksums not initialized"),
+            IntegrityError::NotEmbedded => write!(f, "no build-time kernel image checksums embedded"),
+            IntegrityError::Corrupted { start, end } => {
+                write!(f, "kernel image corrupted in {:#x}..{:#x}", start, end)
+            }
+        }
+    }
+}
+
+// Checks the loaded image against the checksums the build embedded, a segment at a time
+pub fn verify_build_checksums() -> Result<(), IntegrityError> {
+    let (count, segments) = build_checksums();
+    if count == 0 {
+        return Err(IntegrityError::NotEmbedded);
+    }
+    for segment in segments.iter().take(count) {
+        let (start, len) = (segment.start as usize, segment.len as usize);
+        if checksum_range(start, len) != segment.checksum {
+            return Err(IntegrityError::Corrupted { start, end: start + len });
+        }
+    }
+    Ok(())
+}
+
+fn build_checksums() -> (usize, [SegmentChecksum; MAX_SEGMENTS]) {
+    // Volatile, or the compiler would fold in the values the table had before the build step patched it
+    unsafe {
+        let count = core::ptr::read_volatile(&IMAGE_CHECKSUMS.count) as usize;
+        (count.min(MAX_SEGMENTS), core::ptr::read_volatile(&IMAGE_CHECKSUMS.segments))
+    }
+}
+
+/* Records the chunk checksums verify() compares against. Call verify_build_checksums first, so they're taken from an
+ * image known to be intact. Needs the heap, so call it after allocator::init_heap.
+ * Returns the number of bytes covered, or 0 if the ELF header couldn't be found.
+ */
+pub fn init() -> usize {
+    let chunks = CHUNKS.call_once(|| {
+        let mut chunks = Vec::new();
+        for_each_read_only_segment(|start, len| {
+            let mut offset = 0;
+            while offset < len {
+                let chunk_len = CHUNK_SIZE.min(len - offset);
+                let chunk_start = start + offset;
+                chunks.push(Chunk { start: chunk_start, len: chunk_len, checksum: checksum_range(chunk_start, chunk_len) });
+                offset += chunk_len;
+            }
+        });
+        chunks
+    });
+    chunks.iter().map(|chunk| chunk.len).sum()
+}
+
+// Re-checksums the whole image and reports the first chunk that changed
+pub fn verify() -> Result<(), IntegrityError> {
+    let chunks = CHUNKS.r#try().filter(|chunks| !chunks.is_empty()).ok_or(IntegrityError::NotInitialized)?;
+    for chunk in chunks.iter() {
+        if checksum_range(chunk.start, chunk.len) != chunk.checksum {
+            return Err(IntegrityError::Corrupted { start: chunk.start, end: chunk.start + chunk.len });
+        }
+    }
+    Ok(())
+}
+
+// Panics if the image was corrupted since init(); does nothing if init() never ran
+pub fn check() {
+    if let Err(IntegrityError::Corrupted { start, end }) = verify() {
+        panic!("{}", IntegrityError::Corrupted { start, end });
+    }
+}
+
+/* Runs check() if at least CHECK_INTERVAL_MILLIS milliseconds passed since the last time it did. Meant to be called
+ * from the idle loop, so the check only ever uses otherwise idle CPU time.
+ */
+pub fn check_if_due() {
+    use core::sync::atomic::{AtomicU64, Ordering};
+    static LAST_CHECK: AtomicU64 = AtomicU64::new(0);
+    let now = crate::time::uptime_millis();
+    if now.wrapping_sub(LAST_CHECK.load(Ordering::Relaxed)) >= CHECK_INTERVAL_MILLIS {
+        LAST_CHECK.store(now, Ordering::Relaxed);
+        check();
+    }
+}
+
+// Calls `f(start, len)` for every loadable, non-writable segment of the running kernel
+fn for_each_read_only_segment(mut f: impl FnMut(usize, usize)) {
+    let ehdr = unsafe { &__ehdr_start as *const u8 };
+    let read_u16 = |addr: *const u8| unsafe { (addr as *const u16).read_unaligned() };
+    let read_u32 = |addr: *const u8| unsafe { (addr as *const u32).read_unaligned() };
+    let read_u64 = |addr: *const u8| unsafe { (addr as *const u64).read_unaligned() };
+
+    if unsafe { core::slice::from_raw_parts(ehdr, 4) } != b"\x7fELF" {
+        return;
+    }
+    let phoff = read_u64(unsafe { ehdr.add(0x20) }) as usize;
+    let phentsize = read_u16(unsafe { ehdr.add(0x36) }) as usize;
+    let phnum = read_u16(unsafe { ehdr.add(0x38) }) as usize;
+    for i in 0..phnum {
+        let phdr = unsafe { ehdr.add(phoff + i * phentsize) };
+        let p_type = read_u32(phdr);
+        let p_flags = read_u32(unsafe { phdr.add(4) });
+        let p_vaddr = read_u64(unsafe { phdr.add(16) }) as usize;
+        let p_filesz = read_u64(unsafe { phdr.add(32) }) as usize;
+        if p_type == PT_LOAD && p_flags & PF_W == 0 && p_filesz != 0 {
+            f(p_vaddr, p_filesz);
+        }
+    }
+}
+
+fn checksum_range(start: usize, len: usize) -> u64 {
+    fnv1a(unsafe { core::slice::from_raw_parts(start as *const u8, len) })
+}
+
+// 64-bit FNV-1a: cheap, and good enough to notice flipped bits (this is not a defence against tampering)
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+// * TESTS *
+
+#[test_case]
+fn test_fnv1a_known_values() {
+    assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+}
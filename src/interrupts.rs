@@ -20,12 +20,22 @@
  * Because of this, interrupts have to adhere to a separate calling convention from the
  * 'traditional' C calling convention: `x86-interrupt`.
 */
+pub mod vectors; // Dynamically allocated vectors for drivers
+mod exceptions; // Every other CPU exception
+pub mod apic; // Local APIC, and routing device IRQs through the IOAPIC instead of the PICs
+pub mod ioapic; // IOAPIC redirection tables
+pub mod msi; // Message signaled interrupts for PCI devices
+pub mod mce; // Machine check exceptions, decoded from the MCA banks
+
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-use crate::{println, print}; // our println function defined in lib.rs
+use crate::println; // our println function defined in lib.rs
 use crate::gdt; // Have to load the GDT double fault stack when handling a double fault
 use lazy_static::lazy_static; // So the IDT can be loaded and valid for the lifetime of the OS
 use pic8259_simple::ChainedPics; // chains primary and secondary PICs together
 use spin; // Mutex
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::time;
+use crate::sync::InterruptGuard;
 
 
 /* PICs by default send interrupt vectors in the range [0, 15]; However, this conflicts with the CPU exception interrupt
@@ -70,7 +80,17 @@ lazy_static! {
         // We can do this because InterruptDescriptorTable implements IndexMut (https://doc.rust-lang.org/core/ops/trait.IndexMut.html)
         idt[InterruptIndex::Timer.cast_to_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.cast_to_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        unsafe {
+            idt.page_fault
+              .set_handler_fn(page_fault_handler)
+              .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        }
+        exceptions::register(&mut idt);
+        // Every other PIC line and a few spare vectors are dispatched to whichever driver claimed them
+        for &(vector, stub) in vectors::STUBS.iter() {
+            idt[vector as usize].set_handler_fn(stub);
+        }
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(apic::spurious_interrupt_handler);
         idt
     };
 }
@@ -79,6 +99,69 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/* Acknowledges the hardware interrupt on `vector`, to whichever controller delivered it. Handlers for IRQs must call
+ * this before returning, or the line (or with the APIC, every lower priority interrupt) stays blocked.
+ */
+pub fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        // Unsafe because using the wrong interrupt index could delete an interrupt or hang the system
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+    }
+}
+
+/* Silence or re-enable ISA IRQ line `irq` (0-15), e.g. while a driver reconfigures its device. Works on whichever
+ * controller currently delivers the line: the IOAPIC once `apic::init` routed IRQs through it, the PICs before.
+ */
+pub fn mask(irq: u8) {
+    assert!(irq < 16, "IRQ {} isn't an ISA IRQ", irq);
+    // The PIC mask is a read-modify-write that an interrupt handler masking another line mustn't interleave with
+    let _guard = InterruptGuard::new();
+    vectors::set_pic_masked(irq, true);
+}
+
+pub fn unmask(irq: u8) {
+    assert!(irq < 16, "IRQ {} isn't an ISA IRQ", irq);
+    let _guard = InterruptGuard::new();
+    vectors::set_pic_masked(irq, false);
+}
+
+/* Puts the interrupt controllers back into the state init() leaves them in, for integration tests that deliberately
+ * leave a mess (masked or unacknowledged lines, a retuned timer) and then keep testing in the same binary. Every
+ * source is masked first, so nothing arrives half way; whatever was already in service gets its EOI, the PS/2
+ * controller's pending bytes are thrown away, and the PICs are reprogrammed. Then only the timer (and the keyboard, if
+ * there is a PS/2 controller) is unmasked again, if its feature is enabled, on whichever controller delivers ISA IRQs.
+ * Vectors claimed through `vectors` stay claimed, but their lines end up masked. Leaves interrupts enabled.
+ */
+pub fn reset_for_test() {
+    {
+        let _guard = InterruptGuard::new();
+        apic::timer::stop();
+        for irq in 0..16 {
+            vectors::set_pic_masked(irq, true);
+        }
+        vectors::set_pic_masks(0xffff);
+        if apic::is_enabled() {
+            apic::drain_in_service();
+        }
+        vectors::drain_pic_in_service();
+        crate::i8042::drain_output();
+        // Unsafe because the offsets must not overlap the CPU exceptions; they're the ones we always use
+        unsafe { PICS.lock().initialize() };
+        // initialize() restores the masks it found, which are all set; with the APIC in charge they stay that way
+        vectors::set_pic_masks(0xffff);
+        time::set_frequency(time::DEFAULT_FREQUENCY_HZ);
+        if cfg!(feature = "timer") {
+            unmask(0);
+        }
+        if cfg!(feature = "keyboard") && crate::i8042::has_keyboard() {
+            unmask(1);
+        }
+    }
+    x86_64::instructions::interrupts::enable();
+}
+
 // **********************
 // * INTERRUPT HANDLERS *
 // **********************
@@ -90,58 +173,61 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFra
 
 /* Double faults occur when an exception is triggered while handling an exception. If another fault occurs in the 
  * double fault handler, then a triple fault occurs, which usually results in a hardware reset.
+ * The saved instruction pointer is architecturally undefined for a double fault, but CPUs (and QEMU) save the one the
+ * second fault hit, which is what tells one double fault from another in the crash signature.
  */
 extern "x86-interrupt" fn double_fault_handler(stack_frame: &mut InterruptStackFrame, _error_code: u64) -> ! {
+    crate::crash::record_fault_without_stack(8, stack_frame);
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
+/* Instead of printing on every tick, the timer handler counts ticks (see time.rs) and only has the status bar redrawn
+ * when the uptime reaches a new second. This keeps the console readable, and the redraw (which takes the WRITER and
+ * allocator locks) runs as deferred work once a second instead of in every interrupt, however fast the timer runs.
+ */
+static HEARTBEAT: AtomicBool = AtomicBool::new(true);
+
+pub fn set_heartbeat(enabled: bool) {
+    HEARTBEAT.store(enabled, Ordering::Relaxed);
+}
+
 extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: &mut InterruptStackFrame) -> () {
-    print!(".");
+    let ticks = time::tick();
+    // Without this the APIC timer (if it took over the tick) would stop after one interrupt
+    apic::timer::rearm();
+    let uptime_secs = time::ticks_to_millis(ticks) / 1000;
+    if HEARTBEAT.load(Ordering::Relaxed) && uptime_secs != time::ticks_to_millis(ticks - 1) / 1000 {
+        // If the deferred queue is full, the next second's update catches up
+        crate::deferred::schedule(crate::status_bar::update, 0);
+    }
     // notify that we're done processing the timer interrupt
-    // Unsafe because using the wrong interrupt index could delete an interrupt or hang the system
-    unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer as u8) };
+    end_of_interrupt(InterruptIndex::Timer as u8);
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: &mut InterruptStackFrame) -> () {
     use x86_64::instructions::port::Port;
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
-    // Initialize pc_keyboard to handle scancodes
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore));
+    // Nothing to read (a spurious IRQ 1), or a mouse byte the mouse handler will pick up: not a key press either way
+    if !crate::i8042::output_full() || crate::i8042::output_from_aux() {
+        end_of_interrupt(InterruptIndex::Keyboard as u8);
+        return;
     }
-    let mut keyboard = KEYBOARD.lock();
     // 0x60 corresponds to the PS/2 data I/O port
     let mut port = Port::new(0x60);
-    /* The keyboard sends us a scancode, which represents a key press or depress, according to this table (using the
-     * Scan Code Set 1): https://wiki.osdev.org/Keyboard#Scan_Code_Set_1
-     */
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(c) => print!("{}", c),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
-    }
-    unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard as u8) };
+    // Decoding and printing take locks normal code also holds, so they run later, outside the interrupt
+    crate::keyboard::add_scancode(scancode);
+    end_of_interrupt(InterruptIndex::Keyboard as u8);
 }
 
 use x86_64::structures::idt::PageFaultErrorCode;
-use crate::hlt_loop;
 
 extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut InterruptStackFrame, error_code: PageFaultErrorCode) {
     // The cr2 register is populated with the memory address that caused the page fault
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
-
+    crate::crash::record_fault(14, stack_frame);
+    panic!("EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\n{:#?}", Cr2::read(), error_code,
+        stack_frame);
 }
 
 // *********
@@ -151,3 +237,17 @@ extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut InterruptStackFra
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
+
+#[test_case]
+fn test_mask_and_unmask() {
+    // IRQ 5 (a second parallel port or sound card) has no driver, so unmasking it briefly is harmless
+    let before = vectors::pic_masks();
+    unmask(5);
+    assert_eq!(vectors::pic_masks() & (1 << 5), 0);
+    mask(5);
+    assert_ne!(vectors::pic_masks() & (1 << 5), 0);
+    // Masking on the secondary PIC leaves the primary alone
+    mask(12);
+    assert_eq!(vectors::pic_masks() & 0xff, before & 0xff | 1 << 5);
+    vectors::set_pic_masks(before);
+}
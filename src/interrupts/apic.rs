@@ -0,0 +1,212 @@
+/* Switching from the 8259 PICs to the APICs. Each CPU has a local APIC that receives interrupts and needs an EOI
+ * for each one; external device IRQs reach it through the IOAPIC(s), whose pins are described by the ACPI MADT.
+ *
+ * If CPUID says the CPU supports x2APIC mode, we switch the local APIC to it: its registers are then MSRs (0x800 +
+ * MMIO offset / 16) instead of an MMIO page, and APIC IDs are 32 bits wide.
+ *
+ * `init` keeps the vector layout the PICs used (ISA IRQ n on vector PIC_1_OFFSET + n), so the existing handlers and
+ * the dynamic vectors keep working unchanged. It only changes who needs the EOI, which is why handlers must call
+ * `interrupts::end_of_interrupt` rather than talking to the PICs directly.
+ * https://wiki.osdev.org/APIC
+ */
+pub mod timer; // The local APIC timer, which can take over the timer tick from the PIT
+
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::PhysAddr;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::acpi::{self, AcpiError};
+use crate::memory::{map_mmio, KmapError, Mmio};
+use crate::sync::InterruptGuard;
+use super::ioapic::{self, IoApic, Redirection};
+use super::PIC_1_OFFSET;
+
+// Local APIC registers (offsets into its MMIO page)
+const REG_ID: u64 = 0x20;
+const REG_EOI: u64 = 0xb0;
+const REG_SPURIOUS: u64 = 0xf0;
+// In-service register: 256 bits, in eight 32-bit registers 0x10 apart
+const REG_ISR: u64 = 0x100;
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+// The local APIC raises this when an interrupt goes away before it can be delivered; it must not get an EOI
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LOCAL_APIC: Mutex<Option<LocalApic>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub enum ApicError {
+    Acpi(AcpiError),
+    NoIoApic,
+    Map(KmapError),
+}
+
+pub enum LocalApic {
+    XApic(Mmio<u32>),
+    X2Apic,
+}
+
+impl LocalApic {
+    // Switches the local APIC to x2APIC mode if the CPU supports it, and maps its registers otherwise
+    fn new(mmio_base: PhysAddr) -> Result<LocalApic, KmapError> {
+        if !supports_x2apic() {
+            return Ok(LocalApic::XApic(map_mmio(mmio_base, 0x400)?));
+        }
+        // Unsafe because changing the APIC mode under a running interrupt source could lose interrupts; we're
+        // called with the PICs still in charge, so nothing is delivered through the local APIC yet
+        unsafe {
+            let mut base = Msr::new(IA32_APIC_BASE);
+            let value = base.read();
+            // xAPIC has to be enabled before (or together with) x2APIC
+            base.write(value | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+        }
+        Ok(LocalApic::X2Apic)
+    }
+
+    pub fn is_x2apic(&self) -> bool {
+        matches!(self, LocalApic::X2Apic)
+    }
+
+    pub fn id(&self) -> u32 {
+        match self {
+            LocalApic::XApic(regs) => regs.read(REG_ID) >> 24,
+            LocalApic::X2Apic => self.read(REG_ID),
+        }
+    }
+
+    fn read(&self, reg: u64) -> u32 {
+        match self {
+            LocalApic::XApic(regs) => regs.read(reg),
+            // Unsafe because reading an MSR that doesn't exist faults; x2APIC mode guarantees these do
+            LocalApic::X2Apic => unsafe { Msr::new(x2apic_msr(reg)).read() as u32 },
+        }
+    }
+
+    fn write(&mut self, reg: u64, value: u32) {
+        match self {
+            LocalApic::XApic(regs) => regs.write(reg, value),
+            LocalApic::X2Apic => unsafe { Msr::new(x2apic_msr(reg)).write(value as u64) },
+        }
+    }
+
+    fn end_of_interrupt(&mut self) {
+        self.write(REG_EOI, 0);
+    }
+}
+
+fn x2apic_msr(reg: u64) -> u32 {
+    X2APIC_MSR_BASE + (reg >> 4) as u32
+}
+
+// CPUID leaf 1, ECX bit 21
+pub fn supports_x2apic() -> bool {
+    supports_cpuid_feature(21)
+}
+
+fn supports_cpuid_feature(ecx_bit: u32) -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << ecx_bit) != 0
+}
+
+// Whether the local APIC runs in x2APIC mode (only meaningful once init succeeded)
+pub fn is_x2apic() -> bool {
+    let _guard = InterruptGuard::new();
+    let local_apic = LOCAL_APIC.lock();
+    local_apic.as_ref().map_or(false, |local_apic| local_apic.is_x2apic())
+}
+
+// The boot CPU's APIC ID, or None before init
+pub fn local_apic_id() -> Option<u32> {
+    let _guard = InterruptGuard::new();
+    let local_apic = LOCAL_APIC.lock();
+    local_apic.as_ref().map(|local_apic| local_apic.id())
+}
+
+// Whether interrupts are delivered through the APICs (true after a successful init)
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/* Routes the ISA IRQs through the IOAPIC and disables the PICs. Needs memory::init (for ACPI and the MMIO mappings).
+ * On failure nothing has changed and the PICs stay in charge.
+ */
+pub fn init() -> Result<(), ApicError> {
+    let madt = acpi::madt::madt().map_err(ApicError::Acpi)?;
+    let first = madt.io_apics().next().ok_or(ApicError::NoIoApic)?;
+    let mut io_apic = IoApic::new(&first).map_err(ApicError::Map)?;
+    let mut local_apic = LocalApic::new(madt.local_apic_address).map_err(ApicError::Map)?;
+    // IOAPIC physical destinations are 8 bits; fine for the boot CPU, which always has a small ID
+    let destination = local_apic.id() as u8;
+
+    {
+        let _guard = InterruptGuard::new();
+        // Take over each ISA line in the state the PIC had it (masked or not), then mask the PICs entirely
+        let pic_masks = super::vectors::pic_masks();
+        for irq in 0..16u8 {
+            let line = madt.isa_irq(irq);
+            // The cascade line doesn't exist without the PICs
+            if irq == 2 || !io_apic.handles(line.gsi) {
+                continue;
+            }
+            io_apic.set_redirection(line.gsi, Redirection {
+                vector: PIC_1_OFFSET + irq,
+                polarity: line.polarity,
+                trigger: line.trigger,
+                masked: pic_masks & (1 << irq) != 0,
+                destination,
+            });
+        }
+        super::vectors::set_pic_masks(0xffff);
+
+        let spurious = local_apic.read(REG_SPURIOUS);
+        local_apic.write(REG_SPURIOUS, spurious | SPURIOUS_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+        *LOCAL_APIC.lock() = Some(local_apic);
+        ioapic::add(io_apic);
+        ENABLED.store(true, Ordering::SeqCst);
+    }
+    // Any further IOAPICs are registered (and left fully masked) so their pins can be routed later
+    for entry in madt.io_apics().skip(1) {
+        ioapic::add(IoApic::new(&entry).map_err(ApicError::Map)?);
+    }
+    Ok(())
+}
+
+// Acknowledges the interrupt being handled. Only call this from interrupt handlers (interrupts disabled).
+pub(super) fn end_of_interrupt() {
+    if let Some(local_apic) = LOCAL_APIC.lock().as_mut() {
+        local_apic.end_of_interrupt();
+    }
+}
+
+/* Acknowledges every interrupt the local APIC still has in service, e.g. because a test's handler never got to
+ * send its EOI. Each EOI retires the highest priority one.
+ */
+pub(super) fn drain_in_service() {
+    if let Some(local_apic) = LOCAL_APIC.lock().as_mut() {
+        for _ in 0..256 {
+            if (0..8).all(|i| local_apic.read(REG_ISR + 0x10 * i) == 0) {
+                break;
+            }
+            local_apic.end_of_interrupt();
+        }
+    }
+}
+
+/* Masks or unmasks ISA line `irq` at the IOAPIC. Returns false if the line isn't routed through an IOAPIC.
+ */
+pub(super) fn set_isa_masked(irq: u8, masked: bool) -> bool {
+    let gsi = match acpi::madt::madt() {
+        Ok(madt) => madt.isa_irq(irq).gsi,
+        Err(_) => return false,
+    };
+    ioapic::with_io_apic_for(gsi, |io_apic| io_apic.set_masked(gsi, masked)).is_some()
+}
+
+pub(super) extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {}
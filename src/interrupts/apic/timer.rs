@@ -0,0 +1,158 @@
+/* The local APIC timer as the source of the timer tick. Each CPU has its own APIC timer, so unlike the PIT it can
+ * drive a per-CPU preemption tick later on, and it doesn't need the slow port I/O the PIT does.
+ *
+ * Two modes are supported, both re-armed from the timer interrupt:
+ * - TSC-deadline: the interrupt fires when the TSC reaches the value in IA32_TSC_DEADLINE. Deadlines are spaced
+ *   exactly one interval apart, so the tick doesn't drift. Needs CPUID support and a calibrated TSC.
+ * - One-shot: the timer counts down from an initial count at a rate we have to measure first, against time::nanos.
+ *
+ * The timer raises the same vector the PIT did, so the tick handler is unchanged; starting it masks IRQ 0 and
+ * stopping it unmasks IRQ 0 again.
+ */
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use x86_64::registers::model_specific::Msr;
+use crate::interrupts::InterruptIndex;
+use crate::sync::InterruptGuard;
+use crate::time;
+use super::{supports_cpuid_feature, LOCAL_APIC};
+
+const REG_LVT_TIMER: u64 = 0x320;
+const REG_INITIAL_COUNT: u64 = 0x380;
+const REG_CURRENT_COUNT: u64 = 0x390;
+const REG_DIVIDE: u64 = 0x3e0;
+const DIVIDE_BY_16: u32 = 0b0011;
+const LVT_MASKED: u32 = 1 << 16;
+// Bits 17-18 select the mode; one-shot is 0b00
+const LVT_MODE_ONESHOT: u32 = 0;
+const LVT_MODE_TSC_DEADLINE: u32 = 0b10 << 17;
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+const CALIBRATION_NANOS: u64 = 10_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot,
+    TscDeadline,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    // The timer interrupt would go to a local APIC nobody acknowledges
+    ApicDisabled,
+    // The interval has to be between 1 µs and 1 s
+    InvalidInterval,
+    // The timer didn't count down during calibration
+    Calibration,
+}
+
+// 0 while the PIT drives the tick, otherwise 1 + the TimerMode
+static MODE: AtomicU8 = AtomicU8::new(0);
+// The interval in APIC timer counts (one-shot) or TSC cycles (TSC-deadline)
+static INTERVAL: AtomicU64 = AtomicU64::new(0);
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+// The mode the APIC timer runs in, or None while the PIT drives the tick
+pub fn mode() -> Option<TimerMode> {
+    match MODE.load(Ordering::Relaxed) {
+        1 => Some(TimerMode::OneShot),
+        2 => Some(TimerMode::TscDeadline),
+        _ => None,
+    }
+}
+
+/* Moves the timer tick from the PIT to the APIC timer, with one tick every `interval_micros` microseconds. Uses
+ * TSC-deadline mode if it can. Calling it again changes the interval.
+ */
+pub fn start(interval_micros: u64) -> Result<TimerMode, TimerError> {
+    if !super::is_enabled() {
+        return Err(TimerError::ApicDisabled);
+    }
+    if interval_micros == 0 || interval_micros > 1_000_000 {
+        return Err(TimerError::InvalidInterval);
+    }
+    let (mode, interval) = match time::tsc::khz() {
+        // CPUID leaf 1, ECX bit 24
+        Some(khz) if supports_cpuid_feature(24) => (TimerMode::TscDeadline, khz * interval_micros / 1000),
+        _ => (TimerMode::OneShot, calibrate()? * interval_micros / 1000),
+    };
+    if interval == 0 {
+        return Err(TimerError::Calibration);
+    }
+    let lvt_mode = match mode {
+        TimerMode::OneShot => LVT_MODE_ONESHOT,
+        TimerMode::TscDeadline => LVT_MODE_TSC_DEADLINE,
+    };
+    let _guard = InterruptGuard::new();
+    super::set_isa_masked(0, true);
+    if let Some(local_apic) = LOCAL_APIC.lock().as_mut() {
+        local_apic.write(REG_DIVIDE, DIVIDE_BY_16);
+        local_apic.write(REG_LVT_TIMER, lvt_mode | InterruptIndex::Timer as u32);
+    }
+    INTERVAL.store(interval, Ordering::Relaxed);
+    NEXT_DEADLINE.store(time::tsc::read(), Ordering::Relaxed);
+    MODE.store(mode as u8 + 1, Ordering::SeqCst);
+    time::set_tick_frequency(1_000_000_000 / interval_micros);
+    rearm();
+    Ok(mode)
+}
+
+// Stops the APIC timer and hands the tick back to the PIT, at the rate it was programmed to before
+pub fn stop() {
+    let _guard = InterruptGuard::new();
+    if MODE.swap(0, Ordering::SeqCst) == 0 {
+        return;
+    }
+    if let Some(local_apic) = LOCAL_APIC.lock().as_mut() {
+        local_apic.write(REG_LVT_TIMER, LVT_MASKED);
+        local_apic.write(REG_INITIAL_COUNT, 0);
+    }
+    time::set_frequency(time::DEFAULT_FREQUENCY_HZ);
+    super::set_isa_masked(0, false);
+}
+
+/* Arms the timer for the next tick. Called from the timer interrupt handler (and by start), with interrupts
+ * disabled; does nothing while the PIT drives the tick.
+ */
+pub(crate) fn rearm() {
+    let interval = INTERVAL.load(Ordering::Relaxed);
+    match mode() {
+        Some(TimerMode::OneShot) => {
+            if let Some(local_apic) = LOCAL_APIC.lock().as_mut() {
+                local_apic.write(REG_INITIAL_COUNT, interval.min(u32::MAX as u64) as u32);
+            }
+        },
+        Some(TimerMode::TscDeadline) => {
+            let now = time::tsc::read();
+            let mut deadline = NEXT_DEADLINE.load(Ordering::Relaxed).wrapping_add(interval);
+            // If we fell more than a tick behind (e.g. interrupts were disabled for long), skip the missed ticks
+            if deadline <= now {
+                deadline = now + interval;
+            }
+            NEXT_DEADLINE.store(deadline, Ordering::Relaxed);
+            unsafe { Msr::new(IA32_TSC_DEADLINE).write(deadline) };
+        },
+        None => {},
+    }
+}
+
+// APIC timer counts per millisecond (at DIVIDE_BY_16), measured against time::nanos
+fn calibrate() -> Result<u64, TimerError> {
+    // The PIT tick fallback of time::nanos doesn't advance with interrupts disabled
+    if time::tsc::khz().is_none() && !time::hpet::is_available() {
+        return Err(TimerError::Calibration);
+    }
+    let _guard = InterruptGuard::new();
+    let mut locked = LOCAL_APIC.lock();
+    let local_apic = locked.as_mut().ok_or(TimerError::ApicDisabled)?;
+    local_apic.write(REG_DIVIDE, DIVIDE_BY_16);
+    local_apic.write(REG_LVT_TIMER, LVT_MASKED | LVT_MODE_ONESHOT);
+    local_apic.write(REG_INITIAL_COUNT, u32::MAX);
+    let start = time::nanos();
+    while time::nanos() - start < CALIBRATION_NANOS {}
+    let elapsed = u32::MAX - local_apic.read(REG_CURRENT_COUNT);
+    local_apic.write(REG_INITIAL_COUNT, 0);
+    match elapsed as u64 * 1_000_000 / CALIBRATION_NANOS {
+        0 => Err(TimerError::Calibration),
+        per_milli => Ok(per_milli),
+    }
+}
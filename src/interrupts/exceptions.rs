@@ -0,0 +1,119 @@
+/* Handlers for the CPU exceptions (vectors 0-31) that interrupts.rs doesn't handle itself. Without them, any of these
+ * would escalate to a double fault and we'd lose the information about what actually went wrong.
+ *
+ * Faults we can't recover from panic with the exception's name, error code and stack frame, which runs the panic
+ * hooks and halts (or fails the test). Where they happened goes into the crash signature (see crash.rs). Debug and
+ * NMI are reported and execution continues.
+ *
+ * Vectors 9 (coprocessor segment overrun, not raised by any CPU since the 386), 15 and 22-31 are reserved and the
+ * x86_64 crate doesn't expose them.
+ */
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use crate::{println, serial};
+
+pub(super) fn register(idt: &mut InterruptDescriptorTable) {
+    idt.divide_by_zero.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    // Unsafe because the IST index must be valid and not used by another handler that could nest with this one
+    unsafe {
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler).set_stack_index(crate::gdt::NMI_IST_INDEX);
+    }
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+    unsafe {
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler)
+            .set_stack_index(crate::gdt::GENERAL_PROTECTION_FAULT_IST_INDEX);
+    }
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(super::mce::machine_check_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.security_exception.set_handler_fn(security_exception_handler);
+}
+
+/* The error code of the segment related exceptions (invalid TSS, segment not present, stack segment fault, general
+ * protection fault) names the selector that caused them, if any.
+ */
+struct SelectorErrorCode(u64);
+
+impl core::fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "0 (not selector related)");
+        }
+        let table = match (self.0 >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        };
+        let external = if self.0 & 1 != 0 { ", external event" } else { "" };
+        write!(f, "{:#x} ({} index {}{})", self.0, table, (self.0 >> 3) & 0x1fff, external)
+    }
+}
+
+/* Defines a handler that panics with the exception's name (and error code, if it has one), after recording its vector
+ * and where the fault happened for the crash signature.
+ */
+macro_rules! fatal_exception {
+    ($handler:ident, $vector:expr, $name:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: &mut InterruptStackFrame) {
+            crate::crash::record_fault($vector, stack_frame);
+            panic!("EXCEPTION: {}\n{:#?}", $name, stack_frame);
+        }
+    };
+    ($handler:ident, $vector:expr, $name:expr, error_code) => {
+        extern "x86-interrupt" fn $handler(stack_frame: &mut InterruptStackFrame, error_code: u64) {
+            crate::crash::record_fault($vector, stack_frame);
+            panic!("EXCEPTION: {}\nError Code: {:#x}\n{:#?}", $name, error_code, stack_frame);
+        }
+    };
+    ($handler:ident, $vector:expr, $name:expr, selector_error_code) => {
+        extern "x86-interrupt" fn $handler(stack_frame: &mut InterruptStackFrame, error_code: u64) {
+            crate::crash::record_fault($vector, stack_frame);
+            panic!("EXCEPTION: {}\nError Code: {}\n{:#?}", $name, SelectorErrorCode(error_code), stack_frame);
+        }
+    };
+}
+
+fatal_exception!(divide_error_handler, 0, "DIVIDE ERROR");
+fatal_exception!(overflow_handler, 4, "OVERFLOW");
+fatal_exception!(bound_range_exceeded_handler, 5, "BOUND RANGE EXCEEDED");
+fatal_exception!(invalid_opcode_handler, 6, "INVALID OPCODE");
+// We build with soft-float and never enable the FPU, so any FPU/SSE instruction ends up here
+fatal_exception!(device_not_available_handler, 7, "DEVICE NOT AVAILABLE");
+fatal_exception!(invalid_tss_handler, 10, "INVALID TSS", selector_error_code);
+fatal_exception!(segment_not_present_handler, 11, "SEGMENT NOT PRESENT", selector_error_code);
+fatal_exception!(stack_segment_fault_handler, 12, "STACK SEGMENT FAULT", selector_error_code);
+fatal_exception!(general_protection_fault_handler, 13, "GENERAL PROTECTION FAULT", selector_error_code);
+fatal_exception!(x87_floating_point_handler, 16, "x87 FLOATING POINT");
+fatal_exception!(alignment_check_handler, 17, "ALIGNMENT CHECK", error_code);
+fatal_exception!(simd_floating_point_handler, 19, "SIMD FLOATING POINT");
+fatal_exception!(virtualization_handler, 20, "VIRTUALIZATION");
+fatal_exception!(security_exception_handler, 30, "SECURITY EXCEPTION", error_code);
+
+// Raised by hardware breakpoints and single stepping, which are meant to be resumed
+extern "x86-interrupt" fn debug_handler(stack_frame: &mut InterruptStackFrame) {
+    println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+}
+
+/* Reported to serial, but not fatal: QEMU's `nmi` monitor command is the usual source. It runs on its own IST stack
+ * and can't be masked, so it must not take any lock the code it interrupted might hold; emergency_print doesn't.
+ */
+extern "x86-interrupt" fn nmi_handler(stack_frame: &mut InterruptStackFrame) {
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use x86_64::instructions::port::Port;
+    static NMI_COUNT: AtomicU64 = AtomicU64::new(0);
+    let count = NMI_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+    // System control port B: bit 7 reports a memory parity/system error (SERR#), bit 6 an I/O channel check
+    let reason = unsafe { Port::<u8>::new(0x61).read() };
+    serial::emergency_print(format_args!(
+        "EXCEPTION: NON-MASKABLE INTERRUPT #{} at tick {}\nSystem error: {}, I/O channel check: {}\n{:#?}\n",
+        count, crate::time::ticks(), reason & (1 << 7) != 0, reason & (1 << 6) != 0, stack_frame));
+}
@@ -0,0 +1,120 @@
+/* IOAPIC driver. An IOAPIC has a redirection table entry per input pin (global system interrupt, GSI) that says
+ * which vector to raise on which CPU, with which polarity and trigger mode, and whether the pin is masked. The
+ * registers are reached indirectly: write the register index to IOREGSEL, then read or write IOWIN.
+ * https://wiki.osdev.org/IOAPIC
+ */
+use spin::Mutex;
+use crate::acpi::madt::{IoApicEntry, Polarity, TriggerMode};
+use crate::memory::{map_mmio, KmapError, Mmio};
+use crate::sync::InterruptGuard;
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+const MASKED: u64 = 1 << 16;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const ACTIVE_LOW: u64 = 1 << 13;
+
+const MAX_IO_APICS: usize = 8;
+
+pub struct IoApic {
+    regs: Mmio<u32>,
+    gsi_base: u32,
+    pins: u32,
+}
+
+// How a pin is delivered; fixed delivery mode, physical destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Redirection {
+    pub vector: u8,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+    pub masked: bool,
+    // APIC ID of the CPU to deliver to
+    pub destination: u8,
+}
+
+impl IoApic {
+    pub fn new(entry: &IoApicEntry) -> Result<IoApic, KmapError> {
+        let regs = map_mmio(entry.address, 0x20)?;
+        let mut io_apic = IoApic { regs, gsi_base: entry.gsi_base, pins: 0 };
+        // Bits 16-23 of the version register hold the index of the last redirection entry
+        io_apic.pins = ((io_apic.read(REG_VERSION) >> 16) & 0xff) + 1;
+        Ok(io_apic)
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.pins
+    }
+
+    pub fn set_redirection(&mut self, gsi: u32, redirection: Redirection) {
+        let mut entry = redirection.vector as u64 | (redirection.destination as u64) << 56;
+        if redirection.polarity == Polarity::ActiveLow {
+            entry |= ACTIVE_LOW;
+        }
+        if redirection.trigger == TriggerMode::Level {
+            entry |= LEVEL_TRIGGERED;
+        }
+        if redirection.masked {
+            entry |= MASKED;
+        }
+        self.write_entry(gsi, entry);
+    }
+
+    pub fn set_masked(&mut self, gsi: u32, masked: bool) {
+        let entry = self.read_entry(gsi);
+        self.write_entry(gsi, if masked { entry | MASKED } else { entry & !MASKED });
+    }
+
+    pub fn is_masked(&mut self, gsi: u32) -> bool {
+        self.read_entry(gsi) & MASKED != 0
+    }
+
+    fn read_entry(&mut self, gsi: u32) -> u64 {
+        let reg = self.entry_register(gsi);
+        self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32
+    }
+
+    fn write_entry(&mut self, gsi: u32, entry: u64) {
+        let reg = self.entry_register(gsi);
+        // Mask via the low half first, so the pin never fires with a half-written entry
+        self.write(reg, MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+
+    fn entry_register(&self, gsi: u32) -> u32 {
+        assert!(self.handles(gsi), "GSI {} is not handled by this IOAPIC", gsi);
+        REG_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base)
+    }
+
+    fn read(&mut self, reg: u32) -> u32 {
+        self.regs.write(IOREGSEL, reg);
+        self.regs.read(IOWIN)
+    }
+
+    fn write(&mut self, reg: u32, value: u32) {
+        self.regs.write(IOREGSEL, reg);
+        self.regs.write(IOWIN, value);
+    }
+}
+
+// Every IOAPIC in the system; None until apic::init sets them up
+static IO_APICS: Mutex<[Option<IoApic>; MAX_IO_APICS]> = Mutex::new([None, None, None, None, None, None, None, None]);
+
+pub(super) fn add(io_apic: IoApic) {
+    let _guard = InterruptGuard::new();
+    let mut io_apics = IO_APICS.lock();
+    if let Some(slot) = io_apics.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(io_apic);
+    }
+}
+
+// Runs `f` on the IOAPIC that handles `gsi`, or returns None if there isn't one
+pub fn with_io_apic_for<R>(gsi: u32, f: impl FnOnce(&mut IoApic) -> R) -> Option<R> {
+    let _guard = InterruptGuard::new();
+    let mut io_apics = IO_APICS.lock();
+    io_apics.iter_mut().flatten().find(|io_apic| io_apic.handles(gsi)).map(f)
+}
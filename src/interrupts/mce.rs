@@ -0,0 +1,189 @@
+/* Machine check architecture. The CPU raises a machine check (vector 18) when it detects a hardware error it couldn't
+ * correct: a bad memory read, a cache parity error, a bus timeout. Without CR4.MCE set, a machine check shuts the CPU
+ * down instead, which on real hardware looks like a spontaneous reboot.
+ *
+ * Errors are logged in banks of MSRs, each covering a part of the CPU (core, caches, memory controller...). When the
+ * exception fires, the handler walks the banks, decodes every valid MCi_STATUS and panics with the report, so it ends
+ * up on screen (and serial) before we halt. There's no recovering from these here: even errors the CPU says are
+ * restartable would need us to know which page or process to kill.
+ * https://wiki.osdev.org/Machine_Check_Exception and the Intel SDM, volume 3, chapter 15
+ */
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17a;
+const IA32_MCG_CTL: u32 = 0x17b;
+// Bank i's CTL, STATUS, ADDR and MISC registers are at IA32_MC0_CTL + 4 * i + 0..3
+const IA32_MC0_CTL: u32 = 0x400;
+
+const MCG_CAP_COUNT: u64 = 0xff;
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+
+const STATUS_VAL: u64 = 1 << 63;
+const STATUS_OVER: u64 = 1 << 62;
+const STATUS_UC: u64 = 1 << 61;
+const STATUS_EN: u64 = 1 << 60;
+const STATUS_MISCV: u64 = 1 << 59;
+const STATUS_ADDRV: u64 = 1 << 58;
+const STATUS_PCC: u64 = 1 << 57;
+
+// CPUID.1:EDX
+const CPUID_MCE: u32 = 1 << 7;
+const CPUID_MCA: u32 = 1 << 14;
+
+// The most banks we report; MCG_CAP allows 255, real CPUs have a few dozen at most
+const MAX_BANKS: usize = 32;
+
+/* Enables machine check exceptions, and every error source in every bank. Returns false if the CPU doesn't support
+ * them, in which case a machine check still resets the machine.
+ */
+pub fn init() -> bool {
+    let features = unsafe { __cpuid(1) }.edx;
+    if features & CPUID_MCE == 0 {
+        return false;
+    }
+    if features & CPUID_MCA != 0 {
+        let cap = unsafe { Msr::new(IA32_MCG_CAP).read() };
+        unsafe {
+            if cap & MCG_CAP_CTL_P != 0 {
+                Msr::new(IA32_MCG_CTL).write(!0);
+            }
+            for bank in 0..bank_count(cap) {
+                Msr::new(bank_msr(bank, 0)).write(!0);
+                // Whatever is logged from before we booted (e.g. the firmware's own errors) isn't ours to report
+                Msr::new(bank_msr(bank, 1)).write(0);
+            }
+        }
+    }
+    unsafe { Cr4::write(Cr4::read() | Cr4Flags::MACHINE_CHECK) };
+    true
+}
+
+fn bank_count(cap: u64) -> usize {
+    ((cap & MCG_CAP_COUNT) as usize).min(MAX_BANKS)
+}
+
+// The MSR number of register `offset` (0 CTL, 1 STATUS, 2 ADDR, 3 MISC) of `bank`
+fn bank_msr(bank: usize, offset: u32) -> u32 {
+    IA32_MC0_CTL + 4 * bank as u32 + offset
+}
+
+// One valid error logged in a bank
+#[derive(Debug, Clone, Copy)]
+struct BankError {
+    bank: usize,
+    status: u64,
+    addr: Option<u64>,
+    misc: Option<u64>,
+}
+
+// Everything the machine check left in the MSRs
+pub struct MachineCheckReport {
+    mcg_status: u64,
+    errors: [Option<BankError>; MAX_BANKS],
+}
+
+fn read_report() -> MachineCheckReport {
+    let mut report = MachineCheckReport { mcg_status: 0, errors: [None; MAX_BANKS] };
+    if unsafe { __cpuid(1) }.edx & CPUID_MCA == 0 {
+        return report;
+    }
+    unsafe {
+        report.mcg_status = Msr::new(IA32_MCG_STATUS).read();
+        for bank in 0..bank_count(Msr::new(IA32_MCG_CAP).read()) {
+            let status = Msr::new(bank_msr(bank, 1)).read();
+            if status & STATUS_VAL == 0 {
+                continue;
+            }
+            let addr = if status & STATUS_ADDRV != 0 { Some(Msr::new(bank_msr(bank, 2)).read()) } else { None };
+            let misc = if status & STATUS_MISCV != 0 { Some(Msr::new(bank_msr(bank, 3)).read()) } else { None };
+            report.errors[bank] = Some(BankError { bank, status, addr, misc });
+        }
+    }
+    report
+}
+
+impl fmt::Display for MachineCheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "MCG_STATUS {:#x} (restart IP {}, error IP {})", self.mcg_status,
+            if self.mcg_status & MCG_STATUS_RIPV != 0 { "valid" } else { "invalid" },
+            if self.mcg_status & MCG_STATUS_EIPV != 0 { "valid" } else { "invalid" })?;
+        let mut any = false;
+        for error in self.errors.iter().flatten() {
+            any = true;
+            write!(f, "bank {}: status {:#018x}: {}", error.bank, error.status, describe(error.status as u16))?;
+            for &(bit, name) in &[(STATUS_UC, "uncorrected"), (STATUS_PCC, "context corrupt"),
+                                  (STATUS_OVER, "overflow"), (STATUS_EN, "enabled")] {
+                if error.status & bit != 0 {
+                    write!(f, ", {}", name)?;
+                }
+            }
+            if let Some(addr) = error.addr {
+                write!(f, ", address {:#x}", addr)?;
+            }
+            if let Some(misc) = error.misc {
+                write!(f, ", misc {:#x}", misc)?;
+            }
+            writeln!(f)?;
+        }
+        if !any {
+            writeln!(f, "no bank logged an error")?;
+        }
+        Ok(())
+    }
+}
+
+/* The class of error from the architectural MCA error code (the low 16 bits of MCi_STATUS). Only the class: the
+ * remaining fields (cache level, transaction type...) vary per class and are left to the raw status.
+ */
+fn describe(code: u16) -> &'static str {
+    match code {
+        0x0000 => "no error",
+        0x0001 => "unclassified error",
+        0x0002 => "microcode ROM parity error",
+        0x0003 => "external error",
+        0x0004 => "FRC error",
+        0x0005 => "internal parity error",
+        0x0006 => "SMM handler code access violation",
+        0x0400 => "internal timer error",
+        0x0e0b => "I/O error",
+        // The compound codes, from the most specific bit pattern to the least
+        _ if code & 0xeff0 == 0x0010 => "TLB error",
+        _ if code & 0xef80 == 0x0080 => "memory controller error",
+        _ if code & 0xef00 == 0x0100 => "cache hierarchy error",
+        _ if code & 0xe800 == 0x0800 => "bus or interconnect error",
+        _ if code & 0xfc00 == 0x0400 => "internal unclassified error",
+        _ => "model specific error",
+    }
+}
+
+/* The CPU state is unreliable after a machine check, so it can't return. The report goes into the panic message, so
+ * it's printed wherever panics are.
+ */
+pub(super) extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut InterruptStackFrame) -> ! {
+    crate::crash::record_fault(18, stack_frame);
+    panic!("EXCEPTION: MACHINE CHECK\n{}{:#?}", read_report(), stack_frame);
+}
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_describe_error_codes() {
+    assert_eq!(describe(0x0005), "internal parity error");
+    // Level 1 data TLB
+    assert_eq!(describe(0x0011), "TLB error");
+    // Memory controller read error, channel 2
+    assert_eq!(describe(0x00a2), "memory controller error");
+    // Generic cache read, level 2 (the filter bit, 12, doesn't change the class)
+    assert_eq!(describe(0x1136), "cache hierarchy error");
+    assert_eq!(describe(0x0e0f), "bus or interconnect error");
+    assert_eq!(describe(0x0401), "internal unclassified error");
+}
@@ -0,0 +1,63 @@
+/* Message signaled interrupts. Instead of raising an IRQ line, an MSI-capable PCI device writes a small message
+ * (data) to a special address, which the chipset turns into an interrupt at a local APIC. The address selects the
+ * destination CPU and the data the vector, so each device (or each MSI-X queue) can get a vector of its own instead
+ * of sharing a legacy line.
+ *
+ * `allocate_msi` claims a free dynamic vector and returns the message to program into the device's MSI capability or
+ * MSI-X table entry; the driver owns the vector until it drops the returned `Msi`. MSI is always delivered through
+ * the local APIC, so this needs `apic::init` to have succeeded.
+ * https://wiki.osdev.org/PCI#Message_Signaled_Interrupts
+ */
+use super::apic;
+use super::vectors::{self, VectorError, VectorGuard, VectorHandler};
+
+// Fixed delivery, physical destination mode, no redirection hint
+const MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiMessage {
+    // Goes into the capability's message address register(s), or the MSI-X entry's address field
+    pub address: u64,
+    // Goes into the message data register, or the MSI-X entry's data field
+    pub data: u32,
+}
+
+// An allocated MSI vector; dropping it frees the vector again
+#[derive(Debug)]
+pub struct Msi {
+    guard: VectorGuard,
+    message: MsiMessage,
+}
+
+impl Msi {
+    pub fn vector(&self) -> u8 {
+        self.guard.vector()
+    }
+
+    pub fn message(&self) -> MsiMessage {
+        self.message
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    // Interrupts still go through the PICs, which can't receive MSIs
+    ApicDisabled,
+    Vector(VectorError),
+}
+
+// Claims a vector for `handler` and returns the message that makes a device raise it on the boot CPU
+pub fn allocate_msi(handler: VectorHandler) -> Result<Msi, MsiError> {
+    if !apic::is_enabled() {
+        return Err(MsiError::ApicDisabled);
+    }
+    let destination = apic::local_apic_id().ok_or(MsiError::ApicDisabled)?;
+    let guard = vectors::allocate_vector(handler).map_err(MsiError::Vector)?;
+    let message = MsiMessage {
+        // Bits 12-19 hold the destination APIC ID
+        address: MSI_ADDRESS_BASE | (destination as u64 & 0xff) << 12,
+        // Edge triggered, fixed delivery: just the vector
+        data: guard.vector() as u32,
+    };
+    Ok(Msi { guard, message })
+}
@@ -0,0 +1,238 @@
+/* Dynamic interrupt vector allocation for drivers.
+ *
+ * The IDT is built once and can't change after it's loaded, so instead of editing it we point every dynamically
+ * assignable vector at a small stub that looks up the real handler in a table. Drivers claim a vector with
+ * `allocate_vector` (any free vector) or `allocate_irq` (the vector of a specific PIC line) and get back a
+ * `VectorGuard`. Dropping the guard masks the IRQ line (if any) and clears the table slot, so a driver that's
+ * loaded and unloaded during a session can't leak vectors or leave a handler pointing at dead code.
+ */
+use super::{PIC_1_OFFSET, PIC_2_OFFSET};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use crate::sync::InterruptGuard;
+use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
+
+pub type VectorHandler = fn();
+
+// Vectors 34-47 belong to PIC lines 2-15 (32 and 33 are the timer and keyboard); 48-63 have no fixed source
+const FIRST_DYNAMIC_VECTOR: u8 = PIC_1_OFFSET + 2;
+const FIRST_FREE_VECTOR: u8 = PIC_2_OFFSET + 8;
+const LAST_DYNAMIC_VECTOR: u8 = 63;
+const DYNAMIC_VECTORS: usize = (LAST_DYNAMIC_VECTOR - FIRST_DYNAMIC_VECTOR + 1) as usize;
+
+// Only changed with interrupts disabled, so the stubs can't deadlock on it
+static HANDLERS: Mutex<[Option<VectorHandler>; DYNAMIC_VECTORS]> = Mutex::new([None; DYNAMIC_VECTORS]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorError {
+    // Every vector without a fixed source is taken
+    Exhausted,
+    // Somebody already owns this IRQ line
+    InUse,
+    // The timer, keyboard and cascade lines (0-2) are handled statically; valid lines are 3-15
+    InvalidIrq,
+}
+
+// Owns an IDT vector until dropped
+#[derive(Debug)]
+pub struct VectorGuard {
+    vector: u8,
+    irq: Option<u8>,
+}
+
+impl VectorGuard {
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+
+    // The PIC line this vector belongs to, if it was allocated with `allocate_irq`
+    pub fn irq(&self) -> Option<u8> {
+        self.irq
+    }
+}
+
+impl Drop for VectorGuard {
+    fn drop(&mut self) {
+        let _guard = InterruptGuard::new();
+        // Mask first so the line can't fire between clearing the handler and returning
+        if let Some(irq) = self.irq {
+            set_pic_masked(irq, true);
+        }
+        HANDLERS.lock()[slot(self.vector)] = None;
+    }
+}
+
+// Claims any free vector that isn't tied to a PIC line (e.g. for a future APIC or MSI source)
+pub fn allocate_vector(handler: VectorHandler) -> Result<VectorGuard, VectorError> {
+    let _guard = InterruptGuard::new();
+    let mut handlers = HANDLERS.lock();
+    let vector = (FIRST_FREE_VECTOR..=LAST_DYNAMIC_VECTOR)
+        .find(|&v| handlers[slot(v)].is_none())
+        .ok_or(VectorError::Exhausted)?;
+    handlers[slot(vector)] = Some(handler);
+    Ok(VectorGuard { vector, irq: None })
+}
+
+// Claims the vector of PIC line `irq` and unmasks the line
+pub fn allocate_irq(irq: u8, handler: VectorHandler) -> Result<VectorGuard, VectorError> {
+    if irq < 3 || irq > 15 {
+        return Err(VectorError::InvalidIrq);
+    }
+    let vector = PIC_1_OFFSET + irq;
+    let _guard = InterruptGuard::new();
+    let mut handlers = HANDLERS.lock();
+    if handlers[slot(vector)].is_some() {
+        return Err(VectorError::InUse);
+    }
+    handlers[slot(vector)] = Some(handler);
+    set_pic_masked(irq, false);
+    // Lines on the secondary PIC only get through if the cascade line (IRQ 2) on the primary is open too
+    if irq >= 8 && !super::apic::is_enabled() {
+        set_pic_masked(2, false);
+    }
+    Ok(VectorGuard { vector, irq: Some(irq) })
+}
+
+fn slot(vector: u8) -> usize {
+    (vector - FIRST_DYNAMIC_VECTOR) as usize
+}
+
+/* The PIC raises IRQ 7 (or 15 on the secondary) when a line goes away before the CPU acknowledges it, e.g. from
+ * electrical noise or a level-triggered device that deasserts quickly. Such a spurious interrupt isn't marked in the
+ * PIC's in-service register (ISR) and must not get an EOI, or we'd acknowledge some other, real interrupt. A spurious
+ * IRQ 15 still needs an EOI on the primary, though, because the cascade line did fire there.
+ */
+static SPURIOUS_IRQS: AtomicU64 = AtomicU64::new(0);
+
+// Number of spurious PIC interrupts ignored since boot
+pub fn spurious_irqs() -> u64 {
+    SPURIOUS_IRQS.load(Ordering::Relaxed)
+}
+
+fn is_spurious(vector: u8) -> bool {
+    use x86_64::instructions::port::Port;
+    let (command, bit) = match vector {
+        v if v == PIC_1_OFFSET + 7 => (0x20, 7),
+        v if v == PIC_2_OFFSET + 7 => (0xA0, 7),
+        _ => return false,
+    };
+    if read_isr(command) & (1 << bit) != 0 {
+        return false;
+    }
+    SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+    if command == 0xA0 {
+        let mut primary: Port<u8> = Port::new(0x20);
+        unsafe { primary.write(0x20) };
+    }
+    true
+}
+
+// The in-service register of the PIC whose command port is `command`
+fn read_isr(command: u16) -> u8 {
+    use x86_64::instructions::port::Port;
+    let mut port: Port<u8> = Port::new(command);
+    // OCW3: the next read of the command port returns the ISR
+    unsafe {
+        port.write(0x0b);
+        port.read()
+    }
+}
+
+// Sends EOIs until neither PIC has an interrupt in service, secondary first since it's chained to the primary
+pub(super) fn drain_pic_in_service() {
+    use x86_64::instructions::port::Port;
+    for &command in &[0xA0, 0x20] {
+        let mut port: Port<u8> = Port::new(command);
+        // Each non-specific EOI retires the highest priority IRQ in service; there are at most 8
+        for _ in 0..8 {
+            if read_isr(command) == 0 {
+                break;
+            }
+            unsafe { port.write(0x20) };
+        }
+    }
+}
+
+fn dispatch(vector: u8) {
+    // The APIC doesn't use these vectors for spurious interrupts, so only the PIC needs the check
+    if !super::apic::is_enabled() && is_spurious(vector) {
+        return;
+    }
+    // Copy the handler out so the lock isn't held while it runs
+    let handler = HANDLERS.lock()[slot(vector)];
+    if let Some(handler) = handler {
+        handler();
+    }
+    // IRQ lines need an EOI even if nobody claimed them, or the controller stops delivering that line
+    if vector < FIRST_FREE_VECTOR || super::apic::is_enabled() {
+        super::end_of_interrupt(vector);
+    }
+}
+
+/* Masks or unmasks a single ISA IRQ line: at the IOAPIC once interrupts go through the APICs, otherwise via the
+ * PICs' interrupt mask registers (port 0x21 for the primary, 0xA1 for the secondary). A set bit means the line is
+ * masked.
+ */
+pub(crate) fn set_pic_masked(irq: u8, masked: bool) {
+    use x86_64::instructions::port::Port;
+    if super::apic::is_enabled() && super::apic::set_isa_masked(irq, masked) {
+        return;
+    }
+    let (mut port, bit): (Port<u8>, u8) = if irq < 8 { (Port::new(0x21), irq) } else { (Port::new(0xA1), irq - 8) };
+    // Unsafe because writing the wrong mask could silence lines other drivers depend on
+    unsafe {
+        let mask = port.read();
+        port.write(if masked { mask | (1 << bit) } else { mask & !(1 << bit) });
+    }
+}
+
+// Both PICs' masks, primary in the low byte
+pub(super) fn pic_masks() -> u16 {
+    use x86_64::instructions::port::Port;
+    let (mut primary, mut secondary): (Port<u8>, Port<u8>) = (Port::new(0x21), Port::new(0xA1));
+    unsafe { primary.read() as u16 | (secondary.read() as u16) << 8 }
+}
+
+pub(super) fn set_pic_masks(masks: u16) {
+    use x86_64::instructions::port::Port;
+    let (mut primary, mut secondary): (Port<u8>, Port<u8>) = (Port::new(0x21), Port::new(0xA1));
+    unsafe {
+        primary.write(masks as u8);
+        secondary.write((masks >> 8) as u8);
+    }
+}
+
+// One stub per dynamic vector, since an x86-interrupt handler can't tell which vector invoked it
+macro_rules! vector_stubs {
+    ($($vector:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: &mut InterruptStackFrame) {
+                dispatch($vector);
+            }
+        )*
+        // (vector, stub) pairs for the IDT
+        pub(super) const STUBS: [(u8, HandlerFunc); DYNAMIC_VECTORS] = [$(($vector, $name)),*];
+    };
+}
+
+vector_stubs!(
+    34 => vector_34, 35 => vector_35, 36 => vector_36, 37 => vector_37, 38 => vector_38, 39 => vector_39,
+    40 => vector_40, 41 => vector_41, 42 => vector_42, 43 => vector_43, 44 => vector_44, 45 => vector_45,
+    46 => vector_46, 47 => vector_47, 48 => vector_48, 49 => vector_49, 50 => vector_50, 51 => vector_51,
+    52 => vector_52, 53 => vector_53, 54 => vector_54, 55 => vector_55, 56 => vector_56, 57 => vector_57,
+    58 => vector_58, 59 => vector_59, 60 => vector_60, 61 => vector_61, 62 => vector_62, 63 => vector_63,
+);
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_vector_guard_frees_its_vector() {
+    fn handler() {}
+    let first = allocate_vector(handler).unwrap();
+    let vector = first.vector();
+    drop(first);
+    let second = allocate_vector(handler).unwrap();
+    assert_eq!(second.vector(), vector);
+    assert_eq!(allocate_irq(1, handler).unwrap_err(), VectorError::InvalidIrq);
+}
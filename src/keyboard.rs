@@ -0,0 +1,445 @@
+/* Keyboard input. The interrupt handler only reads the scancode from port 0x60 and pushes it onto SCANCODES, a
+ * lock-free queue, so it never takes a lock: taking KEYBOARD or WRITER in the handler deadlocks as soon as the
+ * interrupted code holds them, and even the deferred work queue's lock is one more thing to contend on per key.
+ * Decoding happens in `process_scancodes`, the consumer, which the handler schedules as deferred work
+ * (once per burst) and so runs from the idle loop with interrupts enabled.
+ *
+ * The queue is a single-producer, single-consumer ring (sync::ByteQueue): the handler is the only producer and the
+ * consumer only runs from deferred work, so neither side needs a lock. Scancodes that arrive while it's full are
+ * dropped and counted, and the consumer reports the loss when it next runs (see input::report_overflow).
+ *
+ * The consumer tracks which modifiers (Shift, Ctrl, Alt; left and right alike) are held. Key presses that match a
+ * registered chord (e.g. Ctrl+Alt+Delete) run the chord's handler instead of being delivered; every other press and
+ * release is published to the input subsystem.
+ *
+ * An async task can take the scancodes instead, undecoded, with a ScancodeStream. While one exists the handler wakes
+ * the stream's task (from deferred work, since waking runs the executor's code) instead of scheduling the consumer,
+ * which leaves the queue alone; dropping the stream hands the queue back to the consumer.
+ *
+ * The consumer also mirrors the decoder's Caps/Num/Scroll Lock state and sends it to the keyboard's LEDs whenever a
+ * lock key toggles. Commands to the keyboard (LEDs, typematic rate) are polled for their ACK with interrupts disabled,
+ * so the interrupt handler never sees the replies. https://wiki.osdev.org/PS/2_Keyboard#Commands
+ */
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+use pc_keyboard::{DecodedKey, KeyCode, KeyEvent, KeyState};
+use spin::Mutex;
+use crate::{deferred, i8042, input};
+use crate::input::InputEvent;
+use crate::sync::{ByteQueue, ByteStream, InterruptGuard, QueueWaker};
+
+static SCANCODES: ByteQueue = ByteQueue::new();
+// Whether process_scancodes is already queued as deferred work, so a burst of key presses schedules it once
+static CONSUMER_SCHEDULED: AtomicBool = AtomicBool::new(false);
+
+// Whether a ScancodeStream has the queue, in place of process_scancodes
+static STREAM_TAKEN: AtomicBool = AtomicBool::new(false);
+// The stream's task, waiting for a scancode, if it is
+static WAKER: QueueWaker = QueueWaker::new();
+// Whether wake_stream is already queued as deferred work
+static WAKE_SCHEDULED: AtomicBool = AtomicBool::new(false);
+
+// Called by the keyboard interrupt handler, which is the queue's only producer
+pub(crate) fn add_scancode(scancode: u8) {
+    if !SCANCODES.push(scancode) {
+        return;
+    }
+    let (scheduled, work): (_, fn(usize)) = if STREAM_TAKEN.load(Ordering::Acquire) {
+        (&WAKE_SCHEDULED, wake_stream)
+    } else {
+        (&CONSUMER_SCHEDULED, process_scancodes)
+    };
+    if !scheduled.swap(true, Ordering::AcqRel) && !deferred::schedule(work, 0) {
+        // The deferred queue is full; the next scancode tries again
+        scheduled.store(false, Ordering::Release);
+    }
+}
+
+fn wake_stream(_: usize) {
+    WAKE_SCHEDULED.store(false, Ordering::Release);
+    WAKER.wake();
+}
+
+/* The raw scancodes, for an async task: `while let Some(scancode) = stream.next().await` (with StreamExt). There's
+ * one queue, so there can only be one stream at a time, and keys aren't decoded or delivered to the input subsystem
+ * while it exists. The stream never ends.
+ */
+pub struct ScancodeStream {
+    bytes: ByteStream,
+}
+
+impl ScancodeStream {
+    pub fn new() -> Result<ScancodeStream, KeyboardError> {
+        if STREAM_TAKEN.swap(true, Ordering::AcqRel) {
+            return Err(KeyboardError::StreamTaken);
+        }
+        Ok(ScancodeStream { bytes: ByteStream::new(pop_scancode, &WAKER) })
+    }
+}
+
+fn pop_scancode() -> Option<u8> {
+    SCANCODES.pop()
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        Pin::new(&mut self.bytes).poll_next(cx)
+    }
+}
+
+impl Drop for ScancodeStream {
+    fn drop(&mut self) {
+        STREAM_TAKEN.store(false, Ordering::Release);
+        WAKER.clear();
+        // Hand what's still queued to the consumer
+        if !SCANCODES.is_empty() && !CONSUMER_SCHEDULED.swap(true, Ordering::AcqRel)
+            && !deferred::schedule(process_scancodes, 0)
+        {
+            CONSUMER_SCHEDULED.store(false, Ordering::Release);
+        }
+    }
+}
+
+// Number of scancodes lost because the consumer fell behind
+pub fn dropped_scancodes() -> u64 {
+    SCANCODES.dropped()
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers { shift: false, ctrl: false, alt: false };
+    pub const SHIFT: Modifiers = Modifiers { shift: true, ctrl: false, alt: false };
+    pub const CTRL: Modifiers = Modifiers { shift: false, ctrl: true, alt: false };
+    pub const ALT: Modifiers = Modifiers { shift: false, ctrl: false, alt: true };
+    pub const CTRL_ALT: Modifiers = Modifiers { shift: false, ctrl: true, alt: true };
+
+    // Records a modifier key going down or up; returns false (and changes nothing) for any other key
+    fn update(&mut self, event: &KeyEvent) -> bool {
+        let held = event.state == KeyState::Down;
+        match event.code {
+            KeyCode::ShiftLeft | KeyCode::ShiftRight => self.shift = held,
+            KeyCode::ControlLeft | KeyCode::ControlRight => self.ctrl = held,
+            KeyCode::AltLeft | KeyCode::AltRight => self.alt = held,
+            _ => return false,
+        }
+        true
+    }
+}
+
+// A key going down, with the modifiers held at the time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPress {
+    pub code: KeyCode,
+    pub modifiers: Modifiers,
+    // What the key types in the current layout, if anything (None e.g. for the modifier keys themselves)
+    pub key: Option<DecodedKey>,
+}
+
+// Which lock keys are on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locks {
+    pub caps: bool,
+    pub num: bool,
+    pub scroll: bool,
+}
+
+impl Locks {
+    // What the decoder starts with (Num Lock on)
+    pub const INITIAL: Locks = Locks { caps: false, num: true, scroll: false };
+
+    // Toggles the lock a key press belongs to; returns false (and changes nothing) for any other key
+    fn toggle(&mut self, code: KeyCode) -> bool {
+        match code {
+            KeyCode::CapsLock => self.caps = !self.caps,
+            KeyCode::NumpadLock => self.num = !self.num,
+            KeyCode::ScrollLock => self.scroll = !self.scroll,
+            _ => return false,
+        }
+        true
+    }
+
+    // The argument of the set LEDs command
+    fn led_bits(&self) -> u8 {
+        (self.scroll as u8) | (self.num as u8) << 1 | (self.caps as u8) << 2
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyboardError {
+    // init found no keyboard on the PS/2 controller
+    NoKeyboard,
+    // The controller or the keyboard didn't answer
+    Timeout,
+    // The keyboard answered with something other than an ACK (after retrying a few resends)
+    NotAcknowledged(u8),
+    // There already is a ScancodeStream
+    StreamTaken,
+}
+
+const COMMAND_SET_LEDS: u8 = 0xed;
+const COMMAND_SET_TYPEMATIC: u8 = 0xf3;
+const REPLY_ACK: u8 = 0xfa;
+const REPLY_RESEND: u8 = 0xfe;
+const MAX_RESENDS: usize = 3;
+
+// Sets the LEDs to the decoder's lock state. Called once the controller is initialized.
+pub fn init() -> Result<(), KeyboardError> {
+    set_leds(locks())
+}
+
+// Turns the lock LEDs on and off. This doesn't change the lock state itself; the next lock key press does.
+pub fn set_leds(locks: Locks) -> Result<(), KeyboardError> {
+    send(COMMAND_SET_LEDS, locks.led_bits())
+}
+
+/* Sets how long a key must be held before it repeats (250-1000 ms) and how often it then repeats (2-30 per second).
+ * The keyboard only knows a few of each, so this picks the closest ones.
+ */
+pub fn set_typematic(delay_ms: u16, repeats_per_sec: u8) -> Result<(), KeyboardError> {
+    send(COMMAND_SET_TYPEMATIC, typematic_byte(delay_ms, repeats_per_sec))
+}
+
+/* Bits 5-6 select the delay, (n + 1) * 250 ms. Bits 0-4 select the rate: the period is (8 + A) * 2^B * 4.17 ms, with
+ * A in bits 0-2 and B in bits 3-4, so 0 is the fastest (30 per second) and 0x1F the slowest (2 per second).
+ */
+fn typematic_byte(delay_ms: u16, repeats_per_sec: u8) -> u8 {
+    let delay = ((delay_ms.max(250).min(1000) + 125) / 250 - 1) as u8;
+    // Rates in hundredths of a repeat per second, to compare against the request
+    let hundredths = |rate: u8| 10_000_000 / (((8 + (rate & 0b111) as u32) << (rate >> 3)) * 417);
+    let wanted = repeats_per_sec as u32 * 100;
+    let rate = (0..32u8).min_by_key(|&rate| (hundredths(rate) as i32 - wanted as i32).abs()).unwrap_or(0);
+    delay << 5 | rate
+}
+
+// Sends a command and its argument to the keyboard, resending either if the keyboard asks for it
+fn send(command: u8, argument: u8) -> Result<(), KeyboardError> {
+    if !i8042::has_keyboard() {
+        return Err(KeyboardError::NoKeyboard);
+    }
+    // The ACKs arrive on IRQ 1; with interrupts disabled the handler finds the output buffer empty afterwards
+    let _guard = InterruptGuard::new();
+    for &byte in [command, argument].iter() {
+        send_byte(byte)?;
+    }
+    Ok(())
+}
+
+fn send_byte(byte: u8) -> Result<(), KeyboardError> {
+    let mut reply = REPLY_RESEND;
+    for _ in 0..MAX_RESENDS {
+        // Unsafe because keyboard commands change how it behaves; these are the ones the caller asked for
+        if !unsafe { i8042::write_data(byte) } {
+            return Err(KeyboardError::Timeout);
+        }
+        reply = i8042::read_data_timeout().ok_or(KeyboardError::Timeout)?;
+        if reply != REPLY_RESEND {
+            break;
+        }
+    }
+    if reply == REPLY_ACK { Ok(()) } else { Err(KeyboardError::NotAcknowledged(reply)) }
+}
+
+pub type ChordHandler = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChordError {
+    // Another handler already has this chord
+    InUse,
+    Full,
+}
+
+#[derive(Clone, Copy)]
+struct Chord {
+    modifiers: Modifiers,
+    code: KeyCode,
+    handler: ChordHandler,
+}
+
+const MAX_CHORDS: usize = 16;
+
+// Both are only used by the consumer and by normal code, never in interrupt context
+static CHORDS: Mutex<[Option<Chord>; MAX_CHORDS]> = Mutex::new([None; MAX_CHORDS]);
+static MODIFIERS: Mutex<Modifiers> = Mutex::new(Modifiers::NONE);
+static LOCKS: Mutex<Locks> = Mutex::new(Locks::INITIAL);
+
+/* Runs `handler` whenever `code` is pressed while exactly `modifiers` are held, e.g.
+ * register_chord(Modifiers::CTRL_ALT, KeyCode::Delete, reboot). The key press is then not delivered.
+ */
+pub fn register_chord(modifiers: Modifiers, code: KeyCode, handler: ChordHandler) -> Result<(), ChordError> {
+    let mut chords = CHORDS.lock();
+    if chords.iter().flatten().any(|chord| chord.modifiers == modifiers && chord.code == code) {
+        return Err(ChordError::InUse);
+    }
+    let slot = chords.iter_mut().find(|slot| slot.is_none()).ok_or(ChordError::Full)?;
+    *slot = Some(Chord { modifiers, code, handler });
+    Ok(())
+}
+
+// Removes the chord; returns whether there was one
+pub fn unregister_chord(modifiers: Modifiers, code: KeyCode) -> bool {
+    let mut chords = CHORDS.lock();
+    let found = chords.iter_mut()
+        .find(|slot| matches!(slot, Some(chord) if chord.modifiers == modifiers && chord.code == code));
+    match found {
+        Some(slot) => {
+            *slot = None;
+            true
+        },
+        None => false,
+    }
+}
+
+// The modifiers held right now (as of the last scancode the consumer processed)
+pub fn modifiers() -> Modifiers {
+    *MODIFIERS.lock()
+}
+
+// The lock keys that are on, as the decoder sees them
+pub fn locks() -> Locks {
+    *LOCKS.lock()
+}
+
+fn chord_handler(modifiers: Modifiers, code: KeyCode) -> Option<ChordHandler> {
+    CHORDS.lock().iter().flatten()
+        .find(|chord| chord.modifiers == modifiers && chord.code == code)
+        .map(|chord| chord.handler)
+}
+
+/* The consumer: decodes every queued scancode (Scan Code Set 1, https://wiki.osdev.org/Keyboard#Scan_Code_Set_1),
+ * runs chords and delivers the rest. Runs as deferred work.
+ */
+fn process_scancodes(_: usize) {
+    use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
+    lazy_static::lazy_static! {
+        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
+            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore));
+    }
+    // Cleared first, so a scancode pushed after the loop below finishes schedules us again
+    CONSUMER_SCHEDULED.store(false, Ordering::Release);
+    // Scheduled before a stream took the queue; the stream's task pops now
+    if STREAM_TAKEN.load(Ordering::Acquire) {
+        return;
+    }
+    input::report_overflow();
+    let mut keyboard = KEYBOARD.lock();
+    while let Some(scancode) = SCANCODES.pop() {
+        let event = match keyboard.add_byte(scancode) {
+            Ok(Some(event)) => event,
+            _ => continue,
+        };
+        let (code, down) = (event.code, event.state == KeyState::Down);
+        let modifiers = {
+            let mut modifiers = MODIFIERS.lock();
+            modifiers.update(&event);
+            *modifiers
+        };
+        // The decoder tracks Shift and the lock keys itself, so it sees every event, chords included
+        let key = keyboard.process_keyevent(event);
+        // It toggles a lock when its key goes down; do the same, and show it
+        if down {
+            let mut locks = LOCKS.lock();
+            if locks.toggle(code) {
+                // A keyboard that ignores the LEDs still types
+                let _ = set_leds(*locks);
+            }
+        }
+        if !down {
+            input::publish(InputEvent::KeyRelease { code, modifiers });
+            continue;
+        }
+        // Copied out, so the handlers can (un)register chords
+        if let Some(handler) = chord_handler(modifiers, code) {
+            handler();
+            continue;
+        }
+        input::publish(InputEvent::KeyPress(KeyPress { code, modifiers, key }));
+    }
+}
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_scancode_stream() {
+    use core::future::Future;
+    use core::sync::atomic::AtomicUsize;
+    use core::task::{RawWaker, RawWakerVTable, Waker};
+    use futures_util::stream::StreamExt;
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, release);
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    fn wake(_: *const ()) {
+        WAKES.fetch_add(1, Ordering::SeqCst);
+    }
+    fn release(_: *const ()) {}
+    let waker = unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) };
+    let mut cx = Context::from_waker(&waker);
+
+    let mut stream = ScancodeStream::new().unwrap();
+    assert!(ScancodeStream::new().is_err());
+    assert_eq!(Pin::new(&mut stream.next()).poll(&mut cx), Poll::Pending);
+    // What the interrupt handler does with a key press ('A' going down)
+    add_scancode(0x1e);
+    deferred::run_pending();
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+    assert_eq!(Pin::new(&mut stream.next()).poll(&mut cx), Poll::Ready(Some(0x1e)));
+    drop(stream);
+    assert!(ScancodeStream::new().is_ok());
+}
+
+#[test_case]
+fn test_modifiers_track_both_sides() {
+    let mut modifiers = Modifiers::NONE;
+    assert!(modifiers.update(&KeyEvent::new(KeyCode::ControlLeft, KeyState::Down)));
+    assert!(modifiers.update(&KeyEvent::new(KeyCode::AltRight, KeyState::Down)));
+    assert_eq!(modifiers, Modifiers::CTRL_ALT);
+    assert!(!modifiers.update(&KeyEvent::new(KeyCode::Delete, KeyState::Down)));
+    assert!(modifiers.update(&KeyEvent::new(KeyCode::ControlLeft, KeyState::Up)));
+    assert_eq!(modifiers, Modifiers::ALT);
+}
+
+#[test_case]
+fn test_chords() {
+    fn handler() {}
+    assert_eq!(register_chord(Modifiers::ALT, KeyCode::F1, handler), Ok(()));
+    assert_eq!(register_chord(Modifiers::ALT, KeyCode::F1, handler), Err(ChordError::InUse));
+    assert!(chord_handler(Modifiers::ALT, KeyCode::F1).is_some());
+    // The modifiers must match exactly
+    assert!(chord_handler(Modifiers::CTRL_ALT, KeyCode::F1).is_none());
+    assert!(unregister_chord(Modifiers::ALT, KeyCode::F1));
+    assert!(!unregister_chord(Modifiers::ALT, KeyCode::F1));
+}
+
+#[test_case]
+fn test_locks() {
+    let mut locks = Locks::INITIAL;
+    assert_eq!(locks.led_bits(), 0b010);
+    assert!(locks.toggle(KeyCode::CapsLock));
+    assert!(locks.toggle(KeyCode::NumpadLock));
+    assert!(!locks.toggle(KeyCode::A));
+    assert_eq!(locks, Locks { caps: true, num: false, scroll: false });
+    assert_eq!(locks.led_bits(), 0b100);
+}
+
+#[test_case]
+fn test_typematic_byte() {
+    // The fastest rate with the shortest delay, and the slowest with the longest
+    assert_eq!(typematic_byte(250, 30), 0x00);
+    assert_eq!(typematic_byte(1000, 2), 0x7f);
+    // The BIOS default: 500 ms, 10.9 per second
+    assert_eq!(typematic_byte(500, 11), 0x2b);
+    // Out of range values are clamped
+    assert_eq!(typematic_byte(0, 100), 0x00);
+}
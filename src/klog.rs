@@ -0,0 +1,324 @@
+/* Kernel logging through the `log` crate: once `init` has run, `log::error!`, `warn!`, `info!`, `debug!` and `trace!`
+ * work anywhere in the kernel (messages logged before that are dropped). Every message has a target, by default the
+ * path of the module it was logged from ("rust_os::mouse"). Which levels get through can be set per target with
+ * `set_level`, which also covers the modules below the target, so one driver can be debugged without every other
+ * one getting noisy. Messages are printed like println! does, or only on the screen, serial or debugcon; see `Route`.
+ *
+ * This is also where the kernel message buffer lives: a ring buffer in memory with the newest LOG_SIZE bytes' worth
+ * of whole lines of output, each with the uptime it was printed at. It's one of the console's sinks, so it gets
+ * everything printed, log messages included (whatever the route). `read` copies them out, for a `dmesg` command or
+ * for a dump after a panic, when the messages have long scrolled off the screen.
+ *
+ * Messages may be logged from interrupt handlers: the filters and the buffer are only ever locked with interrupts
+ * disabled, and so are the screen and the serial port.
+ */
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+use log::{LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+use crate::sync::InterruptGuard;
+use crate::{console, debugcon, serial, time, vga_buffer};
+
+// Targets below this one are shown without it: "mouse" instead of "rust_os::mouse"
+const CRATE_PREFIX: &str = "rust_os::";
+const MAX_FILTERS: usize = 16;
+// Size of the message buffer
+pub const LOG_SIZE: usize = 16 * 1024;
+
+// Where messages go: Both prints them like println! (to every console sink), the others only to that one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Route {
+    Vga = 1,
+    Serial = 2,
+    Both = 3,
+    // QEMU's port 0xE9, for trace output that would flood the screen and the serial test protocol
+    Debugcon = 4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogError {
+    // Every per-target filter slot is taken
+    Full,
+}
+
+struct Filters {
+    // For targets without a filter of their own
+    default: LevelFilter,
+    targets: [Option<(&'static str, LevelFilter)>; MAX_FILTERS],
+}
+
+static FILTERS: Mutex<Filters> = Mutex::new(Filters { default: LevelFilter::Info, targets: [None; MAX_FILTERS] });
+static ROUTE: AtomicU8 = AtomicU8::new(Route::Both as u8);
+
+/* Where the bytes of the message buffer are in `data`: `len` bytes from `start` on, wrapping around. Room for a new
+ * message is made by dropping the oldest whole lines.
+ */
+struct Ring {
+    start: usize,
+    len: usize,
+}
+
+impl Ring {
+    const fn new() -> Ring {
+        Ring { start: 0, len: 0 }
+    }
+
+    fn push(&mut self, data: &mut [u8], bytes: &[u8]) {
+        for &byte in bytes {
+            if self.len == data.len() {
+                self.drop_line(data);
+            }
+            data[(self.start + self.len) % data.len()] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn drop_line(&mut self, data: &[u8]) {
+        while self.len > 0 {
+            let byte = data[self.start];
+            self.start = (self.start + 1) % data.len();
+            self.len -= 1;
+            if byte == b'\n' {
+                break;
+            }
+        }
+    }
+
+    // Copies the newest whole lines that fit into `out`, oldest first, and returns how many bytes that is
+    fn read(&self, data: &[u8], out: &mut [u8]) -> usize {
+        let at = |i: usize| data[(self.start + i) % data.len()];
+        let mut skip = self.len.saturating_sub(out.len());
+        // Leave out what's left of a line whose beginning didn't fit
+        while skip > 0 && skip < self.len && at(skip - 1) != b'\n' {
+            skip += 1;
+        }
+        let count = self.len - skip;
+        for (i, byte) in out[..count].iter_mut().enumerate() {
+            *byte = at(skip + i);
+        }
+        count
+    }
+}
+
+struct MessageBuffer {
+    data: [u8; LOG_SIZE],
+    ring: Ring,
+    // Whether the next byte starts a line, and so comes after a timestamp
+    at_line_start: bool,
+}
+
+static MESSAGES: Mutex<MessageBuffer> =
+    Mutex::new(MessageBuffer { data: [0; LOG_SIZE], ring: Ring::new(), at_line_start: true });
+
+impl fmt::Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.ring.push(&mut self.data, s.as_bytes());
+        Ok(())
+    }
+}
+
+// Writes to the message buffer, starting each line with the uptime
+struct Timestamped<'a> {
+    messages: &'a mut MessageBuffer,
+    millis: u64,
+}
+
+impl fmt::Write for Timestamped<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut rest = s.as_bytes();
+        while !rest.is_empty() {
+            if self.messages.at_line_start {
+                write!(self.messages, "[{:>5}.{:03}] ", self.millis / 1000, self.millis % 1000)?;
+            }
+            let end = rest.iter().position(|&byte| byte == b'\n').map_or(rest.len(), |newline| newline + 1);
+            let (line, next) = rest.split_at(end);
+            self.messages.ring.push(&mut self.messages.data, line);
+            self.messages.at_line_start = line.ends_with(b"\n");
+            rest = next;
+        }
+        Ok(())
+    }
+}
+
+/* Adds printed output to the message buffer; the console's log buffer sink. Drops it if the buffer is locked, which
+ * only happens if a panic interrupted the output being added, so printing the panic can't hang.
+ */
+pub fn record(args: fmt::Arguments) {
+    let millis = time::uptime_millis();
+    let _guard = InterruptGuard::new();
+    if let Some(mut messages) = MESSAGES.try_lock() {
+        let _ = Timestamped { messages: &mut *messages, millis }.write_fmt(args);
+    }
+}
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let target = record.target();
+        let target = if target.starts_with(CRATE_PREFIX) { &target[CRATE_PREFIX.len()..] } else { target };
+        emit(route(), format_args!("[{:<5} {}] {}\n", record.level(), target, record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
+fn emit(route: Route, line: fmt::Arguments) {
+    match route {
+        Route::Both => console::_print(line),
+        // The message buffer is a console sink, which these bypass
+        Route::Vga => {
+            vga_buffer::_print(line);
+            record(line);
+        },
+        Route::Serial => {
+            serial::_print(line);
+            record(line);
+        },
+        Route::Debugcon => {
+            debugcon::_print(line);
+            record(line);
+        },
+    }
+}
+
+// Installs the kernel's logger. Needs nothing else to be set up, so it comes first in init.
+pub fn init() {
+    // Fails only if a logger is already installed, i.e. on a second call
+    if log::set_logger(&LOGGER).is_ok() {
+        // Filtering is done per target in `enabled`, so the macros mustn't throw anything away first
+        log::set_max_level(LevelFilter::Trace);
+    }
+}
+
+pub fn set_route(route: Route) {
+    ROUTE.store(route as u8, Ordering::Relaxed);
+}
+
+pub fn route() -> Route {
+    match ROUTE.load(Ordering::Relaxed) {
+        1 => Route::Vga,
+        2 => Route::Serial,
+        4 => Route::Debugcon,
+        _ => Route::Both,
+    }
+}
+
+// Sets the most verbose level shown for targets that have no filter of their own (Info to begin with)
+pub fn set_default_level(level: LevelFilter) {
+    let _guard = InterruptGuard::new();
+    FILTERS.lock().default = level;
+}
+
+/* Sets the most verbose level shown for `target` and the modules below it (`set_level("rust_os::memory", ...)` also
+ * covers "rust_os::memory::pmm"). Where filters overlap, the one for the longest target wins.
+ */
+pub fn set_level(target: &'static str, level: LevelFilter) -> Result<(), LogError> {
+    let _guard = InterruptGuard::new();
+    let mut filters = FILTERS.lock();
+    let slot = filters.targets.iter().position(|slot| matches!(slot, Some((t, _)) if *t == target))
+        .or_else(|| filters.targets.iter().position(|slot| slot.is_none()))
+        .ok_or(LogError::Full)?;
+    filters.targets[slot] = Some((target, level));
+    Ok(())
+}
+
+// The most verbose level shown for `target`
+pub fn level_for(target: &str) -> LevelFilter {
+    let _guard = InterruptGuard::new();
+    let filters = FILTERS.lock();
+    filters.targets.iter().flatten()
+        .filter(|(filter, _)| covers(filter, target))
+        .max_by_key(|(filter, _)| filter.len())
+        .map_or(filters.default, |&(_, level)| level)
+}
+
+/* Copies the buffered messages into `out`, oldest first, one line each, and returns how many bytes it copied. If
+ * they don't all fit, the oldest are left out. Doesn't wait for the buffer: if a panic interrupted a message being
+ * written, it copies nothing rather than hang.
+ */
+pub fn read(out: &mut [u8]) -> usize {
+    let _guard = InterruptGuard::new();
+    match MESSAGES.try_lock() {
+        Some(messages) => messages.ring.read(&messages.data, out),
+        None => 0,
+    }
+}
+
+// Whether a filter for `filter` applies to `target`: the same module, or one below it
+fn covers(filter: &str, target: &str) -> bool {
+    target.starts_with(filter) && (target.len() == filter.len() || target[filter.len()..].starts_with("::"))
+}
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_levels_per_target() {
+    // Made-up targets, so the filters don't change what the rest of the kernel logs
+    set_level("klog_test", LevelFilter::Debug).unwrap();
+    set_level("klog_test::quiet", LevelFilter::Error).unwrap();
+    assert_eq!(level_for("klog_test"), LevelFilter::Debug);
+    assert_eq!(level_for("klog_test::loud"), LevelFilter::Debug);
+    assert_eq!(level_for("klog_test::quiet::inner"), LevelFilter::Error);
+    // Only whole path segments match
+    assert_eq!(level_for("klog_testing"), LevelFilter::Info);
+    // Setting a level again replaces it instead of taking another slot
+    set_level("klog_test", LevelFilter::Warn).unwrap();
+    assert_eq!(level_for("klog_test::loud"), LevelFilter::Warn);
+}
+
+#[test_case]
+fn test_message_buffer_keeps_the_newest_whole_lines() {
+    let mut data = [0u8; 16];
+    let mut ring = Ring::new();
+    ring.push(&mut data, b"one\ntwo\nthree\n");
+    // Doesn't fit: "one" goes, all of it
+    ring.push(&mut data, b"four\n");
+    let mut out = [0u8; 32];
+    let count = ring.read(&data, &mut out);
+    assert_eq!(&out[..count], b"two\nthree\nfour\n");
+    // Only room for part of "three": it's left out
+    let mut out = [0u8; 8];
+    let count = ring.read(&data, &mut out);
+    assert_eq!(&out[..count], b"four\n");
+}
+
+#[test_case]
+fn test_lines_are_timestamped() {
+    let mut messages = MessageBuffer { data: [0; LOG_SIZE], ring: Ring::new(), at_line_start: true };
+    write!(Timestamped { messages: &mut messages, millis: 1234 }, "one\ntw").unwrap();
+    write!(Timestamped { messages: &mut messages, millis: 5678 }, "o\n").unwrap();
+    let mut out = [0u8; 64];
+    let count = messages.ring.read(&messages.data, &mut out);
+    assert_eq!(&out[..count], b"[    1.234] one\n[    1.234] two\n");
+}
+
+#[test_case]
+fn test_messages_are_kept() {
+    log::warn!("kept for dmesg {}", 42);
+    let mut out = [0u8; 1024];
+    let count = read(&mut out);
+    assert!(out[..count].ends_with(b"[WARN  klog] kept for dmesg 42\n"));
+}
+
+#[test_case]
+fn test_debugcon_route_still_keeps_messages() {
+    set_route(Route::Debugcon);
+    assert_eq!(route(), Route::Debugcon);
+    log::warn!("sent to debugcon {}", 7);
+    set_route(Route::Both);
+    let mut out = [0u8; 1024];
+    let count = read(&mut out);
+    assert!(out[..count].ends_with(b"[WARN  klog] sent to debugcon 7\n"));
+}
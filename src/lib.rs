@@ -4,23 +4,71 @@
 #![test_runner(crate::test_runner)] // specify a test runner
 #![reexport_test_harness_main = "test_main"] 
 #![feature(abi_x86_interrupt)] // Allows us to use the unstable x86-interrupt calling convention
+#![feature(alloc_error_handler)] // Lets us define what happens when a heap allocation fails
+#![feature(llvm_asm)] // Reading registers for the panic screen, and rbp to record who allocated what
+
+extern crate alloc; // Box, Vec, etc. backed by our kernel heap
 
 use core::panic::PanicInfo;
+use serial::Channel;
 
+#[macro_use]
+pub mod static_assert; // Compile-time layout checks
 pub mod gdt; // Task State Segment (Interrupt Stack Table, https://os.phil-opp.com/double-fault-exceptions/#creating-a-tss)
 pub mod serial;
+pub mod debugcon; // QEMU's port 0xE9 debug console
 pub mod vga_buffer;
+pub mod klog; // The log crate's logger (per-target levels), and the kernel message buffer behind dmesg
 pub mod interrupts; 
+pub mod time; // PIT setup, tick counter and uptime
+pub mod i8042; // The 8042 PS/2 controller: self-test, port detection and configuration
+pub mod keyboard; // Scancode queue filled by the keyboard interrupt, decoded outside it
+pub mod input; // Device-independent input events and their subscribers
+pub mod mouse; // PS/2 mouse on the controller's auxiliary port
+pub mod console; // Where printed output goes (screen, serial, message buffer), and line editing for read_line
+pub mod status_bar; // Uptime, heap usage and lock keys along the top of the screen
+pub mod cpu; // CPU topology (packages, cores, threads) from CPUID and the MADT
+pub mod deferred; // Work that interrupt handlers hand off to run outside interrupt context
+pub mod crash; // Panic signatures, matched against a table of known issues
+pub mod sync; // Nesting RAII guards that disable interrupts or preemption
+pub mod memory;
+pub mod acpi; // Finding the firmware's ACPI tables (MADT, ...)
+pub mod smbios; // Firmware's description of the machine: BIOS, board, memory devices
+pub mod allocator; // The kernel heap
+pub mod units; // Human-readable byte and duration formatting
+pub mod panic; // Hooks that subsystems can register to run before a panic halts the kernel
+pub mod integrity; // Boot-time checksums of the kernel image, re-verified while idle
+pub mod crypto; // SHA-256, HMAC and ChaCha20
 
 /**
  * General initialization function
+ * The GDT and IDT are always set up, so exceptions are reported no matter what. The rest is only brought up if its
+ * Cargo feature is enabled (see Cargo.toml), so an integration test can build a kernel with only the subsystem under
+ * test, e.g. a paging test that isn't slowed down or broken by keyboard and timer interrupts.
  */
 pub fn init() {
+    klog::init();
     gdt::init();
     interrupts::init_idt();
-    // initialize() is unsafe
-    unsafe { interrupts::PICS.lock().initialize() };
-    x86_64::instructions::interrupts::enable(); // Actually enable interrupts
+    // Before anything that could trip over a hardware error, so it gets reported instead of resetting the machine
+    interrupts::mce::init();
+    if cfg!(feature = "interrupts") {
+        // initialize() is unsafe
+        unsafe { interrupts::PICS.lock().initialize() };
+        // Silence the lines of devices we don't want interrupts from
+        interrupts::vectors::set_pic_masked(0, !cfg!(feature = "timer"));
+        // Without a PS/2 controller (or a keyboard on it) IRQ 1 would only ever deliver bus noise
+        let keyboard = cfg!(feature = "keyboard") && i8042::init().map_or(false, |ports| ports.keyboard);
+        interrupts::vectors::set_pic_masked(1, !keyboard);
+        if keyboard {
+            // A keyboard that ignores LED commands still types
+            let _ = keyboard::init();
+        }
+        if cfg!(feature = "timer") {
+            time::init();
+        }
+        x86_64::instructions::interrupts::enable(); // Actually enable interrupts
+    }
 }
 
 // Continuously execute `hlt`, which makes the CPU sleep instead of loop (which would peg the CPU)
@@ -41,9 +89,9 @@ where
     T: Fn(), // Defines Testable for any Fn()
 {
     fn run (&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>()); // core fn that prints type name
+        serial_print_to!(Channel::Test, "{}...\t", core::any::type_name::<T>()); // core fn that prints type name
         self(); // Run the function embedded in Fn()
-        serial_println!("[ok]");
+        serial_println_to!(Channel::Test, "[ok]");
     }
 }
 
@@ -54,7 +102,11 @@ where
  * exact nature of them.
  */
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
+    // Unframed, printed output on serial would be mixed into the test results; it still reaches the screen and the log
+    if !cfg!(feature = "serial-mux") {
+        console::remove_sink(console::SERIAL_SINK);
+    }
+    serial_println_to!(Channel::Test, "Running {} tests", tests.len());
     for test in tests {
         test.run(); // Call the Testable wrapper around the Fn()
     }
@@ -62,12 +114,20 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
+    panic::run_hooks(info);
+    serial_println_to!(Channel::Test, "[failed]\n");
+    serial_println_to!(Channel::Test, "Error: {}\n", info);
+    serial_println_to!(Channel::Test, "{}", crash::report());
     exit_qemu(QemuExitCode::Failure);
     hlt_loop();
 }
 
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    allocator::report_oom(layout);
+    panic!("allocation error: {:?}", layout)
+}
+
 // Entrypoint for `cargo xtest`
  #[cfg(test)]
  #[no_mangle]
@@ -8,27 +8,89 @@
 
 use core::panic::PanicInfo;
 use rust_os::println; // our println function defined in lib.rs
+use bootloader::{BootInfo, entry_point};
 
 ////////////////////////////////// Main ////////////////////////////////// 
 
-// Don't mangle the start function name or it won't be recognized
-#[no_mangle] 
-pub extern "C" fn _start() -> ! { // Should be divergent
+/* The bootloader passes us a BootInfo struct (memory map, physical memory offset). entry_point! defines the real
+ * `_start` for us and type-checks that kernel_main has the signature the bootloader expects.
+ */
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! { // Should be divergent
     println!("Hello World{}", "!");
 
 
     println!("Currently on Paging Implementation");
 
     rust_os::init();
+    match rust_os::time::tsc::khz() {
+        Some(khz) => println!("TSC runs at {} MHz{}", khz / 1000,
+            if rust_os::time::tsc::is_invariant() { "" } else { " (not invariant)" }),
+        None => log::warn!("TSC calibration failed"),
+    }
+    if !rust_os::i8042::has_keyboard() {
+        log::warn!("No PS/2 keyboard found; keyboard input is only available over serial");
+    }
+    if let Err(e) = rust_os::mouse::init() {
+        log::info!("No PS/2 mouse: {:?}", e);
+    }
+    if let Err(e) = rust_os::serial::enable_receive_interrupt() {
+        log::warn!("Serial input stays polled: {:?}", e);
+    }
+    rust_os::memory::init(boot_info);
+    match rust_os::smbios::smbios() {
+        Ok(smbios) => {
+            if let (Some(system), Some(bios)) = (smbios.system(), smbios.bios()) {
+                println!("Running on {} {} (BIOS {} {})",
+                    system.manufacturer, system.product, bios.vendor, bios.version);
+            }
+            rust_os::serial_print!("{}", smbios);
+        },
+        Err(e) => log::warn!("No SMBIOS tables: {:?}", e),
+    }
+    match rust_os::interrupts::apic::init() {
+        Ok(()) => println!("Device interrupts routed through the IOAPIC"),
+        Err(e) => println!("Staying on the 8259 PICs: {:?}", e),
+    }
+    match rust_os::time::hpet::init() {
+        Ok(()) => println!("HPET running at {} Hz", rust_os::time::hpet::frequency_hz().unwrap_or(0)),
+        Err(e) => println!("No HPET, timing with the PIT: {:?}", e),
+    }
+    let tick_micros = 1_000_000 / rust_os::time::DEFAULT_FREQUENCY_HZ as u64;
+    match rust_os::interrupts::apic::timer::start(tick_micros) {
+        Ok(mode) => println!("Timer tick driven by the APIC timer ({:?})", mode),
+        Err(e) => println!("Timer tick stays on the PIT: {:?}", e),
+    }
+    rust_os::allocator::init_heap().expect("heap initialization failed");
+    rust_os::console::init().expect("console initialization failed");
+    match rust_os::integrity::verify_build_checksums() {
+        Ok(()) => {}
+        // Booted without tools/runner.sh; the boot-time checksums below still catch corruption from here on
+        Err(rust_os::integrity::IntegrityError::NotEmbedded) => println!("No build-time kernel image checksums"),
+        Err(e) => panic!("{}", e),
+    }
+    let covered = rust_os::integrity::init();
+    println!("Kernel image checksummed: {}", rust_os::units::fmt_bytes(covered as u64));
+    rust_os::integrity::check();
+    let topology = rust_os::cpu::topology();
+    println!("{} CPU(s): {} package(s), {} core(s)", topology.cpus.len(), topology.packages, topology.cores);
+    rust_os::serial_print!("{}", topology);
+    rust_os::memory::dump_memory_map();
+    if let Some(memory_map) = rust_os::memory::memory_map() {
+        let usable = rust_os::memory::physmap::summarize(memory_map).usable;
+        println!("Usable memory: {}", rust_os::units::fmt_bytes(usable));
+    }
+    rust_os::memory::VMM.lock().dump();
 
     // Page fault: Writing outside of memory 
     // unsafe {
     //     *(0xdeadbeef as *mut u64) = 42;
     // }
 
-    use x86_64::registers::control::Cr3;
-    let (level_4_page_table, _) = Cr3::read();
-    println!("Level 4 page table at: {:#?}", level_4_page_table.start_address());
+    // Show how the bootloader mapped the VGA buffer
+    use x86_64::VirtAddr;
+    rust_os::memory::dump_page_table(VirtAddr::new(0xb8000)..VirtAddr::new(0xb9000));
 
 
     // Kernel stack overflow (pushing return address too many times)
@@ -48,7 +110,13 @@ pub extern "C" fn _start() -> ! { // Should be divergent
     test_main();
     println!("Didn't crash after running test_main.");
 
-    rust_os::hlt_loop();
+    // Idle loop: run the work interrupt handlers deferred, re-verify the kernel image every now and then, and sleep
+    // until the next interrupt
+    loop {
+        rust_os::deferred::run_pending();
+        rust_os::integrity::check_if_due();
+        rust_os::deferred::wait_for_work();
+    }
 
 }
 
@@ -58,7 +126,10 @@ pub extern "C" fn _start() -> ! { // Should be divergent
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! { // Should never return
-    println!("{}", _info);
+    // Before the hooks run and change them
+    let registers = rust_os::panic::screen::Registers::capture();
+    rust_os::panic::run_hooks(_info);
+    rust_os::panic::screen::show(_info, &registers);
     rust_os::hlt_loop();
 }
 // Alternate panic handler for testing (prints to serial, not vga)
@@ -0,0 +1,115 @@
+/* The memory module collects everything related to virtual and physical memory management:
+ * which virtual regions are in use and by whom, how pages are mapped, and where frames come from.
+ *
+ * The bootloader (with the `map_physical_memory` feature) maps all of physical memory at some virtual offset,
+ * which lets us reach any page table frame by adding that offset to its physical address. We wrap the active
+ * level 4 table in an `OffsetPageTable`, which does exactly that for us.
+ */
+pub mod vma; // Virtual memory areas: a registry of what lives where in the address space
+pub mod kmap; // Mapping anonymous memory and physical (MMIO) ranges on demand
+pub mod physmap; // Reporting on the bootloader's physical memory map
+pub mod pmm; // Bitmap physical memory manager (frame allocator)
+pub mod ptdump; // Page table walker and pretty-printer for debugging
+pub mod dma; // Physically contiguous buffers for devices
+pub mod address_space; // Per-process level 4 tables
+pub mod tlb; // TLB flushes that stay correct once there's more than one CPU
+pub mod mmio; // Typed, volatile access to device registers
+pub mod stack; // Guarded kernel stacks for tasks and IST entries
+
+pub use vma::{Permissions, Region, RegionKind, VmaError, VMM};
+pub use kmap::{kmap, kmap_phys, kunmap, KmapError};
+pub use physmap::{dump_memory_map, memory_map, MemorySummary};
+pub use pmm::BitmapFrameAllocator;
+pub use ptdump::dump_page_table;
+pub use dma::{alloc_dma, alloc_dma_uncached, DmaBuffer, DmaError};
+pub use address_space::{AddressSpace, AddressSpaceError};
+pub use tlb::{flush_tlb_all, flush_tlb_range};
+pub use mmio::{map_mmio, Mmio};
+pub use stack::{alloc_kernel_stack, free_kernel_stack, KernelStack};
+
+use bootloader::BootInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{OffsetPageTable, PageTable};
+
+/* The mapper and frame allocator are global so that any subsystem can map memory without having them threaded
+ * through every call. They are None until `init` runs. Always lock MAPPER before FRAME_ALLOCATOR.
+ */
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/**
+ * Sets up the global mapper and frame allocator from the bootloader's info and records the regions that exist
+ * before any dynamic mapping happens (VGA buffer, IST stacks, the physical memory window, ...)
+ */
+pub fn init(boot_info: &'static BootInfo) {
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    physmap::init(&boot_info.memory_map);
+    address_space::init();
+    // Both are unsafe because the caller has to guarantee the offset and memory map are correct, which the
+    // bootloader does.
+    unsafe {
+        *MAPPER.lock() = Some(OffsetPageTable::new(active_level_4_table(physical_memory_offset), physical_memory_offset));
+        *FRAME_ALLOCATOR.lock() = Some(BitmapFrameAllocator::init(&boot_info.memory_map));
+    }
+
+    vma::register_boot_regions();
+    let physical_memory_size = boot_info.memory_map.iter().map(|r| r.range.end_addr()).max().unwrap_or(0);
+    VMM.lock().insert(Region::new("physical memory", physical_memory_offset, physical_memory_size,
+        RegionKind::Kernel, Permissions::READ_WRITE))
+        .expect("failed to register the physical memory region");
+    crate::gdt::init_fault_stacks().expect("failed to allocate the page fault and GPF stacks");
+}
+
+// Runs `f` with both the mapper and the frame allocator locked
+pub fn with_page_tables<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R,
+{
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    f(mapper.as_mut().expect("memory::init has not been called"),
+      frame_allocator.as_mut().expect("memory::init has not been called"))
+}
+
+/* Like with_page_tables, but returns None instead of spinning if either lock is already held. For paths that can be
+ * reached while the page tables are locked (such as the heap growing itself in the middle of an allocation), where
+ * waiting would deadlock.
+ */
+pub fn try_with_page_tables<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R,
+{
+    let mut mapper = MAPPER.try_lock()?;
+    let mut frame_allocator = FRAME_ALLOCATOR.try_lock()?;
+    Some(f(mapper.as_mut()?, frame_allocator.as_mut()?))
+}
+
+// Resolves a virtual address through the active page tables, or None if it isn't mapped
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    use x86_64::structures::paging::MapperAllSizes;
+    MAPPER.lock().as_ref().expect("memory::init has not been called").translate_addr(addr)
+}
+
+// Returns the virtual address at which the bootloader mapped the given physical address
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/* Returns a mutable reference to the active level 4 table.
+ * Unsafe because the caller must guarantee that all of physical memory is mapped at `physical_memory_offset`, and
+ * this must only be called once to avoid aliasing `&mut` references.
+ */
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    use x86_64::registers::control::Cr3;
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr
+}
@@ -0,0 +1,199 @@
+/* An AddressSpace owns a level 4 page table, i.e. one complete virtual address space. Each future process gets its
+ * own, and switching processes means loading a different level 4 table into CR3.
+ *
+ * All address spaces have to share the kernel's mappings, or the next interrupt after a switch would fault. Unlike
+ * a higher-half kernel, the bootloader maps our kernel, stack and physical memory window at various places in the
+ * lower half, so instead of "copy the upper 256 entries" we copy every level 4 entry that is in use in the kernel's
+ * table. Those level 3 tables are then shared, so kernel mappings below an existing entry show up everywhere; only
+ * kernel mappings in level 4 slots that were empty when the address space was created need `sync_kernel_mappings`.
+ * User regions must live in level 4 slots the kernel doesn't use, so user mappings never leak into shared tables.
+ */
+use super::{phys_to_virt, FRAME_ALLOCATOR};
+use super::vma::{Permissions, Region, RegionKind, VirtualMemoryManager, VmaError};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable,
+    PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::structures::paging::mapper::MapToError;
+
+// The level 4 table the bootloader set up for us, recorded by memory::init
+static KERNEL_LEVEL_4: AtomicU64 = AtomicU64::new(0);
+
+pub(super) fn init() {
+    let (frame, _) = Cr3::read();
+    KERNEL_LEVEL_4.store(frame.start_address().as_u64(), Ordering::Relaxed);
+}
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    FrameAllocationFailed,
+    // The region would share a level 4 entry with kernel mappings
+    KernelRange,
+    Vma(VmaError),
+    MapTo(MapToError<Size4KiB>),
+}
+
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    // The user regions mapped in this address space
+    regions: VirtualMemoryManager,
+}
+
+impl AddressSpace {
+    // Creates an address space containing only the kernel's mappings
+    pub fn new() -> Result<AddressSpace, AddressSpaceError> {
+        let frame = allocate_zeroed_frame()?;
+        let mut space = AddressSpace { level_4_frame: frame, regions: VirtualMemoryManager::new() };
+        space.sync_kernel_mappings();
+        Ok(space)
+    }
+
+    // Copies every level 4 entry the kernel uses into this address space
+    pub fn sync_kernel_mappings(&mut self) {
+        let kernel = unsafe { table(kernel_level_4()) };
+        let table = unsafe { table(self.level_4_frame.start_address()) };
+        for (i, entry) in kernel.iter().enumerate() {
+            if !entry.is_unused() {
+                table[i].set_addr(entry.addr(), entry.flags());
+            }
+        }
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub fn regions(&self) -> &VirtualMemoryManager {
+        &self.regions
+    }
+
+    /* Maps `len` bytes of zeroed, user-accessible memory at `start` (which must be page aligned). If that fails part
+     * way, the pages mapped so far are unmapped again and the region isn't registered.
+     */
+    pub fn map_user_region(&mut self, name: &'static str, start: VirtAddr, len: u64, flags: PageTableFlags)
+        -> Result<(), AddressSpaceError>
+    {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let first = Page::<Size4KiB>::from_start_address(start).map_err(|_| AddressSpaceError::KernelRange)?;
+        let last = Page::<Size4KiB>::containing_address(start + (len.max(1) - 1));
+
+        let kernel = unsafe { table(kernel_level_4()) };
+        for index in usize::from(first.p4_index())..=usize::from(last.p4_index()) {
+            if !kernel[index].is_unused() {
+                return Err(AddressSpaceError::KernelRange);
+            }
+        }
+        let size = last.start_address() + 4096u64 - first.start_address();
+        self.regions.insert(Region::new(name, start, size, RegionKind::User, Permissions::from_flags(flags)))
+            .map_err(AddressSpaceError::Vma)?;
+
+        let active = self.is_active();
+        let mut mapper = unsafe { self.mapper() };
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().expect("memory::init has not been called");
+        let mut error = None;
+        // The first page that isn't mapped yet
+        let mut unmapped = first;
+        for page in Page::range_inclusive(first, last) {
+            let frame = match frame_allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => {
+                    error = Some(AddressSpaceError::FrameAllocationFailed);
+                    break;
+                }
+            };
+            unsafe { core::ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, 4096) };
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                // Stale TLB entries only matter for the address space the CPU is using
+                Ok(flush) => if active { flush.flush() } else { flush.ignore() },
+                Err(err) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    error = Some(AddressSpaceError::MapTo(err));
+                    break;
+                }
+            }
+            unmapped = page + 1;
+        }
+        if let Some(err) = error {
+            // Don't leave the region half mapped: give back the pages mapped so far and forget the region
+            for page in Page::range(first, unmapped) {
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    if active { flush.flush() } else { flush.ignore() }
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+            }
+            self.regions.remove(start).expect("the region was just inserted");
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    // Loads this address space into CR3
+    pub fn activate(&self) {
+        // Safe because every address space contains the kernel's mappings (see sync_kernel_mappings)
+        unsafe { Cr3::write(self.level_4_frame, Cr3Flags::empty()) };
+    }
+
+    // Switches back to the kernel's own address space
+    pub fn activate_kernel() {
+        let frame = PhysFrame::containing_address(kernel_level_4());
+        unsafe { Cr3::write(frame, Cr3Flags::empty()) };
+    }
+
+    // Unsafe because the returned mapper aliases this address space's tables
+    unsafe fn mapper(&self) -> OffsetPageTable<'static> {
+        OffsetPageTable::new(table(self.level_4_frame.start_address()), phys_to_virt(PhysAddr::new(0)))
+    }
+}
+
+impl Drop for AddressSpace {
+    // Frees the user mappings and every page table that isn't shared with the kernel
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        let kernel = unsafe { table(kernel_level_4()) };
+        let level_4 = unsafe { table(self.level_4_frame.start_address()) };
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().unwrap();
+        for (i, entry) in level_4.iter().enumerate() {
+            if kernel[i].is_unused() && !entry.is_unused() {
+                unsafe { free_table(entry.addr(), 3, frame_allocator) };
+            }
+        }
+        unsafe { frame_allocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+// Frees a level 3, 2 or 1 table with everything below it. We never map huge pages in user regions.
+unsafe fn free_table<A: FrameDeallocator<Size4KiB>>(addr: PhysAddr, level: u8, frame_allocator: &mut A) {
+    for entry in table(addr).iter().filter(|e| !e.is_unused()) {
+        if level == 1 {
+            frame_allocator.deallocate_frame(PhysFrame::containing_address(entry.addr()));
+        } else {
+            free_table(entry.addr(), level - 1, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(PhysFrame::containing_address(addr));
+}
+
+fn kernel_level_4() -> PhysAddr {
+    let addr = KERNEL_LEVEL_4.load(Ordering::Relaxed);
+    assert!(addr != 0, "memory::init has not been called");
+    PhysAddr::new(addr)
+}
+
+// Unsafe because the caller must make sure `addr` holds a page table and not alias it mutably
+unsafe fn table(addr: PhysAddr) -> &'static mut PageTable {
+    &mut *phys_to_virt(addr).as_mut_ptr()
+}
+
+fn allocate_zeroed_frame() -> Result<PhysFrame, AddressSpaceError> {
+    let frame = FRAME_ALLOCATOR.lock().as_mut().expect("memory::init has not been called")
+        .allocate_frame().ok_or(AddressSpaceError::FrameAllocationFailed)?;
+    unsafe { table(frame.start_address()).zero() };
+    Ok(frame)
+}
@@ -0,0 +1,97 @@
+/* Buffers for direct memory access (DMA). Devices like ATA bus-master controllers, NICs and virtio queues read and
+ * write RAM by physical address, without going through our page tables, so their buffers must be physically
+ * contiguous and the driver needs to know both addresses: the virtual one to fill the buffer and the physical one
+ * to program into the device.
+ *
+ * DMA on x86 is cache coherent, so normal (write-back) mappings are fine for data buffers. Descriptor rings that
+ * are polled by the device can be mapped uncached with `alloc_dma_uncached` so writes reach memory in order.
+ */
+use super::{kmap, FRAME_ALLOCATOR};
+use super::vma::RegionKind;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug)]
+pub enum DmaError {
+    ZeroLength,
+    // No physically contiguous run of frames was free
+    OutOfMemory,
+    Map(kmap::KmapError),
+}
+
+// A physically contiguous buffer, freed (unmapped and its frames returned) on drop
+#[derive(Debug)]
+pub struct DmaBuffer {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    len: u64,
+    frames: usize,
+}
+
+impl DmaBuffer {
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    // The address to hand to the device
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt.as_ptr(), self.len as usize) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.len as usize) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        kmap::kunmap(self.virt).expect("failed to unmap DMA buffer");
+        let first = PhysFrame::containing_address(self.phys);
+        FRAME_ALLOCATOR.lock().as_mut().unwrap().deallocate_contiguous(first, self.frames);
+    }
+}
+
+/* Allocates a zeroed, physically contiguous buffer of at least `len` bytes whose physical address is a multiple of
+ * `alignment` (a power of two; anything up to 4096 just means page aligned).
+ */
+pub fn alloc_dma(len: u64, alignment: u64) -> Result<DmaBuffer, DmaError> {
+    alloc_dma_with_flags(len, alignment, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+}
+
+// Same as alloc_dma, but the CPU accesses the buffer uncached
+pub fn alloc_dma_uncached(len: u64, alignment: u64) -> Result<DmaBuffer, DmaError> {
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    alloc_dma_with_flags(len, alignment, flags)
+}
+
+fn alloc_dma_with_flags(len: u64, alignment: u64, flags: PageTableFlags) -> Result<DmaBuffer, DmaError> {
+    if len == 0 {
+        return Err(DmaError::ZeroLength);
+    }
+    let frames = ((len + PAGE_SIZE - 1) / PAGE_SIZE) as usize;
+    let align_frames = (alignment / PAGE_SIZE).max(1) as usize;
+    let first = FRAME_ALLOCATOR.lock().as_mut().expect("memory::init has not been called")
+        .allocate_contiguous(frames, align_frames)
+        .ok_or(DmaError::OutOfMemory)?;
+    let phys = first.start_address();
+    let virt = match kmap::map_phys_region("dma", RegionKind::Dma, phys, frames as u64 * PAGE_SIZE, flags) {
+        Ok(virt) => virt,
+        Err(e) => {
+            FRAME_ALLOCATOR.lock().as_mut().unwrap().deallocate_contiguous(first, frames);
+            return Err(DmaError::Map(e));
+        }
+    };
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, frames * PAGE_SIZE as usize) };
+    Ok(DmaBuffer { virt, phys, len, frames })
+}
@@ -0,0 +1,177 @@
+/* kmap hands out mapped virtual memory to drivers and subsystems, so they don't have to write page table code:
+ *  - `kmap(len, flags)` maps freshly allocated (zeroed) frames, i.e. anonymous memory
+ *  - `kmap_phys(phys, len, flags)` maps a given physical range, e.g. a device's MMIO registers
+ *  - `kunmap(addr)` removes either kind of mapping again
+ *
+ * Every mapping lives in a dedicated window of the address space and is recorded in the VMA table, which is also
+ * how we find free space for new mappings.
+ */
+use super::vma::{Permissions, Region, RegionKind, VmaError, VMM};
+use super::with_page_tables;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+
+// A 1 TiB window that nothing else uses (the bootloader maps the kernel and physical memory elsewhere)
+pub const KMAP_START: u64 = 0x_5555_0000_0000;
+pub const KMAP_SIZE: u64 = 1 << 40;
+
+const PAGE_SIZE: u64 = 4096;
+// Pages unmapped per TLB flush in kunmap
+const UNMAP_BATCH: usize = 32;
+
+#[derive(Debug)]
+pub enum KmapError {
+    ZeroLength,
+    OutOfVirtualSpace,
+    FrameAllocationFailed,
+    Vma(VmaError),
+    MapTo(MapToError<Size4KiB>),
+    Unmap(UnmapError),
+    // kunmap was given an address that kmap didn't hand out
+    NotMapped,
+}
+
+// Maps `len` bytes (rounded up to whole pages) of zeroed memory and returns its start address
+pub fn kmap(len: u64, flags: PageTableFlags) -> Result<VirtAddr, KmapError> {
+    let start = reserve("kmap", len, RegionKind::Anonymous, flags)?;
+    let pages = Page::<Size4KiB>::range_inclusive(Page::containing_address(start), Page::containing_address(start + len - 1u64));
+    let result = with_page_tables(|mapper, frame_allocator| -> Result<(), KmapError> {
+        for page in pages {
+            let frame = frame_allocator.allocate_frame().ok_or(KmapError::FrameAllocationFailed)?;
+            // Unsafe because mapping arbitrary frames can alias memory; these are fresh from the allocator
+            unsafe {
+                mapper.map_to(page, frame, flags | PageTableFlags::PRESENT, frame_allocator)
+                    .map_err(KmapError::MapTo)?
+                    .flush();
+            }
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        // Undo whatever part of the mapping succeeded
+        let _ = kunmap(start);
+        return Err(e);
+    }
+    // Frames come with whatever the previous owner left in them
+    unsafe { core::ptr::write_bytes(start.as_mut_ptr::<u8>(), 0, round_up(len) as usize) };
+    Ok(start)
+}
+
+/* Maps the physical range [phys, phys + len) and returns the virtual address corresponding to `phys`. `phys` doesn't
+ * have to be page aligned. For device memory, pass NO_CACHE (and usually WRITE_THROUGH) in `flags`.
+ */
+pub fn kmap_phys(phys: PhysAddr, len: u64, flags: PageTableFlags) -> Result<VirtAddr, KmapError> {
+    map_phys_region("kmap mmio", RegionKind::Mmio, phys, len, flags)
+}
+
+// kmap_phys with the name and kind of the VMA region chosen by the caller
+pub(super) fn map_phys_region(name: &'static str, kind: RegionKind, phys: PhysAddr, len: u64, flags: PageTableFlags)
+    -> Result<VirtAddr, KmapError>
+{
+    if len == 0 {
+        return Err(KmapError::ZeroLength);
+    }
+    let first_frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(phys);
+    let last_frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(phys + (len - 1));
+    let offset = phys.as_u64() - first_frame.start_address().as_u64();
+    let mapped_len = last_frame.start_address().as_u64() + PAGE_SIZE - first_frame.start_address().as_u64();
+
+    let start = reserve(name, mapped_len, kind, flags)?;
+    let result = with_page_tables(|mapper, frame_allocator| -> Result<(), KmapError> {
+        let frames = PhysFrame::range_inclusive(first_frame, last_frame);
+        for (i, frame) in frames.enumerate() {
+            let page = Page::<Size4KiB>::containing_address(start + i as u64 * PAGE_SIZE);
+            // Unsafe because the caller is responsible for the physical range not aliasing ordinary memory
+            unsafe {
+                mapper.map_to(page, frame, flags | PageTableFlags::PRESENT, frame_allocator)
+                    .map_err(KmapError::MapTo)?
+                    .flush();
+            }
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        let _ = kunmap(start);
+        return Err(e);
+    }
+    Ok(start + offset)
+}
+
+// Unmaps the kmap region containing `addr`
+pub fn kunmap(addr: VirtAddr) -> Result<(), KmapError> {
+    let region = {
+        let mut vmm = VMM.lock();
+        let start = vmm.find(addr).filter(|r| is_kmap_region(r)).ok_or(KmapError::NotMapped)?.start;
+        vmm.remove(start).map_err(KmapError::Vma)?
+    };
+    let pages = Page::<Size4KiB>::range(Page::containing_address(region.start), Page::containing_address(region.end()));
+    // Anonymous memory and stacks came from the frame allocator, so they go back to it. MMIO frames were never ours.
+    let owns_frames = region.kind == RegionKind::Anonymous || region.kind == RegionKind::Stack;
+    with_page_tables(|mapper, frame_allocator| -> Result<(), KmapError> {
+        /* Unmap in batches and flush each batch before freeing its frames, so no CPU can still reach a frame
+         * through a stale TLB entry once somebody else owns it.
+         */
+        let mut batch: [Option<PhysFrame>; UNMAP_BATCH] = [None; UNMAP_BATCH];
+        let mut pages = pages.peekable();
+        while let Some(&batch_start) = pages.peek() {
+            let mut batch_end = batch_start;
+            let mut result = Ok(());
+            for slot in batch.iter_mut() {
+                let page = match pages.next() {
+                    Some(page) => page,
+                    None => break,
+                };
+                batch_end = page + 1;
+                match mapper.unmap(page) {
+                    // Flushed together with the rest of the batch
+                    Ok((frame, flush)) => {
+                        flush.ignore();
+                        *slot = Some(frame);
+                    },
+                    // A failed kmap may have only mapped part of its region
+                    Err(UnmapError::PageNotMapped) => {},
+                    Err(e) => {
+                        result = Err(KmapError::Unmap(e));
+                        break;
+                    },
+                }
+            }
+            super::flush_tlb_range(batch_start.start_address()..batch_end.start_address());
+            for slot in batch.iter_mut() {
+                if let Some(frame) = slot.take() {
+                    if owns_frames {
+                        // Safe because the page is unmapped and flushed everywhere, and kmap memory isn't shared
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                    }
+                }
+            }
+            result?;
+        }
+        Ok(())
+    })
+}
+
+// Finds a free, page-aligned range in the kmap window and records it in the VMA table
+pub(super) fn reserve(name: &'static str, len: u64, kind: RegionKind, flags: PageTableFlags)
+    -> Result<VirtAddr, KmapError>
+{
+    if len == 0 {
+        return Err(KmapError::ZeroLength);
+    }
+    let size = round_up(len);
+    let mut vmm = VMM.lock();
+    let start = vmm.find_free(VirtAddr::new(KMAP_START), VirtAddr::new(KMAP_START + KMAP_SIZE), size)
+        .ok_or(KmapError::OutOfVirtualSpace)?;
+    vmm.insert(Region::new(name, start, size, kind, Permissions::from_flags(flags))).map_err(KmapError::Vma)?;
+    Ok(start)
+}
+
+fn is_kmap_region(region: &Region) -> bool {
+    let start = region.start.as_u64();
+    start >= KMAP_START && start < KMAP_START + KMAP_SIZE
+}
+
+fn round_up(len: u64) -> u64 {
+    (len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
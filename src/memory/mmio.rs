@@ -0,0 +1,68 @@
+/* Memory-mapped device registers. `map_mmio` maps a device's physical register window uncached and wraps it in an
+ * `Mmio<T>`, where T is the register width the device expects (u32 for the local APIC and IOAPIC, u64 for the HPET,
+ * ...). All accesses are volatile, bounds checked and aligned to T, so drivers never touch raw pointers. The window
+ * is unmapped again when the Mmio is dropped.
+ */
+use core::marker::PhantomData;
+use core::mem;
+use core::ptr;
+use super::kmap::{self, KmapError};
+use super::vma::RegionKind;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::PageTableFlags;
+
+pub struct Mmio<T> {
+    base: VirtAddr,
+    phys: PhysAddr,
+    len: u64,
+    _register: PhantomData<T>,
+}
+
+// Maps the `len` bytes of registers at `phys`
+pub fn map_mmio<T: Copy>(phys: PhysAddr, len: u64) -> Result<Mmio<T>, KmapError> {
+    assert!(phys.as_u64() % mem::align_of::<T>() as u64 == 0, "MMIO window {:#x} is misaligned", phys.as_u64());
+    // Device registers must never be cached or executed
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    let base = kmap::map_phys_region("mmio", RegionKind::Mmio, phys, len, flags)?;
+    Ok(Mmio { base, phys, len, _register: PhantomData })
+}
+
+impl<T: Copy> Mmio<T> {
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    // Size of the window in bytes
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    // Reads the register at byte offset `offset` from the start of the window
+    pub fn read(&self, offset: u64) -> T {
+        unsafe { ptr::read_volatile(self.register(offset)) }
+    }
+
+    pub fn write(&mut self, offset: u64, value: T) {
+        unsafe { ptr::write_volatile(self.register(offset), value) }
+    }
+
+    // Read-modify-write of a single register
+    pub fn update(&mut self, offset: u64, f: impl FnOnce(T) -> T) {
+        let value = self.read(offset);
+        self.write(offset, f(value));
+    }
+
+    fn register(&self, offset: u64) -> *mut T {
+        let size = mem::size_of::<T>() as u64;
+        assert!(offset + size <= self.len, "MMIO offset {:#x} is out of bounds ({:#x} bytes mapped)", offset, self.len);
+        assert!(offset % size == 0, "MMIO offset {:#x} is not aligned to the register size", offset);
+        (self.base + offset).as_mut_ptr()
+    }
+}
+
+impl<T> Drop for Mmio<T> {
+    fn drop(&mut self) {
+        kmap::kunmap(self.base).expect("failed to unmap an MMIO window");
+    }
+}
@@ -0,0 +1,74 @@
+/* Reporting on the bootloader-provided physical memory map, so we can check how much RAM the kernel actually sees
+ * (e.g. after changing QEMU's `-m` option). The bootloader derives the map from the BIOS' E820 map and marks the
+ * regions it used itself (kernel image, page tables, boot info) with their own types.
+ */
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Once;
+use crate::println;
+use crate::units::fmt_bytes;
+
+static MEMORY_MAP: Once<&'static MemoryMap> = Once::new();
+
+pub(super) fn init(memory_map: &'static MemoryMap) {
+    MEMORY_MAP.call_once(|| memory_map);
+}
+
+// The bootloader's memory map, or None before memory::init
+pub fn memory_map() -> Option<&'static MemoryMap> {
+    MEMORY_MAP.r#try().copied()
+}
+
+// Byte totals per kind of region
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemorySummary {
+    pub usable: u64,
+    // Used by the kernel image, its stack, page tables, the boot info and the bootloader itself
+    pub in_use: u64,
+    pub reserved: u64,
+    pub acpi_reclaimable: u64,
+    pub acpi_nvs: u64,
+    pub bad: u64,
+}
+
+impl MemorySummary {
+    pub fn total(&self) -> u64 {
+        self.usable + self.in_use + self.reserved + self.acpi_reclaimable + self.acpi_nvs + self.bad
+    }
+}
+
+pub fn summarize(memory_map: &MemoryMap) -> MemorySummary {
+    let mut summary = MemorySummary::default();
+    for region in memory_map.iter() {
+        let size = region.range.end_addr() - region.range.start_addr();
+        match region.region_type {
+            MemoryRegionType::Usable => summary.usable += size,
+            MemoryRegionType::AcpiReclaimable => summary.acpi_reclaimable += size,
+            MemoryRegionType::AcpiNvs => summary.acpi_nvs += size,
+            MemoryRegionType::BadMemory => summary.bad += size,
+            MemoryRegionType::Reserved | MemoryRegionType::FrameZero | MemoryRegionType::Empty => summary.reserved += size,
+            // Kernel, KernelStack, PageTable, Bootloader, BootInfo, Package, InUse
+            _ => summary.in_use += size,
+        }
+    }
+    summary
+}
+
+// Prints every region of the memory map followed by the totals
+pub fn dump_memory_map() {
+    let memory_map = match memory_map() {
+        Some(memory_map) => memory_map,
+        None => {
+            println!("memory map not available before memory::init");
+            return;
+        }
+    };
+    println!("Physical memory map:");
+    for region in memory_map.iter() {
+        let (start, end) = (region.range.start_addr(), region.range.end_addr());
+        println!("  {:#012x}-{:#012x} {:>10} {:?}", start, end, fmt_bytes(end - start), region.region_type);
+    }
+    let summary = summarize(memory_map);
+    println!("usable: {}, in use: {}, reserved: {}, ACPI: {} reclaimable + {} NVS, bad: {}",
+        fmt_bytes(summary.usable), fmt_bytes(summary.in_use), fmt_bytes(summary.reserved),
+        fmt_bytes(summary.acpi_reclaimable), fmt_bytes(summary.acpi_nvs), fmt_bytes(summary.bad));
+}
@@ -0,0 +1,266 @@
+/* A bitmap physical memory manager (PMM). Every 4 KiB frame up to the end of the highest usable region gets one
+ * bit: 1 means used (or not RAM at all), 0 means free. Compared with walking the memory map on every allocation:
+ *  - allocation scans 64 frames per word and remembers where it last found a free frame, so it's O(1) in practice
+ *  - freeing is just clearing a bit
+ *  - we can look for runs of free frames, which drivers need for physically contiguous (DMA) buffers
+ *
+ * The bitmap itself lives in the first usable region that's big enough for it, accessed through the physical
+ * memory mapping, and those frames are marked as used.
+ *
+ * With the `page-poison` feature, free frames are filled with a poison pattern and checked when they're allocated
+ * again, which catches writes through stale mappings of freed frames.
+ */
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::PhysAddr;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use super::phys_to_virt;
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+#[cfg(feature = "page-poison")]
+const POISON_WORD: u64 = 0x6b6b_6b6b_6b6b_6b6b;
+
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    // Number of frames the bitmap describes (frame i covers [i * 4096, (i + 1) * 4096))
+    frames: usize,
+    free_frames: usize,
+    // Word index to start the next search from; no word below it has a free frame
+    next_word: usize,
+    // Whether the frames are real memory that can be poisoned (false for test bitmaps)
+    poison: bool,
+}
+
+impl BitmapFrameAllocator {
+    /* Builds the allocator from the bootloader's memory map.
+     * Unsafe because the caller must guarantee that every frame marked `Usable` in the map is really unused and
+     * that physical memory is mapped (memory::init sets up phys_to_virt first).
+     */
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        let usable = || memory_map.iter().filter(|r| r.region_type == MemoryRegionType::Usable);
+        let end = usable().map(|r| r.range.end_addr()).max().expect("no usable memory");
+        let frames = (end / FRAME_SIZE) as usize;
+        let words = (frames + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_bytes = (words * 8) as u64;
+
+        // Put the bitmap at the start of the first usable region that can hold it
+        let bitmap_start = usable()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_bytes)
+            .expect("no usable region large enough for the frame bitmap")
+            .range.start_addr();
+        let bitmap_ptr = phys_to_virt(PhysAddr::new(bitmap_start)).as_mut_ptr::<u64>();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, words);
+
+        let mut allocator = BitmapFrameAllocator::from_bitmap(bitmap, frames);
+        for region in usable() {
+            allocator.mark_free(region.range.start_addr() / FRAME_SIZE, region.range.end_addr() / FRAME_SIZE);
+        }
+        let bitmap_frames = (bitmap_bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+        allocator.mark_used(bitmap_start / FRAME_SIZE, bitmap_start / FRAME_SIZE + bitmap_frames);
+        // Only now, so that poisoning the free frames can't overwrite the bitmap
+        allocator.poison = cfg!(feature = "page-poison");
+        for frame in 0..frames {
+            if !allocator.is_used(frame) {
+                allocator.poison_frame(frame);
+            }
+        }
+        allocator
+    }
+
+    // Wraps `bitmap` with every frame marked as used
+    pub fn from_bitmap(bitmap: &'static mut [u64], frames: usize) -> Self {
+        assert!(bitmap.len() * BITS_PER_WORD >= frames, "bitmap too small");
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+        BitmapFrameAllocator { bitmap, frames, free_frames: 0, next_word: 0, poison: false }
+    }
+
+    // Marks frames [start, end) as free
+    pub fn mark_free(&mut self, start: u64, end: u64) {
+        for frame in start as usize..(end as usize).min(self.frames) {
+            if self.is_used(frame) {
+                self.clear(frame);
+                self.free_frames += 1;
+                self.poison_frame(frame);
+            }
+        }
+        self.next_word = self.next_word.min(start as usize / BITS_PER_WORD);
+    }
+
+    // Marks frames [start, end) as used
+    pub fn mark_used(&mut self, start: u64, end: u64) {
+        for frame in start as usize..(end as usize).min(self.frames) {
+            if !self.is_used(frame) {
+                self.set(frame);
+                self.free_frames -= 1;
+            }
+        }
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /* Allocates `count` physically contiguous frames whose first frame is aligned to `align_frames` frames
+     * (a power of two) and returns the first one. This is a linear scan, so it's meant for driver setup rather than
+     * hot paths.
+     */
+    pub fn allocate_contiguous(&mut self, count: usize, align_frames: usize) -> Option<PhysFrame> {
+        assert!(align_frames.is_power_of_two(), "alignment must be a power of two");
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+        let mut start = 0;
+        while start + count <= self.frames {
+            // Find the first used frame in the candidate run, if any, and restart the search just past it
+            match (start..start + count).find(|&frame| self.is_used(frame)) {
+                Some(used) => start = (used + 1 + align_frames - 1) & !(align_frames - 1),
+                None => {
+                    for frame in start..start + count {
+                        self.set(frame);
+                        self.check_poison(frame);
+                    }
+                    self.free_frames -= count;
+                    return Some(frame_at(start));
+                }
+            }
+        }
+        None
+    }
+
+    // Frees `count` frames starting at `first`, as returned by allocate_contiguous
+    pub fn deallocate_contiguous(&mut self, first: PhysFrame, count: usize) {
+        let start = frame_index(first);
+        for frame in start..start + count {
+            self.free(frame);
+        }
+    }
+
+    fn free(&mut self, frame: usize) {
+        assert!(frame < self.frames && self.is_used(frame), "freeing frame {} which isn't allocated", frame);
+        self.clear(frame);
+        self.free_frames += 1;
+        self.next_word = self.next_word.min(frame / BITS_PER_WORD);
+        self.poison_frame(frame);
+    }
+
+    #[cfg(feature = "page-poison")]
+    fn poison_frame(&mut self, frame: usize) {
+        if self.poison {
+            let words = frame_words(frame);
+            for word in words.iter_mut() {
+                *word = POISON_WORD;
+            }
+        }
+    }
+
+    #[cfg(not(feature = "page-poison"))]
+    fn poison_frame(&mut self, _frame: usize) {}
+
+    // Panics if a free frame was written to since it was poisoned
+    #[cfg(feature = "page-poison")]
+    fn check_poison(&self, frame: usize) {
+        if self.poison {
+            if let Some(offset) = frame_words(frame).iter().position(|&word| word != POISON_WORD) {
+                panic!("frame use-after-free: frame {:#x} was modified at offset {:#x} after being freed",
+                    frame as u64 * FRAME_SIZE, offset * 8);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "page-poison"))]
+    fn check_poison(&self, _frame: usize) {}
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    fn set(&mut self, frame: usize) {
+        self.bitmap[frame / BITS_PER_WORD] |= 1 << (frame % BITS_PER_WORD);
+    }
+
+    fn clear(&mut self, frame: usize) {
+        self.bitmap[frame / BITS_PER_WORD] &= !(1 << (frame % BITS_PER_WORD));
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        for word_index in self.next_word..self.bitmap.len() {
+            let word = self.bitmap[word_index];
+            if word == !0 {
+                continue;
+            }
+            // The lowest zero bit is the first free frame in this word
+            let frame = word_index * BITS_PER_WORD + (!word).trailing_zeros() as usize;
+            if frame >= self.frames {
+                break;
+            }
+            self.set(frame);
+            self.free_frames -= 1;
+            self.next_word = word_index;
+            self.check_poison(frame);
+            return Some(frame_at(frame));
+        }
+        self.next_word = self.bitmap.len();
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    // Unsafe because the caller must guarantee the frame is no longer mapped or used anywhere
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free(frame_index(frame));
+    }
+}
+
+// The contents of a frame, through the physical memory mapping
+#[cfg(feature = "page-poison")]
+fn frame_words(frame: usize) -> &'static mut [u64] {
+    let virt = phys_to_virt(PhysAddr::new(frame as u64 * FRAME_SIZE));
+    unsafe { core::slice::from_raw_parts_mut(virt.as_mut_ptr(), FRAME_SIZE as usize / 8) }
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+fn frame_index(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_bitmap_allocate_and_free() {
+    static mut BITMAP: [u64; 2] = [0; 2];
+    let mut pmm = BitmapFrameAllocator::from_bitmap(unsafe { &mut BITMAP }, 100);
+    pmm.mark_free(10, 100);
+    assert_eq!(pmm.free_frames(), 90);
+    let first = pmm.allocate_frame().unwrap();
+    assert_eq!(frame_index(first), 10);
+    assert_eq!(frame_index(pmm.allocate_frame().unwrap()), 11);
+    unsafe { pmm.deallocate_frame(first) };
+    assert_eq!(frame_index(pmm.allocate_frame().unwrap()), 10);
+    assert_eq!(pmm.free_frames(), 88);
+}
+
+#[test_case]
+fn test_bitmap_allocate_contiguous() {
+    static mut BITMAP: [u64; 2] = [0; 2];
+    let mut pmm = BitmapFrameAllocator::from_bitmap(unsafe { &mut BITMAP }, 128);
+    pmm.mark_free(3, 128);
+    pmm.mark_used(9, 10);
+    // [3, 9) is too short for 8 aligned frames, so the run has to start at 16
+    let run = pmm.allocate_contiguous(8, 8).unwrap();
+    assert_eq!(frame_index(run), 16);
+    assert!(pmm.allocate_contiguous(200, 1).is_none());
+    pmm.deallocate_contiguous(run, 8);
+    assert_eq!(pmm.free_frames(), 124);
+}
@@ -0,0 +1,89 @@
+/* Page table introspection: walks the four levels of the active page table and prints every mapping in a virtual
+ * address range, with its flags and frame address. Runs of pages that map contiguous frames with the same flags are
+ * printed as a single line, so dumping e.g. the whole physical memory window stays readable.
+ *
+ * The tables are read through the physical memory mapping (see memory.rs), not through the mapper, so this also
+ * works on tables that aren't the active ones.
+ */
+use core::ops::Range;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use super::{phys_to_virt, Permissions};
+use crate::println;
+use crate::units::fmt_bytes;
+
+// Prints the mappings of the active address space that overlap `range`
+pub fn dump_page_table(range: Range<VirtAddr>) {
+    let (level_4_frame, _) = Cr3::read();
+    dump_page_table_at(level_4_frame, range);
+}
+
+// Same as dump_page_table, but for the level 4 table in `level_4_frame`
+pub fn dump_page_table_at(level_4_frame: PhysFrame, range: Range<VirtAddr>) {
+    println!("Page table at {:#x}, range {:#x}-{:#x}:",
+        level_4_frame.start_address().as_u64(), range.start.as_u64(), range.end.as_u64());
+    let mut run = None;
+    walk(level_4_frame.start_address(), 4, 0, &range, &mut run);
+    if let Some(run) = run {
+        print_run(&run);
+    }
+}
+
+// A contiguous stretch of mappings waiting to be printed
+struct Run {
+    virt: u64,
+    phys: u64,
+    size: u64,
+    flags: PageTableFlags,
+}
+
+fn walk(table: PhysAddr, level: u8, base: u64, range: &Range<VirtAddr>, run: &mut Option<Run>) {
+    let table: &PageTable = unsafe { &*phys_to_virt(table).as_ptr() };
+    // Each entry at level 1 covers 4 KiB, each level up covers 512 times as much
+    let entry_size = 1u64 << (12 + 9 * (level as u64 - 1));
+    for (i, entry) in table.iter().enumerate() {
+        let virt = canonical(base + i as u64 * entry_size);
+        // Inclusive, since the last entry's exclusive end would overflow
+        let last = virt + (entry_size - 1);
+        if last < range.start.as_u64() || virt >= range.end.as_u64() {
+            continue;
+        }
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            add_mapping(run, virt, entry.addr().as_u64(), entry_size, flags);
+        } else {
+            walk(entry.addr(), level - 1, virt, range, run);
+        }
+    }
+}
+
+fn add_mapping(run: &mut Option<Run>, virt: u64, phys: u64, size: u64, flags: PageTableFlags) {
+    // The CPU sets ACCESSED and DIRTY as it goes, so they shouldn't split a run
+    let flags = flags - PageTableFlags::ACCESSED - PageTableFlags::DIRTY;
+    if let Some(current) = run {
+        if current.virt + current.size == virt && current.phys + current.size == phys && current.flags == flags {
+            current.size += size;
+            return;
+        }
+        print_run(current);
+    }
+    *run = Some(Run { virt, phys, size, flags });
+}
+
+fn print_run(run: &Run) {
+    println!("  {:#018x}-{:#018x} -> {:#012x} {:>10} {}{}{}{}",
+        run.virt, run.virt + run.size, run.phys, fmt_bytes(run.size),
+        Permissions::from_flags(run.flags),
+        if run.flags.contains(PageTableFlags::HUGE_PAGE) { " huge" } else { "" },
+        if run.flags.contains(PageTableFlags::GLOBAL) { " global" } else { "" },
+        if run.flags.contains(PageTableFlags::NO_CACHE) { " uncached" } else { "" });
+}
+
+// Addresses above the lower half have to be sign-extended from bit 47
+fn canonical(addr: u64) -> u64 {
+    if addr & (1 << 47) != 0 { addr | 0xffff_0000_0000_0000 } else { addr }
+}
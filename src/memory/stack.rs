@@ -0,0 +1,69 @@
+/* Kernel stacks for new tasks and IST entries. Each stack gets its own region in the kmap window with one unmapped
+ * guard page below it, so an overflow page faults right away instead of silently overwriting whatever happens to be
+ * mapped below. The guard page is part of the stack's VMA region, which lets the page fault handler tell a stack
+ * overflow from any other bad access.
+ */
+use super::kmap::{self, KmapError};
+use super::vma::RegionKind;
+use super::with_page_tables;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelStack {
+    // Start of the guard page, which is also the start of the stack's VMA region
+    guard: VirtAddr,
+    top: VirtAddr,
+}
+
+impl KernelStack {
+    // The initial stack pointer: one past the highest usable byte (stacks grow down)
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    // The lowest usable address
+    pub fn bottom(&self) -> VirtAddr {
+        self.guard + PAGE_SIZE
+    }
+
+    pub fn guard_page(&self) -> Page<Size4KiB> {
+        Page::containing_address(self.guard)
+    }
+}
+
+// Maps a zeroed stack of `pages` pages (plus an unmapped guard page below it)
+pub fn alloc_kernel_stack(pages: u64) -> Result<KernelStack, KmapError> {
+    if pages == 0 {
+        return Err(KmapError::ZeroLength);
+    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let guard = kmap::reserve("kernel stack", (pages + 1) * PAGE_SIZE, RegionKind::Stack, flags)?;
+    let bottom = guard + PAGE_SIZE;
+    let top = bottom + pages * PAGE_SIZE;
+    let first = Page::<Size4KiB>::containing_address(bottom);
+    let result = with_page_tables(|mapper, frame_allocator| -> Result<(), KmapError> {
+        for page in Page::range(first, first + pages) {
+            let frame = frame_allocator.allocate_frame().ok_or(KmapError::FrameAllocationFailed)?;
+            unsafe {
+                mapper.map_to(page, frame, flags, frame_allocator).map_err(KmapError::MapTo)?.flush();
+            }
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        let _ = kmap::kunmap(guard);
+        return Err(e);
+    }
+    unsafe { core::ptr::write_bytes(bottom.as_mut_ptr::<u8>(), 0, (pages * PAGE_SIZE) as usize) };
+    Ok(KernelStack { guard, top })
+}
+
+/* Unmaps a stack and returns its frames.
+ * Unsafe because nothing may be running on the stack, or hold pointers into it, anymore.
+ */
+pub unsafe fn free_kernel_stack(stack: KernelStack) -> Result<(), KmapError> {
+    kmap::kunmap(stack.guard)
+}
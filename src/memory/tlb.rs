@@ -0,0 +1,48 @@
+/* TLB maintenance. Whenever a mapping is removed or made more restrictive, every CPU that might have cached the old
+ * translation has to drop it, or it can keep using the old frame. Unmap paths should call flush_tlb_range for the
+ * whole range once they're done, instead of flushing page by page through the mapper's MapperFlush (which only ever
+ * affects the current CPU).
+ *
+ * We only run on one CPU for now, so a flush is just local invlpg (or a full flush for big ranges). The remote half
+ * is kept separate so that once SMP lands it can send an IPI to the other online CPUs and wait for them to flush too,
+ * without any caller changing.
+ */
+use core::ops::Range;
+use x86_64::VirtAddr;
+use x86_64::instructions::tlb;
+
+const PAGE_SIZE: u64 = 4096;
+// Past this many pages, reloading CR3 is cheaper than invalidating pages one by one
+const FULL_FLUSH_THRESHOLD: u64 = 32;
+
+// Makes every CPU forget cached translations for the pages overlapping `range`
+pub fn flush_tlb_range(range: Range<VirtAddr>) {
+    if range.start >= range.end {
+        return;
+    }
+    flush_local(range.clone());
+    shootdown_remote(range);
+}
+
+// Makes every CPU forget all its cached (non-global) translations
+pub fn flush_tlb_all() {
+    tlb::flush_all();
+    shootdown_remote(VirtAddr::new(0)..VirtAddr::new(0));
+}
+
+fn flush_local(range: Range<VirtAddr>) {
+    let first = range.start.align_down(PAGE_SIZE);
+    let pages = (range.end - first + PAGE_SIZE - 1) / PAGE_SIZE;
+    if pages > FULL_FLUSH_THRESHOLD {
+        tlb::flush_all();
+        return;
+    }
+    for i in 0..pages {
+        tlb::flush(first + i * PAGE_SIZE);
+    }
+}
+
+/* Asks the other CPUs to flush `range` (an empty range means everything) and waits until they have. There are no
+ * other CPUs yet, so there's nothing to do.
+ */
+fn shootdown_remote(_range: Range<VirtAddr>) {}
@@ -0,0 +1,238 @@
+/* A Virtual Memory Area (VMA) is a contiguous, named range of virtual addresses with a single set of permissions.
+ * Linux keeps one `vm_area_struct` per region of a process; we keep a single kernel-wide table so that every
+ * mapping (heap, stacks, MMIO windows, and later user mappings) is recorded in one place. Nothing here touches the
+ * page tables: the manager only answers "is this range free?" and "what lives at this address?".
+ *
+ * We don't have a heap yet, so the table is a fixed-size array kept sorted by start address.
+ */
+use core::fmt;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+use crate::println;
+use crate::units::fmt_bytes;
+
+const MAX_REGIONS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Kernel,
+    Heap,
+    Stack,
+    Mmio,
+    // Memory handed out by kmap that isn't backed by anything in particular
+    Anonymous,
+    // Physically contiguous buffers that devices access directly (see dma.rs)
+    Dma,
+    User,
+}
+
+// Reading is always allowed for a mapped region, so only the interesting bits are tracked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub write: bool,
+    pub execute: bool,
+    pub user: bool,
+}
+
+impl Permissions {
+    pub const READ_ONLY: Permissions = Permissions { write: false, execute: false, user: false };
+    pub const READ_WRITE: Permissions = Permissions { write: true, execute: false, user: false };
+    pub const READ_EXECUTE: Permissions = Permissions { write: false, execute: true, user: false };
+
+    // The permissions a page table entry with these flags grants
+    pub fn from_flags(flags: PageTableFlags) -> Permissions {
+        Permissions {
+            write: flags.contains(PageTableFlags::WRITABLE),
+            execute: !flags.contains(PageTableFlags::NO_EXECUTE),
+            user: flags.contains(PageTableFlags::USER_ACCESSIBLE),
+        }
+    }
+}
+
+// Prints like the permission column of /proc/<pid>/maps, e.g. `rw-k`
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "r{}{}{}",
+            if self.write { 'w' } else { '-' },
+            if self.execute { 'x' } else { '-' },
+            if self.user { 'u' } else { 'k' })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub size: u64,
+    pub kind: RegionKind,
+    pub permissions: Permissions,
+}
+
+impl Region {
+    pub fn new(name: &'static str, start: VirtAddr, size: u64, kind: RegionKind, permissions: Permissions) -> Region {
+        Region { name, start, size, kind, permissions }
+    }
+
+    // Exclusive end address
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    pub fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    EmptyRegion,
+    // The new region overlaps an already registered one (whose name is given)
+    Overlap(&'static str),
+    TableFull,
+    NotFound,
+}
+
+pub struct VirtualMemoryManager {
+    // Sorted by start address; only the first `len` entries are Some
+    regions: [Option<Region>; MAX_REGIONS],
+    len: usize,
+}
+
+impl VirtualMemoryManager {
+    pub const fn new() -> VirtualMemoryManager {
+        VirtualMemoryManager { regions: [None; MAX_REGIONS], len: 0 }
+    }
+
+    pub fn insert(&mut self, region: Region) -> Result<(), VmaError> {
+        if region.size == 0 {
+            return Err(VmaError::EmptyRegion);
+        }
+        if let Some(existing) = self.iter().find(|r| r.overlaps(&region)) {
+            return Err(VmaError::Overlap(existing.name));
+        }
+        if self.len == MAX_REGIONS {
+            return Err(VmaError::TableFull);
+        }
+        // Find the insertion point, then shift everything after it one slot to the right
+        let index = self.iter().position(|r| r.start > region.start).unwrap_or(self.len);
+        for i in (index..self.len).rev() {
+            self.regions[i + 1] = self.regions[i];
+        }
+        self.regions[index] = Some(region);
+        self.len += 1;
+        Ok(())
+    }
+
+    // Removes the region starting exactly at `start`
+    pub fn remove(&mut self, start: VirtAddr) -> Result<Region, VmaError> {
+        let index = self.iter().position(|r| r.start == start).ok_or(VmaError::NotFound)?;
+        let region = self.regions[index].take().unwrap();
+        for i in index..self.len - 1 {
+            self.regions[i] = self.regions[i + 1];
+        }
+        self.regions[self.len - 1] = None;
+        self.len -= 1;
+        Ok(region)
+    }
+
+    // Returns the lowest page-aligned address in [window_start, window_end) with `size` free bytes after it
+    pub fn find_free(&self, window_start: VirtAddr, window_end: VirtAddr, size: u64) -> Option<VirtAddr> {
+        let mut candidate = window_start.align_up(4096u64);
+        for region in self.iter().filter(|r| r.end() > window_start && r.start < window_end) {
+            if region.start >= candidate + size {
+                break;
+            }
+            if region.end() > candidate {
+                candidate = region.end().align_up(4096u64);
+            }
+        }
+        if candidate + size <= window_end { Some(candidate) } else { None }
+    }
+
+    // Returns the region containing `addr`, if any
+    pub fn find(&self, addr: VirtAddr) -> Option<&Region> {
+        self.iter().find(|r| r.contains(addr))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions[..self.len].iter().filter_map(|r| r.as_ref())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn dump(&self) {
+        println!("{} virtual memory regions:", self.len);
+        for region in self.iter() {
+            println!("{:#018x}-{:#018x} {:>10} {} {:?} {}",
+                region.start.as_u64(), region.end().as_u64(), fmt_bytes(region.size),
+                region.permissions, region.kind, region.name);
+        }
+    }
+}
+
+// The kernel-wide region table. Interrupt handlers never touch it, so a plain spin lock is enough.
+pub static VMM: Mutex<VirtualMemoryManager> = Mutex::new(VirtualMemoryManager::new());
+
+// Registers regions that exist before we start mapping anything ourselves
+pub fn register_boot_regions() {
+    let mut vmm = VMM.lock();
+    // The bootloader identity-maps the VGA text buffer (see vga_buffer.rs)
+    vmm.insert(Region::new("vga text buffer", VirtAddr::new(0xb8000), 4096, RegionKind::Mmio, Permissions::READ_WRITE))
+        .expect("failed to register the VGA buffer region");
+    let (stack_start, stack_size) = crate::gdt::double_fault_stack();
+    vmm.insert(Region::new("double fault stack", stack_start, stack_size, RegionKind::Stack, Permissions::READ_WRITE))
+        .expect("failed to register the double fault stack region");
+    let (stack_start, stack_size) = crate::gdt::nmi_stack();
+    vmm.insert(Region::new("nmi stack", stack_start, stack_size, RegionKind::Stack, Permissions::READ_WRITE))
+        .expect("failed to register the NMI stack region");
+    let (stack_start, stack_size) = crate::gdt::early_fault_stack();
+    vmm.insert(Region::new("early fault stack", stack_start, stack_size, RegionKind::Stack, Permissions::READ_WRITE))
+        .expect("failed to register the early fault stack region");
+}
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_vma_insert_keeps_regions_sorted() {
+    let mut vmm = VirtualMemoryManager::new();
+    vmm.insert(Region::new("b", VirtAddr::new(0x2000), 0x1000, RegionKind::Heap, Permissions::READ_WRITE)).unwrap();
+    vmm.insert(Region::new("a", VirtAddr::new(0x1000), 0x1000, RegionKind::Heap, Permissions::READ_WRITE)).unwrap();
+    let mut names = vmm.iter().map(|r| r.name);
+    assert_eq!(names.next(), Some("a"));
+    assert_eq!(names.next(), Some("b"));
+    assert_eq!(vmm.find(VirtAddr::new(0x2fff)).map(|r| r.name), Some("b"));
+    assert!(vmm.find(VirtAddr::new(0x3000)).is_none());
+}
+
+#[test_case]
+fn test_vma_detects_overlap() {
+    let mut vmm = VirtualMemoryManager::new();
+    vmm.insert(Region::new("a", VirtAddr::new(0x1000), 0x2000, RegionKind::Stack, Permissions::READ_WRITE)).unwrap();
+    let overlapping = Region::new("b", VirtAddr::new(0x2000), 0x2000, RegionKind::Heap, Permissions::READ_WRITE);
+    assert_eq!(vmm.insert(overlapping), Err(VmaError::Overlap("a")));
+    assert_eq!(vmm.remove(VirtAddr::new(0x1000)).map(|r| r.name), Ok("a"));
+    assert_eq!(vmm.insert(overlapping), Ok(()));
+}
+
+#[test_case]
+fn test_vma_find_free() {
+    let mut vmm = VirtualMemoryManager::new();
+    vmm.insert(Region::new("a", VirtAddr::new(0x1000), 0x1000, RegionKind::Anonymous, Permissions::READ_WRITE)).unwrap();
+    vmm.insert(Region::new("b", VirtAddr::new(0x3000), 0x1000, RegionKind::Anonymous, Permissions::READ_WRITE)).unwrap();
+    let (start, end) = (VirtAddr::new(0x1000), VirtAddr::new(0x6000));
+    assert_eq!(vmm.find_free(start, end, 0x1000), Some(VirtAddr::new(0x2000)));
+    assert_eq!(vmm.find_free(start, end, 0x2000), Some(VirtAddr::new(0x4000)));
+    assert_eq!(vmm.find_free(start, end, 0x3000), None);
+}
@@ -0,0 +1,140 @@
+/* PS/2 mouse, on the 8042 controller's second (auxiliary) port, which i8042::init enables if it finds a device there.
+ * Init sets the mouse to its defaults, turns on data reporting and claims its interrupt (IRQ 12). From then on every
+ * movement or button change arrives as a 3-byte packet, one byte per interrupt:
+ *
+ *   byte 0: bit 0-2 left/right/middle button, bit 3 always set, bit 4/5 sign of X/Y, bit 6/7 X/Y overflow
+ *   byte 1: X movement, byte 2: Y movement (9-bit two's complement with the sign bits of byte 0; positive Y is up)
+ *
+ * The interrupt handler only collects bytes; a complete packet is decoded as deferred work and published as input
+ * events. https://wiki.osdev.org/PS/2_Mouse
+ */
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
+use crate::input::{self, InputEvent, MouseButton};
+use crate::interrupts::vectors::{self, VectorError, VectorGuard};
+use crate::sync::InterruptGuard;
+use crate::{deferred, i8042};
+
+const IRQ: u8 = 12;
+
+const MOUSE_SET_DEFAULTS: u8 = 0xf6;
+const MOUSE_ENABLE_REPORTING: u8 = 0xf4;
+const MOUSE_ACK: u8 = 0xfa;
+
+const PACKET_ALWAYS_SET: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+const PACKET_BUTTONS: u8 = 0b111;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseError {
+    // The controller has no second port, or nothing answered on it
+    NoDevice,
+    // The controller or the mouse didn't answer (most likely there is no mouse)
+    Timeout,
+    // The mouse answered a command with something other than an ACK
+    NotAcknowledged(u8),
+    Irq(VectorError),
+}
+
+// The handler's claim on IRQ 12, kept for as long as the mouse is enabled
+static IRQ_GUARD: Mutex<Option<VectorGuard>> = Mutex::new(None);
+// The packet being received; only the interrupt handler (and init, with interrupts disabled) touches it
+static PACKET: Mutex<([u8; 3], usize)> = Mutex::new(([0; 3], 0));
+// Buttons held as of the last packet, to publish only the changes
+static BUTTONS: AtomicU8 = AtomicU8::new(0);
+static DROPPED_PACKETS: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() -> Result<(), MouseError> {
+    if !i8042::has_aux() {
+        return Err(MouseError::NoDevice);
+    }
+    let _guard = InterruptGuard::new();
+    send(MOUSE_SET_DEFAULTS)?;
+    send(MOUSE_ENABLE_REPORTING)?;
+    *PACKET.lock() = ([0; 3], 0);
+    BUTTONS.store(0, Ordering::Relaxed);
+    let guard = vectors::allocate_irq(IRQ, mouse_interrupt).map_err(MouseError::Irq)?;
+    *IRQ_GUARD.lock() = Some(guard);
+    Ok(())
+}
+
+// Sends a command byte to the mouse and waits for its ACK
+fn send(command: u8) -> Result<(), MouseError> {
+    // Unsafe because mouse commands change what it reports; these are the ones init means to send
+    if !unsafe { i8042::send_command(i8042::COMMAND_WRITE_AUX) && i8042::write_data(command) } {
+        return Err(MouseError::Timeout);
+    }
+    match i8042::read_data_timeout() {
+        Some(MOUSE_ACK) => Ok(()),
+        Some(reply) => Err(MouseError::NotAcknowledged(reply)),
+        None => Err(MouseError::Timeout),
+    }
+}
+
+fn mouse_interrupt() {
+    use x86_64::instructions::port::Port;
+    if !i8042::output_from_aux() {
+        return;
+    }
+    let byte: u8 = unsafe { Port::new(0x60).read() };
+    let mut packet = PACKET.lock();
+    let (bytes, len) = &mut *packet;
+    // Bit 3 of the first byte is always set; without it we're out of step, so wait for the next first byte
+    if *len == 0 && byte & PACKET_ALWAYS_SET == 0 {
+        return;
+    }
+    bytes[*len] = byte;
+    *len += 1;
+    if *len == bytes.len() {
+        *len = 0;
+        let packed = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]);
+        if !deferred::schedule(process_packet, packed as usize) {
+            DROPPED_PACKETS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Number of packets lost because the deferred work queue was full
+pub fn dropped_packets() -> u64 {
+    DROPPED_PACKETS.load(Ordering::Relaxed)
+}
+
+// Movement and buttons from a packet; movement that overflowed is reported as none
+fn decode(packet: [u8; 3]) -> (i16, i16, u8) {
+    let flags = packet[0];
+    let axis = |value: u8, negative: bool| if negative { value as i16 - 0x100 } else { value as i16 };
+    let dx = if flags & PACKET_X_OVERFLOW != 0 { 0 } else { axis(packet[1], flags & PACKET_X_SIGN != 0) };
+    let dy = if flags & PACKET_Y_OVERFLOW != 0 { 0 } else { axis(packet[2], flags & PACKET_Y_SIGN != 0) };
+    (dx, dy, flags & PACKET_BUTTONS)
+}
+
+fn process_packet(packed: usize) {
+    let bytes = (packed as u32).to_le_bytes();
+    let (dx, dy, buttons) = decode([bytes[0], bytes[1], bytes[2]]);
+    input::report_overflow();
+    if dx != 0 || dy != 0 {
+        input::publish(InputEvent::MouseMove { dx, dy });
+    }
+    let changed = BUTTONS.swap(buttons, Ordering::Relaxed) ^ buttons;
+    for (bit, button) in [MouseButton::Left, MouseButton::Right, MouseButton::Middle].iter().enumerate() {
+        if changed & (1 << bit) != 0 {
+            input::publish(InputEvent::MouseButton { button: *button, pressed: buttons & (1 << bit) != 0 });
+        }
+    }
+}
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_decode_packet() {
+    // Left button held, moved right 5 and down 3
+    assert_eq!(decode([0b0010_1001, 5, 0xfd]), (5, -3, 0b001));
+    // Moved left 256 (the most a packet can say)
+    assert_eq!(decode([0b0001_1000, 0, 0]), (-256, 0, 0));
+    // X overflowed: only Y is reported
+    assert_eq!(decode([0b0100_1010, 0xff, 7]), (0, 7, 0b010));
+}
@@ -0,0 +1,64 @@
+/* Panic hooks let subsystems get a last word before the kernel halts: the block layer can flush its cache, a tracer
+ * can dump its buffer, a NIC driver can stop DMA. Hooks run in registration order from the panic handlers in
+ * main.rs and lib.rs, before they print the final message and halt (or exit QEMU).
+ *
+ * The hook table is a fixed array of atomics rather than a Mutex: a panic can happen while the table is being
+ * modified (or while any lock is held), and the panic path must never block.
+ */
+pub mod screen; // The report main.rs's panic handler fills the screen with: message, registers, stack
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub type PanicHook = fn(&PanicInfo);
+
+const MAX_HOOKS: usize = 8;
+
+// Each slot holds a PanicHook cast to usize, or 0 if it's free
+static HOOKS: [AtomicUsize; MAX_HOOKS] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+// Set once the hooks start running, so a hook that panics itself doesn't re-run the whole chain
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicHookError {
+    TableFull,
+    NotRegistered,
+}
+
+pub fn add_hook(hook: PanicHook) -> Result<(), PanicHookError> {
+    for slot in HOOKS.iter() {
+        if slot.compare_exchange(0, hook as usize, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            return Ok(());
+        }
+    }
+    Err(PanicHookError::TableFull)
+}
+
+pub fn remove_hook(hook: PanicHook) -> Result<(), PanicHookError> {
+    for slot in HOOKS.iter() {
+        if slot.compare_exchange(hook as usize, 0, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            return Ok(());
+        }
+    }
+    Err(PanicHookError::NotRegistered)
+}
+
+/* Runs every registered hook once. If a hook panics, the nested panic lands back here and returns immediately,
+ * so the panic handler still gets to print the (nested) message and halt.
+ */
+pub fn run_hooks(info: &PanicInfo) {
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    for slot in HOOKS.iter() {
+        let hook = slot.load(Ordering::SeqCst);
+        if hook != 0 {
+            // Safe because only PanicHooks are ever stored in the table
+            let hook: PanicHook = unsafe { core::mem::transmute(hook) };
+            hook(info);
+        }
+    }
+}
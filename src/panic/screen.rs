@@ -0,0 +1,156 @@
+/* The panic screen: what the panic handler in main.rs leaves on the screen when the kernel dies. It switches to the
+ * kernel log (tty1) and fills it with a report in red on gray: the panic message and where it happened, the crash
+ * signature, the registers and the top of the stack. The same report goes to serial first, through emergency_print,
+ * because the panic may have hit while a print held the screen's or the console's locks, which are never released
+ * after that. The screen is painted last, after taking its locks by force.
+ *
+ * The registers are read in the panic handler itself, so rsp and rbp are its frame and the general purpose registers
+ * hold whatever the panicking code and the handler left in them. Not the state at the panic, but often a hint.
+ */
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use x86_64::registers::control::{Cr0, Cr2, Cr3};
+use x86_64::registers::rflags;
+use crate::vga_buffer::{self, Color};
+use crate::{crash, serial};
+
+const REGISTER_COUNT: usize = 20;
+const REGISTERS_PER_LINE: usize = 3;
+// How much of the stack is shown, in rows of STACK_WORDS_PER_ROW 64-bit words
+const STACK_ROWS: usize = 6;
+const STACK_WORDS_PER_ROW: usize = 4;
+
+// Reads a general purpose register: read_register!("mov %rax, $0")
+macro_rules! read_register {
+    ($template:tt) => {{
+        let value: u64;
+        unsafe { llvm_asm!($template : "=r"(value)) };
+        value
+    }};
+}
+
+pub struct Registers {
+    // Name and value, in the order they're shown
+    values: [(&'static str, u64); REGISTER_COUNT],
+}
+
+impl Registers {
+    // Always inlined, so the stack and frame pointers are the caller's
+    #[inline(always)]
+    pub fn capture() -> Registers {
+        let (level_4_table, cr3_flags) = Cr3::read();
+        Registers {
+            values: [
+                ("rax", read_register!("mov %rax, $0")),
+                ("rbx", read_register!("mov %rbx, $0")),
+                ("rcx", read_register!("mov %rcx, $0")),
+                ("rdx", read_register!("mov %rdx, $0")),
+                ("rsi", read_register!("mov %rsi, $0")),
+                ("rdi", read_register!("mov %rdi, $0")),
+                ("rbp", read_register!("mov %rbp, $0")),
+                ("rsp", read_register!("mov %rsp, $0")),
+                ("r8", read_register!("mov %r8, $0")),
+                ("r9", read_register!("mov %r9, $0")),
+                ("r10", read_register!("mov %r10, $0")),
+                ("r11", read_register!("mov %r11, $0")),
+                ("r12", read_register!("mov %r12, $0")),
+                ("r13", read_register!("mov %r13, $0")),
+                ("r14", read_register!("mov %r14, $0")),
+                ("r15", read_register!("mov %r15, $0")),
+                ("rflags", rflags::read_raw()),
+                ("cr0", Cr0::read_raw()),
+                // The last page fault's address
+                ("cr2", Cr2::read().as_u64()),
+                ("cr3", level_4_table.start_address().as_u64() | cr3_flags.bits()),
+            ],
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.values.iter().find(|(register, _)| *register == name).map(|&(_, value)| value)
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in self.values.chunks(REGISTERS_PER_LINE) {
+            for (name, value) in line {
+                write!(f, " {:>6} {:016x} ", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/* Shows the report for `info`. Never returns to anything else being printed: interrupts stay disabled from here on,
+ * so the only output after it is the panic handler's own.
+ */
+pub fn show(info: &PanicInfo, registers: &Registers) {
+    x86_64::instructions::interrupts::disable();
+    // Once, from here: the signature of a panic that wasn't raised by a fault depends on the frames above the call
+    let crash = crash::report();
+    let report = Report { info, registers, crash: &crash };
+    // Before anything that could wait on a lock, so the report gets out whatever happens to the screen
+    serial::emergency_print(format_args!("{}", report));
+    // Whoever held them won't run again: interrupts are off and the panic handler never returns
+    unsafe { vga_buffer::force_unlock() };
+    vga_buffer::switch_to(0);
+    let mut writer = vga_buffer::WRITER.lock();
+    // Background colors only go up to LightGray; the brighter ones would make the text blink
+    writer.set_color(Color::Red, Color::LightGray);
+    writer.clear_screen();
+    let _ = write!(writer, "{}", report);
+}
+
+struct Report<'a> {
+    info: &'a PanicInfo<'a>,
+    registers: &'a Registers,
+    crash: &'a crash::CrashReport,
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, " *** KERNEL PANIC ***")?;
+        writeln!(f)?;
+        writeln!(f, " {}", self.info)?;
+        writeln!(f, " {}", self.crash)?;
+        writeln!(f)?;
+        write!(f, "{}", self.registers)?;
+        if let Some(rsp) = self.registers.get("rsp") {
+            writeln!(f)?;
+            writeln!(f, " stack at {:#x}:", rsp)?;
+            write_stack(f, rsp)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_stack(f: &mut fmt::Formatter, rsp: u64) -> fmt::Result {
+    for row in 0..STACK_ROWS {
+        let offset = row * STACK_WORDS_PER_ROW * 8;
+        write!(f, " +{:#04x}", offset)?;
+        for word in 0..STACK_WORDS_PER_ROW {
+            // rsp is the panic handler's, and the frames of everything that led to the panic are above it
+            let value = unsafe { core::ptr::read_volatile((rsp as usize + offset + word * 8) as *const u64) };
+            write!(f, " {:016x}", value)?;
+        }
+        writeln!(f)?;
+    }
+    Ok(())
+}
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_capture_reads_the_callers_frame() {
+    let local = 0u64;
+    let registers = Registers::capture();
+    // `local` is in this function's frame, a little above its stack pointer
+    let here = &local as *const u64 as u64;
+    let rsp = registers.get("rsp").unwrap();
+    assert!(rsp <= here && here - rsp < 4096);
+    assert_eq!(registers.get("cr3").map(|cr3| cr3 & !0xfff), Some(Cr3::read().0.start_address().as_u64()));
+    assert_eq!(registers.get("xmm0"), None);
+}
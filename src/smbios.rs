@@ -0,0 +1,294 @@
+/* SMBIOS (DMI): the firmware's description of the machine it runs on, which tells QEMU's machine types apart from real
+ * boards. An entry point structure somewhere in 0xF0000-0xFFFFF ("_SM_" for 2.x, "_SM3_" for 3.x, on a 16-byte
+ * boundary) gives the address and size of the structure table. Each structure has a 4-byte header (type, length,
+ * handle), `length` bytes of fields, then its strings, NUL terminated, with an extra NUL after the last one. Fields
+ * refer to strings by their 1-based index, 0 meaning "no string".
+ *
+ * We only decode the few types we report (BIOS, system, memory devices), and read them in place through the physical
+ * memory mapping, so this needs memory::init but not the heap.
+ * https://wiki.osdev.org/System_Management_BIOS and the DMTF's SMBIOS specification (DSP0134)
+ */
+use core::fmt;
+use core::slice;
+use spin::Once;
+use x86_64::PhysAddr;
+use crate::acpi;
+use crate::memory::phys_to_virt;
+
+pub const TYPE_BIOS: u8 = 0;
+pub const TYPE_SYSTEM: u8 = 1;
+pub const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_END: u8 = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmbiosError {
+    // No valid entry point in the BIOS area
+    NoEntryPoint,
+    BadChecksum(PhysAddr),
+}
+
+pub struct Smbios {
+    pub major: u8,
+    pub minor: u8,
+    table: &'static [u8],
+}
+
+static SMBIOS: Once<Result<Smbios, SmbiosError>> = Once::new();
+
+// The SMBIOS tables, found on the first call (which must come after memory::init)
+pub fn smbios() -> Result<&'static Smbios, SmbiosError> {
+    SMBIOS.call_once(find).as_ref().map_err(|&e| e)
+}
+
+fn find() -> Result<Smbios, SmbiosError> {
+    // A 3.x entry point describes the table in full (a 64-bit address, no structure count), so it wins over a 2.x one
+    let mut entry = (0xf0000..0x100000u64).step_by(16).map(PhysAddr::new);
+    if let Some(addr) = entry.clone().find(|&addr| &acpi::read::<[u8; 5]>(addr) == b"_SM3_") {
+        let length = acpi::read::<u8>(addr + 6u64) as u64;
+        if !acpi::checksum_ok(addr, length) {
+            return Err(SmbiosError::BadChecksum(addr));
+        }
+        let table = PhysAddr::new(acpi::read::<u64>(addr + 0x10u64));
+        let size = acpi::read::<u32>(addr + 0xcu64) as usize;
+        return Ok(Smbios { major: acpi::read(addr + 7u64), minor: acpi::read(addr + 8u64), table: map(table, size) });
+    }
+    let addr = entry.find(|&addr| &acpi::read::<[u8; 4]>(addr) == b"_SM_").ok_or(SmbiosError::NoEntryPoint)?;
+    let length = acpi::read::<u8>(addr + 5u64) as u64;
+    if !acpi::checksum_ok(addr, length) {
+        return Err(SmbiosError::BadChecksum(addr));
+    }
+    let table = PhysAddr::new(acpi::read::<u32>(addr + 0x18u64) as u64);
+    let size = acpi::read::<u16>(addr + 0x16u64) as usize;
+    Ok(Smbios { major: acpi::read(addr + 6u64), minor: acpi::read(addr + 7u64), table: map(table, size) })
+}
+
+fn map(addr: PhysAddr, size: usize) -> &'static [u8] {
+    // The table is firmware data that stays put, and all of physical memory stays mapped
+    unsafe { slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), size) }
+}
+
+impl Smbios {
+    pub fn structures(&self) -> Structures<'static> {
+        Structures { table: self.table, offset: 0 }
+    }
+
+    pub fn bios(&self) -> Option<BiosInfo> {
+        self.structures().find(|s| s.kind == TYPE_BIOS).map(|s| BiosInfo {
+            vendor: s.string(0x4).unwrap_or(""),
+            version: s.string(0x5).unwrap_or(""),
+            release_date: s.string(0x8).unwrap_or(""),
+        })
+    }
+
+    pub fn system(&self) -> Option<SystemInfo> {
+        self.structures().find(|s| s.kind == TYPE_SYSTEM).map(|s| SystemInfo {
+            manufacturer: s.string(0x4).unwrap_or(""),
+            product: s.string(0x5).unwrap_or(""),
+            version: s.string(0x6).unwrap_or(""),
+        })
+    }
+
+    // The populated memory slots
+    pub fn memory_devices(&self) -> impl Iterator<Item = MemoryDevice> {
+        self.structures().filter(|s| s.kind == TYPE_MEMORY_DEVICE).filter_map(memory_device)
+    }
+}
+
+// One structure of the table
+#[derive(Debug, Clone, Copy)]
+pub struct Structure<'a> {
+    pub kind: u8,
+    pub handle: u16,
+    // The formatted area, header included, so field offsets match the specification
+    fields: &'a [u8],
+    // The string set, without the final NUL
+    strings: &'a [u8],
+}
+
+impl<'a> Structure<'a> {
+    pub fn byte(&self, offset: usize) -> Option<u8> {
+        self.fields.get(offset).copied()
+    }
+
+    pub fn word(&self, offset: usize) -> Option<u16> {
+        Some(u16::from_le_bytes([self.byte(offset)?, self.byte(offset + 1)?]))
+    }
+
+    pub fn dword(&self, offset: usize) -> Option<u32> {
+        Some(self.word(offset)? as u32 | (self.word(offset + 2)? as u32) << 16)
+    }
+
+    // The string the byte at `offset` refers to, trimmed (firmware likes to pad them with spaces)
+    pub fn string(&self, offset: usize) -> Option<&'a str> {
+        let index = self.byte(offset)? as usize;
+        if index == 0 {
+            return None;
+        }
+        let string = self.strings.split(|&b| b == 0).nth(index - 1)?;
+        core::str::from_utf8(string).ok().map(str::trim)
+    }
+}
+
+pub struct Structures<'a> {
+    table: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Structures<'a> {
+    type Item = Structure<'a>;
+
+    // Stops at the end-of-table structure, or at the first one that doesn't fit in the table
+    fn next(&mut self) -> Option<Structure<'a>> {
+        let rest = self.table.get(self.offset..)?;
+        let length = *rest.get(1)? as usize;
+        if length < 4 || rest.len() < length || rest[0] == TYPE_END {
+            return None;
+        }
+        // The string set ends with two NULs (just the two if the structure has no strings)
+        let strings_len = rest[length..].windows(2).position(|pair| pair == [0, 0])?;
+        self.offset += length + strings_len + 2;
+        Some(Structure {
+            kind: rest[0],
+            handle: u16::from_le_bytes([rest[2], rest[3]]),
+            fields: &rest[..length],
+            strings: &rest[length..length + strings_len],
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BiosInfo {
+    pub vendor: &'static str,
+    pub version: &'static str,
+    pub release_date: &'static str,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SystemInfo {
+    pub manufacturer: &'static str,
+    pub product: &'static str,
+    pub version: &'static str,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryDevice {
+    // Where the device sits, e.g. "DIMM 0" or "ChannelA-DIMM1"
+    pub locator: &'static str,
+    // None if the firmware doesn't know
+    pub size_bytes: Option<u64>,
+    pub memory_type: &'static str,
+    // In MT/s; 0 if unknown
+    pub speed: u16,
+    pub manufacturer: &'static str,
+    pub part_number: &'static str,
+}
+
+// None for an empty slot
+fn memory_device(s: Structure<'static>) -> Option<MemoryDevice> {
+    const KIB: u64 = 1024;
+    const MIB: u64 = 1024 * 1024;
+    let size_bytes = match s.word(0xc)? {
+        0 => return None,
+        0xffff => None,
+        // Too big for the 15-bit field: the extended size (in MiB) has it
+        0x7fff => s.dword(0x1c).map(|mib| (mib & 0x7fff_ffff) as u64 * MIB),
+        size if size & 0x8000 != 0 => Some((size & 0x7fff) as u64 * KIB),
+        size => Some(size as u64 * MIB),
+    };
+    Some(MemoryDevice {
+        locator: s.string(0x10).unwrap_or(""),
+        size_bytes,
+        memory_type: memory_type_name(s.byte(0x12).unwrap_or(0)),
+        speed: s.word(0x15).unwrap_or(0),
+        manufacturer: s.string(0x17).unwrap_or(""),
+        part_number: s.string(0x1a).unwrap_or(""),
+    })
+}
+
+fn memory_type_name(memory_type: u8) -> &'static str {
+    match memory_type {
+        0x07 => "RAM",
+        0x0f => "SDRAM",
+        0x12 => "DDR",
+        0x13 => "DDR2",
+        0x18 => "DDR3",
+        0x1a => "DDR4",
+        0x1b => "LPDDR",
+        0x1c => "LPDDR2",
+        0x1d => "LPDDR3",
+        0x1e => "LPDDR4",
+        0x22 => "DDR5",
+        0x23 => "LPDDR5",
+        _ => "Other",
+    }
+}
+
+// What dmidecode would print for the types we decode
+impl fmt::Display for Smbios {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "SMBIOS {}.{}", self.major, self.minor)?;
+        if let Some(bios) = self.bios() {
+            writeln!(f, "BIOS: {} {} ({})", bios.vendor, bios.version, bios.release_date)?;
+        }
+        if let Some(system) = self.system() {
+            writeln!(f, "System: {} {} {}", system.manufacturer, system.product, system.version)?;
+        }
+        for device in self.memory_devices() {
+            write!(f, "Memory device {}: ", device.locator)?;
+            match device.size_bytes {
+                Some(size) => write!(f, "{}", crate::units::fmt_bytes(size))?,
+                None => write!(f, "unknown size")?,
+            }
+            write!(f, " {}", device.memory_type)?;
+            if device.speed != 0 {
+                write!(f, " @ {} MT/s", device.speed)?;
+            }
+            writeln!(f, " {} {}", device.manufacturer, device.part_number)?;
+        }
+        Ok(())
+    }
+}
+
+// *********
+// * TESTS *
+// *********
+// A system structure with two strings, an 8 GiB memory device without strings, an empty slot and the end marker
+#[cfg(test)]
+static TEST_TABLE: [u8; 74] = [
+    TYPE_SYSTEM, 8, 0x01, 0x00, 1, 2, 0, 0,
+    b'Q', b'E', b'M', b'U', 0, b'S', b't', b'a', b'n', b'd', b'a', b'r', b'd', b' ', b'P', b'C', b' ', 0, 0,
+    TYPE_MEMORY_DEVICE, 0x17, 0x00, 0x11, 0, 0, 0, 0, 0, 0, 0, 0, 0x00, 0x20, 0, 0, 0, 0, 0x1a, 0, 0, 0x40, 0x0b,
+    0, 0,
+    TYPE_MEMORY_DEVICE, 0x0e, 0x01, 0x11, 0, 0, 0, 0, 0, 0, 0, 0, 0x00, 0x00,
+    0, 0,
+    TYPE_END, 4, 0xff, 0xff, 0, 0,
+];
+
+#[test_case]
+fn test_structures() {
+    let mut structures = Structures { table: &TEST_TABLE, offset: 0 };
+    let system = structures.next().unwrap();
+    assert_eq!((system.kind, system.handle), (TYPE_SYSTEM, 1));
+    assert_eq!(system.string(4), Some("QEMU"));
+    // Trimmed
+    assert_eq!(system.string(5), Some("Standard PC"));
+    assert_eq!(system.string(6), None);
+    let memory = structures.next().unwrap();
+    assert_eq!((memory.kind, memory.handle), (TYPE_MEMORY_DEVICE, 0x1100));
+    assert_eq!(memory.string(0x10), None);
+    assert_eq!(structures.next().unwrap().handle, 0x1101);
+    // The end marker isn't returned
+    assert!(structures.next().is_none());
+}
+
+#[test_case]
+fn test_memory_devices() {
+    let smbios = Smbios { major: 2, minor: 8, table: &TEST_TABLE };
+    let mut devices = smbios.memory_devices();
+    let device = devices.next().unwrap();
+    assert_eq!(device.size_bytes, Some(8 << 30));
+    assert_eq!(device.memory_type, "DDR4");
+    assert_eq!(device.speed, 2880);
+    // The empty slot is skipped
+    assert!(devices.next().is_none());
+}
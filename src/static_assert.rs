@@ -0,0 +1,33 @@
+/* Compile-time assertions. Several of our types are read directly by hardware (VGA cells, the TSS, IDT entries,
+ * page tables), so their size and alignment are part of an ABI we don't control. Asserting them at compile time
+ * turns a refactor that changes the layout into a build failure instead of corrupted hardware state at runtime.
+ *
+ * The trick (borrowed from the static_assertions crate): the array length `0 - !cond as usize` underflows when
+ * `cond` is false, which is a hard compile error.
+ */
+
+// static_assert!(condition) or static_assert!(condition, "explanation")
+#[macro_export]
+macro_rules! static_assert {
+  ($cond:expr $(,)?) => {
+    const _: [(); 0 - !{ const ASSERT: bool = $cond; ASSERT } as usize] = [];
+  };
+  // The message isn't printed by the compiler, but documents the assertion at the call site
+  ($cond:expr, $msg:literal $(,)?) => {
+    const _: [(); 0 - !{ const ASSERT: bool = $cond; ASSERT } as usize] = [];
+  };
+}
+
+// static_assert_layout!(Type, size, align): asserts both the size and the alignment of a type
+#[macro_export]
+macro_rules! static_assert_layout {
+  ($ty:ty, $size:expr, $align:expr $(,)?) => {
+    $crate::static_assert!(core::mem::size_of::<$ty>() == $size);
+    $crate::static_assert!(core::mem::align_of::<$ty>() == $align);
+  };
+}
+
+// Layouts defined by the x86_64 architecture for types we hand to the CPU
+static_assert!(core::mem::size_of::<x86_64::structures::tss::TaskStateSegment>() == 104);
+static_assert!(core::mem::size_of::<x86_64::structures::idt::Entry<x86_64::structures::idt::HandlerFunc>>() == 16);
+static_assert_layout!(x86_64::structures::paging::PageTable, 4096, 4096);
@@ -0,0 +1,16 @@
+/* The status bar along the top of every console: uptime, timer ticks, heap usage and the lock keys that are on. The
+ * timer handler schedules `update` once a second. It runs as deferred work because reading the heap usage takes the
+ * allocator's lock, which an interrupt handler must never wait for.
+ */
+use crate::{allocator, keyboard, time, vga_buffer};
+use crate::units::fmt_bytes;
+
+pub fn update(_: usize) {
+    let size = allocator::heap_size();
+    let used = size.saturating_sub(allocator::stats().free_bytes);
+    let locks = keyboard::locks();
+    vga_buffer::draw_status_bar(format_args!(" up {}s | {} ticks | heap {} of {} |{}{}{}",
+        time::uptime_millis() / 1000, time::ticks(), fmt_bytes(used as u64), fmt_bytes(size as u64),
+        if locks.caps { " CAPS" } else { "" }, if locks.num { " NUM" } else { "" },
+        if locks.scroll { " SCROLL" } else { "" }));
+}
@@ -0,0 +1,334 @@
+/* RAII guards for the two things kernel code most often needs to turn off for a while.
+ *
+ * `InterruptGuard` disables interrupts until it's dropped. Guards nest: a depth counter tracks how many are alive,
+ * and interrupts are only re-enabled when the last one goes away, and only if they were enabled when the first one
+ * was created. So a function that takes a guard can safely be called from code that already holds one (or from an
+ * interrupt handler, where interrupts are off to begin with), which a plain disable/enable pair gets wrong.
+ *
+ * `PreemptGuard` marks a section that mustn't be preempted (e.g. while using per-CPU data) without masking
+ * interrupts. There's no scheduler yet; when there is, its tick must check `preemptible()` before switching tasks.
+ *
+ * `ByteQueue` hands bytes from an interrupt handler to code outside it without a lock, and `ByteStream` lets an async
+ * task wait for them.
+ *
+ * Both counters are global, which is only correct while a single CPU runs; they become per-CPU with SMP. Guards are
+ * !Send for the same reason: they must be dropped on the CPU that created them.
+ */
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use futures_util::stream::Stream;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+static INTERRUPT_DEPTH: AtomicUsize = AtomicUsize::new(0);
+// Whether interrupts were enabled before the outermost InterruptGuard disabled them
+static INTERRUPTS_WERE_ENABLED: AtomicBool = AtomicBool::new(false);
+static PREEMPT_DEPTH: AtomicUsize = AtomicUsize::new(0);
+// A power of two, so ByteQueue's indices can wrap freely
+const BYTE_QUEUE_SIZE: usize = 128;
+
+// Interrupts stay disabled while this is alive
+pub struct InterruptGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl InterruptGuard {
+    pub fn new() -> InterruptGuard {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        // Interrupts are off now, so nothing can run between the check and the store
+        if INTERRUPT_DEPTH.fetch_add(1, Ordering::SeqCst) == 0 {
+            INTERRUPTS_WERE_ENABLED.store(enabled, Ordering::SeqCst);
+        }
+        InterruptGuard { _not_send: PhantomData }
+    }
+}
+
+impl Default for InterruptGuard {
+    fn default() -> InterruptGuard {
+        InterruptGuard::new()
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if INTERRUPT_DEPTH.fetch_sub(1, Ordering::SeqCst) == 1 && INTERRUPTS_WERE_ENABLED.load(Ordering::SeqCst) {
+            interrupts::enable();
+        }
+    }
+}
+
+// Number of InterruptGuards alive
+pub fn interrupt_depth() -> usize {
+    INTERRUPT_DEPTH.load(Ordering::SeqCst)
+}
+
+// The current task can't be preempted while this is alive; interrupts still arrive
+pub struct PreemptGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl PreemptGuard {
+    pub fn new() -> PreemptGuard {
+        PREEMPT_DEPTH.fetch_add(1, Ordering::SeqCst);
+        PreemptGuard { _not_send: PhantomData }
+    }
+}
+
+impl Default for PreemptGuard {
+    fn default() -> PreemptGuard {
+        PreemptGuard::new()
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        PREEMPT_DEPTH.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Number of PreemptGuards alive
+pub fn preempt_depth() -> usize {
+    PREEMPT_DEPTH.load(Ordering::SeqCst)
+}
+
+// Whether a scheduler may switch away from the current task right now
+pub fn preemptible() -> bool {
+    preempt_depth() == 0 && interrupt_depth() == 0 && interrupts::are_enabled()
+}
+
+/* A single-producer, single-consumer ring of bytes, for an interrupt handler (the producer) to hand what it reads,
+ * like scancodes or bytes received on a serial port, to code outside the interrupt (the consumer). Neither side needs
+ * a lock, just ordered head and tail indices. Bytes that arrive while it's full are dropped and counted.
+ */
+pub struct ByteQueue {
+    slots: UnsafeCell<[u8; BYTE_QUEUE_SIZE]>,
+    // Next slot to pop; only the consumer writes it
+    head: AtomicUsize,
+    // Next slot to push; only the producer writes it
+    tail: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+// Safe as long as there's one producer and one consumer at a time, which push and pop document
+unsafe impl Sync for ByteQueue {}
+
+impl ByteQueue {
+    pub const fn new() -> ByteQueue {
+        ByteQueue {
+            slots: UnsafeCell::new([0; BYTE_QUEUE_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    // Only one producer may push at a time. Returns false (and counts the byte as dropped) if the queue is full.
+    pub fn push(&self, byte: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == BYTE_QUEUE_SIZE {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        // The consumer doesn't read this slot until the tail store below publishes it
+        unsafe { (*self.slots.get())[tail % BYTE_QUEUE_SIZE] = byte };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    // Only one consumer may pop at a time
+    pub fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = unsafe { (*self.slots.get())[head % BYTE_QUEUE_SIZE] };
+        // Hands the slot back to the producer
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+
+    // Number of bytes dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for ByteQueue {
+    fn default() -> ByteQueue {
+        ByteQueue::new()
+    }
+}
+
+/* The task waiting for a ByteQueue to receive something, if one is. The consumer registers it through `poll` when
+ * there's nothing to pop; the producer's side calls `wake` after pushing, from deferred work rather than the interrupt
+ * handler itself, since waking runs the executor's code.
+ */
+pub struct QueueWaker {
+    waker: Mutex<Option<Waker>>,
+}
+
+impl QueueWaker {
+    pub const fn new() -> QueueWaker {
+        QueueWaker { waker: Mutex::new(None) }
+    }
+
+    // The next byte `pop` takes, or Pending with the task registered to be woken when there may be one
+    pub fn poll(&self, pop: impl Fn() -> Option<u8>, cx: &mut Context) -> Poll<u8> {
+        if let Some(byte) = pop() {
+            return Poll::Ready(byte);
+        }
+        {
+            let _guard = InterruptGuard::new();
+            *self.waker.lock() = Some(cx.waker().clone());
+        }
+        // A byte may have arrived before the waker was registered
+        match pop() {
+            Some(byte) => Poll::Ready(byte),
+            None => Poll::Pending,
+        }
+    }
+
+    pub fn wake(&self) {
+        if let Some(waker) = self.take() {
+            waker.wake();
+        }
+    }
+
+    // Forgets the waiting task without waking it
+    pub fn clear(&self) {
+        self.take();
+    }
+
+    fn take(&self) -> Option<Waker> {
+        let _guard = InterruptGuard::new();
+        self.waker.lock().take()
+    }
+}
+
+impl Default for QueueWaker {
+    fn default() -> QueueWaker {
+        QueueWaker::new()
+    }
+}
+
+/* The bytes `pop` takes, as a Stream that never ends, for an async task woken through `waker`. `pop` is usually a
+ * ByteQueue's own pop, but a driver can look past its queue too (serial::try_read_byte polls the UART while the
+ * receive interrupt is off).
+ */
+pub struct ByteStream {
+    pop: fn() -> Option<u8>,
+    waker: &'static QueueWaker,
+}
+
+impl ByteStream {
+    pub fn new(pop: fn() -> Option<u8>, waker: &'static QueueWaker) -> ByteStream {
+        ByteStream { pop, waker }
+    }
+}
+
+impl Stream for ByteStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        self.waker.poll(self.pop, cx).map(Some)
+    }
+}
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_interrupt_guards_nest() {
+    assert!(interrupts::are_enabled());
+    let outer = InterruptGuard::new();
+    {
+        let _inner = InterruptGuard::new();
+        assert_eq!(interrupt_depth(), 2);
+    }
+    // The inner guard must not re-enable interrupts under the outer one
+    assert!(!interrupts::are_enabled());
+    drop(outer);
+    assert!(interrupts::are_enabled());
+    assert_eq!(interrupt_depth(), 0);
+}
+
+#[test_case]
+fn test_interrupt_guard_keeps_interrupts_disabled() {
+    interrupts::disable();
+    drop(InterruptGuard::new());
+    assert!(!interrupts::are_enabled());
+    interrupts::enable();
+}
+
+#[test_case]
+fn test_preempt_guard() {
+    assert!(preemptible());
+    let guard = PreemptGuard::new();
+    assert!(!preemptible());
+    // Preemption is off, interrupts aren't
+    assert!(interrupts::are_enabled());
+    drop(guard);
+    assert!(preemptible());
+}
+
+#[test_case]
+fn test_byte_queue() {
+    let queue = ByteQueue::new();
+    assert_eq!(queue.pop(), None);
+    for byte in 0..BYTE_QUEUE_SIZE {
+        assert!(queue.push(byte as u8));
+    }
+    // Full: dropped, not overwritten
+    assert!(!queue.push(0xff));
+    assert_eq!(queue.dropped(), 1);
+    assert_eq!(queue.pop(), Some(0));
+    // Wraps around into the freed slot
+    assert!(queue.push(0xaa));
+    for byte in 1..BYTE_QUEUE_SIZE {
+        assert_eq!(queue.pop(), Some(byte as u8));
+    }
+    assert_eq!(queue.pop(), Some(0xaa));
+    assert!(queue.is_empty());
+}
+
+#[test_case]
+fn test_byte_stream_wakes_its_task() {
+    use core::future::Future;
+    use core::task::{RawWaker, RawWakerVTable};
+    use futures_util::stream::StreamExt;
+    static QUEUE: ByteQueue = ByteQueue::new();
+    static WAKER: QueueWaker = QueueWaker::new();
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, release);
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    fn wake(_: *const ()) {
+        WAKES.fetch_add(1, Ordering::SeqCst);
+    }
+    fn release(_: *const ()) {}
+    fn pop() -> Option<u8> {
+        QUEUE.pop()
+    }
+    let waker = unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) };
+    let mut cx = Context::from_waker(&waker);
+
+    let mut stream = ByteStream::new(pop, &WAKER);
+    assert_eq!(Pin::new(&mut stream.next()).poll(&mut cx), Poll::Pending);
+    // What the producer's side does
+    QUEUE.push(7);
+    WAKER.wake();
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+    assert_eq!(Pin::new(&mut stream.next()).poll(&mut cx), Poll::Ready(Some(7)));
+    // Nobody is waiting any more
+    WAKER.wake();
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,136 @@
+/* Timekeeping based on the Programmable Interval Timer (PIT). Channel 0 of the PIT divides its 1.193182 MHz input
+ * clock by a 16-bit divisor and raises IRQ 0 every time the counter wraps. At power on the divisor is 65536, which
+ * gives the awkward ~18.2 Hz the BIOS used; `init` reprograms it to DEFAULT_FREQUENCY_HZ so a tick is a round 10 ms.
+ *
+ * The timer interrupt only bumps an atomic counter, so reading the time never takes a lock and is safe from any
+ * context, including other interrupt handlers.
+ * https://wiki.osdev.org/Programmable_Interval_Timer
+ */
+pub mod hpet; // High Precision Event Timer: a nanosecond clock and one-shot timers, when the machine has one
+pub mod tsc; // Time Stamp Counter, calibrated against the PIT: the cheapest high-resolution clock
+pub mod speaker; // PC speaker beeps on PIT channel 2
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use crate::sync::InterruptGuard;
+
+pub const PIT_BASE_FREQUENCY_HZ: u64 = 1_193_182;
+// The rate the PIT runs at until it's reprogrammed: 1193182 Hz / 65536, i.e. ~18.2065 Hz
+pub const PIT_DEFAULT_FREQUENCY_MILLIHZ: u64 = 18_207;
+pub const DEFAULT_FREQUENCY_HZ: u32 = 100;
+
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+// Channel 0, low then high byte of the divisor, mode 2 (rate generator), binary counting
+const PIT_CHANNEL0_RATE_GENERATOR: u8 = 0b00_11_010_0;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static FREQUENCY_MILLIHZ: AtomicU64 = AtomicU64::new(PIT_DEFAULT_FREQUENCY_MILLIHZ);
+// Tick count and uptime at the last change of the tick rate; later ticks are counted at the new rate
+static BASE_TICKS: AtomicU64 = AtomicU64::new(0);
+static BASE_MILLIS: AtomicU64 = AtomicU64::new(0);
+
+// Programs the PIT to DEFAULT_FREQUENCY_HZ and calibrates the TSC
+pub fn init() {
+    set_frequency(DEFAULT_FREQUENCY_HZ);
+    tsc::calibrate();
+}
+
+/* Programs the PIT to tick as close to `hz` times per second as its divisor allows (19 Hz to 1.19 MHz; anything
+ * outside that is clamped) and returns the actual rate in millihertz.
+ */
+pub fn set_frequency(hz: u32) -> u64 {
+    let divisor = divisor_for(hz);
+    let millihz = frequency_millihz_for(divisor);
+    let _guard = InterruptGuard::new();
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut channel0: Port<u8> = Port::new(PIT_CHANNEL0);
+    // Unsafe because the PIT's ports have side effects; writing a complete command and divisor is what they expect
+    unsafe {
+        command.write(PIT_CHANNEL0_RATE_GENERATOR);
+        // A divisor of 65536 is written as 0
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+    set_tick_frequency(millihz);
+    millihz
+}
+
+/* Records that ticks arrive at `millihz` from now on (the PIT was reprogrammed, or another timer took over the tick).
+ * Uptime so far is kept, so it doesn't jump. Call with interrupts disabled.
+ */
+pub(crate) fn set_tick_frequency(millihz: u64) {
+    let ticks = ticks();
+    BASE_MILLIS.store(ticks_to_millis(ticks), Ordering::Relaxed);
+    BASE_TICKS.store(ticks, Ordering::Relaxed);
+    FREQUENCY_MILLIHZ.store(millihz, Ordering::Relaxed);
+}
+
+// The rate the timer interrupt currently fires at, in millihertz
+pub fn frequency_millihz() -> u64 {
+    FREQUENCY_MILLIHZ.load(Ordering::Relaxed)
+}
+
+// Number of timer interrupts handled since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// Milliseconds since the timer started ticking, with the resolution of one tick
+pub fn uptime_millis() -> u64 {
+    ticks_to_millis(ticks())
+}
+
+/* Nanoseconds since the TSC was calibrated, with sub-microsecond resolution; this is the clock for benchmarks and
+ * profiling. Falls back to monotonic_nanos() if calibration failed.
+ */
+pub fn nanos() -> u64 {
+    tsc::nanos().unwrap_or_else(monotonic_nanos)
+}
+
+// Nanoseconds since boot from the best clock available: the HPET if init found one, the PIT tick otherwise
+pub fn monotonic_nanos() -> u64 {
+    hpet::nanos().unwrap_or_else(|| uptime_millis() * 1_000_000)
+}
+
+// Uptime in milliseconds at tick number `ticks`, which mustn't be from before the last change of the tick rate
+pub fn ticks_to_millis(ticks: u64) -> u64 {
+    let since_base = ticks.saturating_sub(BASE_TICKS.load(Ordering::Relaxed));
+    BASE_MILLIS.load(Ordering::Relaxed) + since_base * 1_000_000 / frequency_millihz()
+}
+
+// Called by the timer interrupt handler; returns the new tick count
+pub(crate) fn tick() -> u64 {
+    TICKS.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+fn divisor_for(hz: u32) -> u32 {
+    let hz = hz.max(1) as u64;
+    // Round to the nearest divisor
+    ((PIT_BASE_FREQUENCY_HZ + hz / 2) / hz).max(1).min(65536) as u32
+}
+
+fn frequency_millihz_for(divisor: u32) -> u64 {
+    (PIT_BASE_FREQUENCY_HZ * 1000 + divisor as u64 / 2) / divisor as u64
+}
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_divisor_rounds_and_clamps() {
+    assert_eq!(divisor_for(100), 11932);
+    assert_eq!(divisor_for(1000), 1193);
+    assert_eq!(divisor_for(1), 65536);
+    assert_eq!(divisor_for(u32::MAX), 1);
+    assert_eq!(frequency_millihz_for(65536), PIT_DEFAULT_FREQUENCY_MILLIHZ);
+    assert_eq!(frequency_millihz_for(11932), 99_998);
+}
+
+#[test_case]
+fn test_ticks_advance() {
+    let start = ticks();
+    while ticks() < start + 2 {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,199 @@
+/* High Precision Event Timer. The HPET has a free-running main counter that ticks at a fixed rate (at least 10 MHz,
+ * the period in femtoseconds is in its capabilities register), which gives us a monotonic clock with far better
+ * resolution than the PIT tick, plus a handful of comparators that raise an interrupt when the counter reaches them.
+ *
+ * Its registers are found through the ACPI "HPET" table. We never use legacy replacement routing (which would take
+ * over IRQ 0 and 8 from the PIT and RTC): comparator 0 is routed through the IOAPIC to a dynamically allocated
+ * vector instead, so one-shot timers need `apic::init` to have succeeded. The clock itself works without it.
+ * https://wiki.osdev.org/HPET
+ */
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, Once};
+use x86_64::PhysAddr;
+use crate::acpi::{self, AcpiError};
+use crate::acpi::madt::{Polarity, TriggerMode};
+use crate::interrupts::{apic, ioapic};
+use crate::interrupts::ioapic::Redirection;
+use crate::interrupts::vectors::{self, VectorError, VectorGuard, VectorHandler};
+use crate::memory::{map_mmio, KmapError, Mmio};
+use crate::sync::InterruptGuard;
+
+const REG_CAPABILITIES: u64 = 0x00;
+const REG_CONFIG: u64 = 0x10;
+const REG_MAIN_COUNTER: u64 = 0xf0;
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CAPABILITIES_COUNTER_64BIT: u64 = 1 << 13;
+
+// Per-comparator registers, 0x20 bytes apart
+const fn timer_config(timer: u64) -> u64 { 0x100 + 0x20 * timer }
+const fn timer_comparator(timer: u64) -> u64 { 0x108 + 0x20 * timer }
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+// The comparator we use for one-shot timers
+const ONESHOT_TIMER: u64 = 0;
+
+#[derive(Debug)]
+pub enum HpetError {
+    Acpi(AcpiError),
+    Map(KmapError),
+    // init() hasn't run or didn't find an HPET
+    NotInitialized,
+    // One-shot timers are delivered through the IOAPIC
+    ApicDisabled,
+    // The comparator can't be routed to any IOAPIC pin we're allowed to use
+    NoRoute,
+    // Another one-shot timer is still armed
+    Busy,
+    Vector(VectorError),
+}
+
+struct Hpet {
+    // Only locked with interrupts disabled, so the clock can be read from interrupt handlers too
+    regs: Mutex<Mmio<u64>>,
+    period_fs: u64,
+    counter_64bit: bool,
+}
+
+static HPET: Once<Hpet> = Once::new();
+static ONESHOT_ARMED: AtomicBool = AtomicBool::new(false);
+
+/* Finds the HPET, resets its main counter to zero and starts it. Needs memory::init. Calling it again after it
+ * succeeded does nothing.
+ */
+pub fn init() -> Result<(), HpetError> {
+    if HPET.r#try().is_some() {
+        return Ok(());
+    }
+    let table = acpi::find_table(b"HPET").map_err(HpetError::Acpi)?;
+    // The base address is a Generic Address Structure right after the header and the 4-byte hardware ID; its 64-bit
+    // address field is at offset 4
+    let base: u64 = acpi::read(table + acpi::HEADER_SIZE + 8u64);
+    let mut regs = map_mmio::<u64>(PhysAddr::new(base), 0x400).map_err(HpetError::Map)?;
+    let capabilities = regs.read(REG_CAPABILITIES);
+    // Stop the counter while resetting it, and make sure legacy replacement routing is off
+    regs.write(REG_CONFIG, 0);
+    regs.write(REG_MAIN_COUNTER, 0);
+    regs.write(REG_CONFIG, CONFIG_ENABLE);
+    HPET.call_once(|| Hpet {
+        regs: Mutex::new(regs),
+        period_fs: capabilities >> 32,
+        counter_64bit: capabilities & CAPABILITIES_COUNTER_64BIT != 0,
+    });
+    Ok(())
+}
+
+pub fn is_available() -> bool {
+    HPET.r#try().is_some()
+}
+
+// The counter's rate, or None without an HPET
+pub fn frequency_hz() -> Option<u64> {
+    HPET.r#try().map(|hpet| 1_000_000_000_000_000 / hpet.period_fs)
+}
+
+// Nanoseconds since init, or None without an HPET
+pub fn nanos() -> Option<u64> {
+    let hpet = HPET.r#try()?;
+    Some(hpet.counter_to_nanos(hpet.counter()))
+}
+
+impl Hpet {
+    fn counter(&self) -> u64 {
+        let counter = {
+            let _guard = InterruptGuard::new();
+            let regs = self.regs.lock();
+            regs.read(REG_MAIN_COUNTER)
+        };
+        // A 32-bit counter wraps after a few minutes; callers only get monotonic time from 64-bit ones
+        if self.counter_64bit { counter } else { counter & 0xffff_ffff }
+    }
+
+    fn counter_to_nanos(&self, counter: u64) -> u64 {
+        (counter as u128 * self.period_fs as u128 / FEMTOS_PER_NANO) as u64
+    }
+
+    fn nanos_to_counter(&self, nanos: u64) -> u64 {
+        (nanos as u128 * FEMTOS_PER_NANO / self.period_fs as u128) as u64
+    }
+}
+
+/* An armed one-shot timer. Its handler runs once, `delay_nanos` after it was started; dropping the OneShot
+ * disarms the comparator and frees the vector, whether or not it has fired yet.
+ */
+#[derive(Debug)]
+pub struct OneShot {
+    guard: VectorGuard,
+    gsi: u32,
+}
+
+impl OneShot {
+    pub fn vector(&self) -> u8 {
+        self.guard.vector()
+    }
+}
+
+impl Drop for OneShot {
+    fn drop(&mut self) {
+        if let Some(hpet) = HPET.r#try() {
+            let _guard = InterruptGuard::new();
+            hpet.regs.lock().update(timer_config(ONESHOT_TIMER), |config| config & !TIMER_INTERRUPT_ENABLE);
+        }
+        ioapic::with_io_apic_for(self.gsi, |io_apic| io_apic.set_masked(self.gsi, true));
+        ONESHOT_ARMED.store(false, Ordering::SeqCst);
+    }
+}
+
+// Runs `handler` (in interrupt context) once, after `delay_nanos` nanoseconds
+pub fn start_oneshot(delay_nanos: u64, handler: VectorHandler) -> Result<OneShot, HpetError> {
+    let hpet = HPET.r#try().ok_or(HpetError::NotInitialized)?;
+    if !apic::is_enabled() {
+        return Err(HpetError::ApicDisabled);
+    }
+    let destination = apic::local_apic_id().ok_or(HpetError::ApicDisabled)? as u8;
+    if ONESHOT_ARMED.swap(true, Ordering::SeqCst) {
+        return Err(HpetError::Busy);
+    }
+    let armed = || -> Result<OneShot, HpetError> {
+        let gsi = route_for(hpet).ok_or(HpetError::NoRoute)?;
+        let guard = vectors::allocate_vector(handler).map_err(HpetError::Vector)?;
+        ioapic::with_io_apic_for(gsi, |io_apic| io_apic.set_redirection(gsi, Redirection {
+            vector: guard.vector(),
+            polarity: Polarity::ActiveHigh,
+            trigger: TriggerMode::Edge,
+            masked: false,
+            destination,
+        }));
+        {
+            let _guard = InterruptGuard::new();
+            let mut regs = hpet.regs.lock();
+            regs.update(timer_config(ONESHOT_TIMER), |config| {
+                let config = config & !(TIMER_ROUTE_MASK | TIMER_PERIODIC | TIMER_LEVEL_TRIGGERED);
+                config | (gsi as u64) << TIMER_ROUTE_SHIFT | TIMER_INTERRUPT_ENABLE
+            });
+            let deadline = regs.read(REG_MAIN_COUNTER).wrapping_add(hpet.nanos_to_counter(delay_nanos).max(1));
+            regs.write(timer_comparator(ONESHOT_TIMER), deadline);
+        }
+        Ok(OneShot { guard, gsi })
+    };
+    armed().map_err(|e| {
+        ONESHOT_ARMED.store(false, Ordering::SeqCst);
+        e
+    })
+}
+
+/* The IOAPIC pin to route the one-shot comparator to. The capability bits say which pins the comparator can drive;
+ * the ISA pins (0-15) belong to legacy devices, so only GSIs 16-31 are considered.
+ */
+fn route_for(hpet: &Hpet) -> Option<u32> {
+    let capabilities = {
+        let _guard = InterruptGuard::new();
+        let regs = hpet.regs.lock();
+        regs.read(timer_config(ONESHOT_TIMER)) >> 32
+    };
+    (16..32).find(|&gsi| capabilities & (1 << gsi) != 0 && ioapic::with_io_apic_for(gsi, |_| ()).is_some())
+}
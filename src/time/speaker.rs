@@ -0,0 +1,43 @@
+/* The PC speaker, driven by PIT channel 2 in square wave mode: the channel's divisor sets the pitch and port 0x61
+ * connects its output to the speaker. Channel 2 is otherwise only used to calibrate the TSC at boot.
+ * https://wiki.osdev.org/PC_Speaker
+ */
+use x86_64::instructions::port::Port;
+use crate::sync::InterruptGuard;
+use super::tsc::{self, PORT_B, PORT_B_GATE, PORT_B_SPEAKER};
+use super::PIT_BASE_FREQUENCY_HZ;
+
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+// Channel 2, low then high byte of the divisor, mode 3 (square wave), binary counting
+const PIT_CHANNEL2_SQUARE_WAVE: u8 = 0b10_11_011_0;
+
+/* Sounds a `hz` tone for `millis` ms, busy-waiting meanwhile, so keep it short. Timed with the TSC; returns false
+ * (without a sound) if it isn't calibrated, since the tick may not be running to time it.
+ */
+pub fn beep(hz: u32, millis: u64) -> bool {
+    let start = match tsc::nanos() {
+        Some(start) => start,
+        None => return false,
+    };
+    let divisor = (PIT_BASE_FREQUENCY_HZ / hz.max(19) as u64).min(0xffff) as u16;
+    let mut port_b: Port<u8> = Port::new(PORT_B);
+    unsafe {
+        let _guard = InterruptGuard::new();
+        Port::<u8>::new(PIT_COMMAND).write(PIT_CHANNEL2_SQUARE_WAVE);
+        let mut channel2: Port<u8> = Port::new(PIT_CHANNEL2);
+        channel2.write(divisor as u8);
+        channel2.write((divisor >> 8) as u8);
+        let value = port_b.read();
+        port_b.write(value | PORT_B_GATE | PORT_B_SPEAKER);
+    }
+    while tsc::nanos().map_or(false, |now| now - start < millis * 1_000_000) {
+        core::sync::atomic::spin_loop_hint();
+    }
+    unsafe {
+        let _guard = InterruptGuard::new();
+        let value = port_b.read();
+        port_b.write(value & !(PORT_B_GATE | PORT_B_SPEAKER));
+    }
+    true
+}
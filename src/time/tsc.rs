@@ -0,0 +1,133 @@
+/* The Time Stamp Counter. Every CPU since the Pentium counts cycles in the TSC, and reading it with rdtsc takes a few
+ * nanoseconds, so it's the clock to use for benchmarks and profiling. Its rate isn't reported anywhere reliable,
+ * though, so we measure it at boot: gate the PIT's channel 2 for a known number of PIT cycles and count how many TSC
+ * cycles pass meanwhile. Channel 2 is polled through port 0x61 and never raises an interrupt, so this doesn't
+ * disturb the timer tick on channel 0.
+ *
+ * On older CPUs the TSC rate follows frequency scaling; only an "invariant" TSC (CPUID 0x80000007, EDX bit 8) keeps
+ * counting at the same rate in every power state. We use it either way, but report which one we have.
+ * https://wiki.osdev.org/TSC
+ */
+use core::arch::x86_64::{__cpuid, __rdtscp, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use crate::sync::InterruptGuard;
+use super::PIT_BASE_FREQUENCY_HZ;
+
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+// Channel 2, low then high byte of the count, mode 0 (interrupt on terminal count), binary counting
+const PIT_CHANNEL2_ONESHOT: u8 = 0b10_11_000_0;
+// Port 0x61: bit 0 gates channel 2, bit 1 connects it to the speaker, bit 5 reads its output
+pub(super) const PORT_B: u16 = 0x61;
+pub(super) const PORT_B_GATE: u8 = 1 << 0;
+pub(super) const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUTPUT: u8 = 1 << 5;
+
+// Each calibration run measures 10 ms; the fastest of a few runs is the least disturbed (e.g. by an SMI)
+const CALIBRATION_MILLIS: u64 = 10;
+const CALIBRATION_RUNS: usize = 3;
+
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+static HAS_RDTSCP: AtomicBool = AtomicBool::new(false);
+
+/* Measures the TSC rate and takes the current TSC as time zero. Returns the rate in kHz, or None if the measurement
+ * came out as zero (e.g. on an emulator without a working PIT channel 2).
+ */
+pub fn calibrate() -> Option<u64> {
+    HAS_RDTSCP.store(unsafe { __cpuid(0x8000_0001) }.edx & (1 << 27) != 0, Ordering::Relaxed);
+    let cycles = (0..CALIBRATION_RUNS).map(|_| measure()).filter(|&cycles| cycles != 0).min().unwrap_or(0);
+    let khz = cycles / CALIBRATION_MILLIS;
+    if khz == 0 {
+        return None;
+    }
+    BOOT_TSC.store(read(), Ordering::Relaxed);
+    TSC_KHZ.store(khz, Ordering::Relaxed);
+    Some(khz)
+}
+
+// The measured TSC rate in kHz, or None before a successful calibrate()
+pub fn khz() -> Option<u64> {
+    match TSC_KHZ.load(Ordering::Relaxed) {
+        0 => None,
+        khz => Some(khz),
+    }
+}
+
+// Whether the TSC runs at a constant rate regardless of power state
+pub fn is_invariant() -> bool {
+    unsafe { __cpuid(0x8000_0000) }.eax >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/* Reads the TSC. rdtscp (when the CPU has it) waits for earlier instructions to finish first, so the code being
+ * timed can't leak past the read.
+ */
+pub fn read() -> u64 {
+    if HAS_RDTSCP.load(Ordering::Relaxed) {
+        let mut aux = 0u32;
+        unsafe { __rdtscp(&mut aux) }
+    } else {
+        unsafe { _rdtsc() }
+    }
+}
+
+// Nanoseconds since calibration, or None before it
+pub fn nanos() -> Option<u64> {
+    let khz = khz()?;
+    Some(cycles_to_nanos(read().wrapping_sub(BOOT_TSC.load(Ordering::Relaxed)), khz))
+}
+
+fn cycles_to_nanos(cycles: u64, khz: u64) -> u64 {
+    (cycles as u128 * 1_000_000 / khz as u128) as u64
+}
+
+// TSC cycles during one CALIBRATION_MILLIS window of PIT channel 2
+fn measure() -> u64 {
+    let count = (PIT_BASE_FREQUENCY_HZ * CALIBRATION_MILLIS / 1000) as u16;
+    let mut port_b: Port<u8> = Port::new(PORT_B);
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut channel2: Port<u8> = Port::new(PIT_CHANNEL2);
+    let _guard = InterruptGuard::new();
+    unsafe {
+        // Gate off and speaker off while loading the count
+        let saved = port_b.read();
+        port_b.write(saved & !(PORT_B_GATE | PORT_B_SPEAKER));
+        command.write(PIT_CHANNEL2_ONESHOT);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+        // Raising the gate starts the countdown; the output goes high when it reaches zero
+        port_b.write((saved & !PORT_B_SPEAKER) | PORT_B_GATE);
+        let start = _rdtsc();
+        let mut polls = 0u64;
+        while port_b.read() & PORT_B_OUTPUT == 0 {
+            polls += 1;
+            // Port reads take about a microsecond, so this is far longer than the window; give up on a dead PIT
+            if polls > 1_000_000 {
+                port_b.write(saved);
+                return 0;
+            }
+        }
+        let end = _rdtsc();
+        port_b.write(saved);
+        end.wrapping_sub(start)
+    }
+}
+
+// *********
+// * TESTS *
+// *********
+#[test_case]
+fn test_cycles_to_nanos() {
+    assert_eq!(cycles_to_nanos(2_000_000, 2_000_000), 1_000);
+    assert_eq!(cycles_to_nanos(3, 3_000_000), 1);
+    // A day at 4 GHz doesn't overflow
+    assert_eq!(cycles_to_nanos(4_000_000_000 * 86_400, 4_000_000), 86_400_000_000_000);
+}
+
+#[test_case]
+fn test_tsc_clock_advances() {
+    let start = nanos().expect("TSC not calibrated");
+    assert!(nanos().unwrap() >= start);
+    assert!(read() != read());
+}
@@ -0,0 +1,134 @@
+/* Human-readable formatting for sizes and durations, e.g. "16.0 MiB" and "1.250 s" instead of raw integers.
+ * Both helpers return small wrapper types implementing Display, so they work anywhere a format string does
+ * (including width/alignment like `{:>10}`) and never allocate.
+ */
+use core::fmt;
+use core::fmt::Write;
+
+const BYTE_UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+// Nanoseconds per unit, and its name
+const DURATION_UNITS: [(u64, &str); 4] = [(1, "ns"), (1_000, "us"), (1_000_000, "ms"), (1_000_000_000, "s")];
+
+pub fn fmt_bytes(bytes: u64) -> FmtBytes {
+    FmtBytes(bytes)
+}
+
+// `nanos` is a duration in nanoseconds
+pub fn fmt_duration(nanos: u64) -> FmtDuration {
+    FmtDuration(nanos)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FmtBytes(pub u64);
+
+impl fmt::Display for FmtBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buf = StackStr::new();
+        if self.0 < 1024 {
+            write!(buf, "{} B", self.0)?;
+        } else {
+            // One decimal of the largest unit that keeps it >= 1, rounded first: 1023.96 KiB is 1.0 MiB, not 1024.0 KiB
+            let mut unit = 1024u128;
+            let mut index = 0;
+            let mut tenths = (self.0 as u128 * 10 + unit / 2) / unit;
+            while index + 1 < BYTE_UNITS.len() && tenths >= 1024 * 10 {
+                unit *= 1024;
+                index += 1;
+                tenths = (self.0 as u128 * 10 + unit / 2) / unit;
+            }
+            write!(buf, "{}.{} {}", tenths / 10, tenths % 10, BYTE_UNITS[index])?;
+        }
+        f.pad(buf.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FmtDuration(pub u64);
+
+impl fmt::Display for FmtDuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buf = StackStr::new();
+        let nanos = self.0;
+        // Three decimals of the largest unit that fits; VGA has no µ, so microseconds are "us"
+        let mut index = match nanos {
+            0..=999 => 0,
+            1_000..=999_999 => 1,
+            1_000_000..=999_999_999 => 2,
+            _ => 3,
+        };
+        if index == 0 {
+            write!(buf, "{} ns", nanos)?;
+        } else {
+            let rounded = |index: usize| {
+                let unit = DURATION_UNITS[index].0 as u128;
+                (nanos as u128 * 1000 + unit / 2) / unit
+            };
+            let mut thousandths = rounded(index);
+            // Like for bytes: 999.9996 ms rounds to 1000.000 ms, which is shown as 1.000 s
+            while index + 1 < DURATION_UNITS.len() && thousandths >= 1000 * 1000 {
+                index += 1;
+                thousandths = rounded(index);
+            }
+            write!(buf, "{}.{:03} {}", thousandths / 1000, thousandths % 1000, DURATION_UNITS[index].1)?;
+        }
+        f.pad(buf.as_str())
+    }
+}
+
+// A fixed-size string buffer, so we can format first and then pad the result as a whole
+struct StackStr {
+    buf: [u8; 32],
+    len: usize,
+}
+
+impl StackStr {
+    fn new() -> StackStr {
+        StackStr { buf: [0; 32], len: 0 }
+    }
+
+    fn as_str(&self) -> &str {
+        // Only ever filled from &strs, so this is valid UTF-8
+        core::str::from_utf8(&self.buf[..self.len]).unwrap()
+    }
+}
+
+impl fmt::Write for StackStr {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+// *********
+// * TESTS *
+// *********
+#[cfg(test)]
+fn formats_as(value: impl fmt::Display, expected: &str) -> bool {
+    let mut buf = StackStr::new();
+    write!(buf, "{}", value).unwrap();
+    buf.as_str() == expected
+}
+
+#[test_case]
+fn test_fmt_bytes() {
+    assert!(formats_as(fmt_bytes(512), "512 B"));
+    assert!(formats_as(fmt_bytes(1536), "1.5 KiB"));
+    assert!(formats_as(fmt_bytes(16 * 1024 * 1024), "16.0 MiB"));
+    assert!(formats_as(format_args!("{:>10}", fmt_bytes(4096)), "   4.0 KiB"));
+    // Rounds up into the next unit rather than showing 1024.0 of this one
+    assert!(formats_as(fmt_bytes(1024 * 1024 - 1), "1.0 MiB"));
+}
+
+#[test_case]
+fn test_fmt_duration() {
+    assert!(formats_as(fmt_duration(999), "999 ns"));
+    assert!(formats_as(fmt_duration(1_250_000_000), "1.250 s"));
+    assert!(formats_as(fmt_duration(2_500), "2.500 us"));
+    assert!(formats_as(fmt_duration(999_999_999), "1.000 s"));
+    assert!(formats_as(fmt_duration(999_999), "999.999 us"));
+}
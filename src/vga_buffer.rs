@@ -1,4 +1,16 @@
+/* Text output on the 80x25 VGA text buffer at 0xb8000, split into VT_COUNT virtual consoles (tty1 to tty4). Each
+ * has its own Writer, with its own contents, output position, colors and scrollback. The kernel log (print!) goes to
+ * tty1, the interactive console to tty2 (see console.rs), and Alt+F1..F4 switch between them. The top row of every
+ * console is the status bar (see status_bar.rs); text scrolls in the rows below it.
+ *
+ * Writers draw on a screen in ordinary memory, never directly on the hardware. The one that's shown also holds the
+ * Display, which remembers what's in video memory and only writes the cells that change. So scrolling a line is a
+ * memmove in memory plus the cells that differ, instead of a volatile read and write of all 2000 cells, and showing
+ * another console only rewrites what differs between the two.
+ */
 use lazy_static::lazy_static;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 #[allow(dead_code)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -31,23 +43,71 @@ struct ColorCode(u8);
 // Implementing a newtype allows us to type-check while still
 // using u8 data types!
 impl ColorCode {
-  fn new(foreground: Color, background: Color) -> ColorCode {
+  const fn new(foreground: Color, background: Color) -> ColorCode {
     ColorCode((background as u8) << 4 | (foreground as u8))
   }
+
+  /* Bit 7 is the top bit of the background, which is why backgrounds past LightGray blink: the attribute controller
+   * makes it blink the character instead, unless blinking is turned off (set_blink_enabled), when it does brighten
+   * the background.
+   */
+  fn with_blink(self, blink: bool) -> ColorCode {
+    ColorCode(if blink { self.0 | ATTRIBUTE_BLINK } else { self.0 & !ATTRIBUTE_BLINK })
+  }
 }
 
+const ATTRIBUTE_BLINK: u8 = 1 << 7;
+
 // repr(C) interprets structs as C structs ( ex. fixed struct 
 // field ordering, which is not available in Rust structs)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
-struct ScreenChar {
+pub struct ScreenChar {
   ascii_char: u8,
   color_code: ColorCode,
 }
 
+// The VGA hardware reads these directly: one byte per color attribute, two bytes per character cell
+static_assert_layout!(ColorCode, 1, 1);
+static_assert_layout!(ScreenChar, 2, 1);
+
+/* The characters the VGA font has at 0x80..=0xff: code page 437. The font also has glyphs at 0x01..=0x1f, but those
+ * bytes are control characters everywhere else, so nothing is mapped there.
+ */
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»\
+  ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+// Shown for characters the font doesn't have
+const PLACEHOLDER: u8 = 0xfe;
+
+// The code page 437 byte that shows `c`, or the placeholder
+fn to_cp437(c: char) -> u8 {
+  match c {
+    ' '..='~' => c as u8,
+    // The font only has one mu, and one beta; the Greek letters look the same
+    'μ' => 0xe6,
+    'β' => 0xe1,
+    _ => CP437_HIGH.chars().position(|high| high == c).map_or(PLACEHOLDER, |i| 0x80 + i as u8),
+  }
+}
+
+// The character a code page 437 byte shows; the font's glyphs for control characters aren't mapped back
+fn from_cp437(byte: u8) -> char {
+  match byte {
+    0x20..=0x7e => byte as char,
+    0x80..=0xff => CP437_HIGH.chars().nth((byte - 0x80) as usize).unwrap_or(core::char::REPLACEMENT_CHARACTER),
+    _ => core::char::REPLACEMENT_CHARACTER,
+  }
+}
+
 // Size of the VGA buffer
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+// Tab stops are every TAB_WIDTH columns
+const TAB_WIDTH: usize = 8;
+const BACKSPACE: u8 = 0x08;
+// The status bar's row, and the first row of the scrolling region below it
+const STATUS_ROW: usize = 0;
+const SCROLL_TOP: usize = STATUS_ROW + 1;
 
 // We have to make Buffer::chars Volatile so it won't get optimized away; since we're just writing to it and never
 // reading from it, the compiler might decide not to include writes to it (not knowing about the screen printing)
@@ -56,28 +116,166 @@ use volatile::Volatile;
 struct Buffer {
   chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
+static_assert!(core::mem::size_of::<Buffer>() == BUFFER_WIDTH * BUFFER_HEIGHT * 2, "Buffer must overlay 0xb8000 exactly");
+
+// How many screens of text scrolled off the top are kept
+pub const SCROLLBACK_SCREENS: usize = 4;
+
+pub type Row = [ScreenChar; BUFFER_WIDTH];
+
+/* Where a Writer keeps the characters it shows. The consoles keep theirs in static memory (and their Writers copy
+ * them to the hardware while they're shown). A MemoryBuffer is a screen of its own, for exercising a Writer's
+ * wrapping and scrolling in tests without going near the consoles.
+ */
+pub trait TextBuffer {
+  fn rows(&self) -> &[Row; BUFFER_HEIGHT];
+  fn rows_mut(&mut self) -> &mut [Row; BUFFER_HEIGHT];
+}
+
+// A console's screen
+type ConsoleScreen = &'static mut [Row; BUFFER_HEIGHT];
+
+impl TextBuffer for ConsoleScreen {
+  fn rows(&self) -> &[Row; BUFFER_HEIGHT] {
+    self
+  }
+
+  fn rows_mut(&mut self) -> &mut [Row; BUFFER_HEIGHT] {
+    self
+  }
+}
+
+pub struct MemoryBuffer {
+  rows: [Row; BUFFER_HEIGHT],
+}
+
+impl MemoryBuffer {
+  pub fn new() -> MemoryBuffer {
+    MemoryBuffer { rows: [[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT] }
+  }
+}
+
+impl Default for MemoryBuffer {
+  fn default() -> MemoryBuffer {
+    MemoryBuffer::new()
+  }
+}
+
+impl TextBuffer for MemoryBuffer {
+  fn rows(&self) -> &[Row; BUFFER_HEIGHT] {
+    &self.rows
+  }
+
+  fn rows_mut(&mut self) -> &mut [Row; BUFFER_HEIGHT] {
+    &mut self.rows
+  }
+}
+
+/* Rows that scrolled off the top, in a ring that overwrites the oldest once full. All the memory is allocated up
+ * front, so nothing allocates while printing (which interrupt handlers do).
+ */
+struct Scrollback {
+  rows: Vec<Row>,
+  // Where the next row goes once `rows` is full
+  next: usize,
+  // How many rows back the screen is scrolled; 0 shows the live screen
+  offset: usize,
+}
 
-// Specify 'static to buffer because the buffer will always be around
-pub struct Writer {
+impl Scrollback {
+  fn push(&mut self, row: Row) {
+    if self.rows.len() < self.rows.capacity() {
+      self.rows.push(row);
+    } else {
+      self.rows[self.next] = row;
+      self.next = (self.next + 1) % self.rows.len();
+    }
+  }
+
+  // The row `back` rows above the live screen, 1 being the last one that scrolled off
+  fn history(&self, back: usize) -> &Row {
+    let len = self.rows.len();
+    let newest = if len < self.rows.capacity() { len } else { self.next + len };
+    &self.rows[(newest - back) % len]
+  }
+}
+
+// The hardware buffer, and a copy of what's in it so only the cells that change are written (reading it is slow too)
+struct Display {
+  buffer: &'static mut Buffer,
+  shown: &'static mut [Row; BUFFER_HEIGHT],
+}
+
+impl Display {
+  fn put(&mut self, row: usize, col: usize, c: ScreenChar) {
+    if self.shown[row][col] != c {
+      self.shown[row][col] = c;
+      self.buffer.chars[row][col].write(c);
+    }
+  }
+
+  fn put_row(&mut self, row: usize, chars: &Row) {
+    for (col, &c) in chars.iter().enumerate() {
+      self.put(row, col, c);
+    }
+  }
+}
+
+// A console's Writer by default, which draws on a ConsoleScreen
+pub struct Writer<B: TextBuffer = ConsoleScreen> {
+  // Output starts on the bottom row and scrolls up from there, unless moved with set_cursor
+  row_position: usize,
   column_position: usize,
   color_code: ColorCode,
-  buffer: &'static mut Buffer,
+  // What the console shows, whether or not it's on the hardware right now
+  screen: B,
+  // The hardware, while this console is the one shown
+  display: Option<Display>,
+  // None until enable_scrollback (it needs the heap)
+  scrollback: Option<Scrollback>,
 }
 
-impl Writer {
+impl<B: TextBuffer> Writer<B> {
+  // A writer on `screen` that isn't shown; output starts on the bottom row, in the default colors
+  pub fn new(screen: B) -> Writer<B> {
+    Writer {
+      row_position: BUFFER_HEIGHT - 1,
+      column_position: 0,
+      color_code: DEFAULT_COLOR,
+      screen,
+      display: None,
+      scrollback: None,
+    }
+  }
+
+  /* Writes a code page 437 byte at the output position, except for the control characters a terminal handles:
+   * '\n' starts a new line, '\r' goes back to the start of this one, '\t' moves on to the next multiple of
+   * TAB_WIDTH columns, and backspace erases the character before the output position.
+   */
   pub fn write_byte(&mut self, byte: u8) {
+    // New output brings the view back to the live screen, like a terminal does
+    self.scroll_to_live();
     match byte {
       b'\n' => self.new_line(),
+      b'\r' => self.column_position = 0,
+      b'\t' => {
+        if self.column_position >= BUFFER_WIDTH {
+          self.new_line();
+        }
+        // Moves without erasing; at the last stop, the next character wraps
+        self.column_position = ((self.column_position / TAB_WIDTH + 1) * TAB_WIDTH).min(BUFFER_WIDTH);
+      },
+      BACKSPACE => self.backspace(),
       byte => {
         if self.column_position >= BUFFER_WIDTH {
           self.new_line();
         }
 
-        let row = BUFFER_HEIGHT - 1;
+        let row = self.row_position;
         let col = self.column_position;
 
         let color_code = self.color_code;
-        self.buffer.chars[row][col].write(ScreenChar {
+        self.put(row, col, ScreenChar {
           ascii_char: byte,
           color_code: color_code,
         });
@@ -87,32 +285,209 @@ impl Writer {
   }
 
   fn new_line(&mut self) { 
-   for row in 1..BUFFER_HEIGHT {
-     for col in 0..BUFFER_WIDTH {
-       // Use read() and write() because each value is wrapped in Volatile
-      let c = self.buffer.chars[row][col].read();
-      self.buffer.chars[row-1][col].write(c);
-     }
+   // Only scroll once we're on the bottom row
+   if self.row_position < BUFFER_HEIGHT - 1 {
+     self.row_position += 1;
+     self.column_position = 0;
+     return;
    }
-   self.clear_row(BUFFER_HEIGHT - 1);
+   if let Some(scrollback) = &mut self.scrollback {
+     scrollback.push(self.screen.rows()[SCROLL_TOP]);
+   }
+   // A memmove in memory; the hardware then only gets the cells that came out different
+   let blank = self.blank();
+   let rows = self.screen.rows_mut();
+   rows.copy_within(SCROLL_TOP + 1.., SCROLL_TOP);
+   rows[BUFFER_HEIGHT - 1] = [blank; BUFFER_WIDTH];
+   self.render();
    self.column_position = 0;
   }
 
-  fn clear_row(&mut self, row: usize) {
-    let blank = ScreenChar {
-      ascii_char: b' ',
-      color_code: self.color_code
+  // Writes a cell of the screen, and of the hardware if it's showing that row of the live screen
+  fn put(&mut self, row: usize, col: usize, c: ScreenChar) {
+    self.screen.rows_mut()[row][col] = c;
+    if row == STATUS_ROW || self.view_offset() == 0 {
+      if let Some(display) = &mut self.display {
+        display.put(row, col, c);
+      }
+    }
+  }
+
+  // Brings the hardware (if this console is shown) up to date with the view: the live screen or a page of history
+  fn render(&mut self) {
+    let display = match &mut self.display {
+      Some(display) => display,
+      None => return,
+    };
+    let offset = self.scrollback.as_ref().map_or(0, |scrollback| scrollback.offset);
+    // The status bar stays put; only the scrolling region shows history
+    let rows = self.screen.rows();
+    display.put_row(STATUS_ROW, &rows[STATUS_ROW]);
+    for row in SCROLL_TOP..BUFFER_HEIGHT {
+      match &self.scrollback {
+        Some(scrollback) if row - SCROLL_TOP < offset => {
+          display.put_row(row, scrollback.history(offset - (row - SCROLL_TOP)))
+        },
+        _ => display.put_row(row, &rows[row - offset]),
+      }
+    }
+  }
+
+  fn view_offset(&self) -> usize {
+    self.scrollback.as_ref().map_or(0, |scrollback| scrollback.offset)
+  }
+
+  fn blank(&self) -> ScreenChar {
+    ScreenChar { ascii_char: b' ', color_code: self.color_code }
+  }
+
+  /* Starts keeping text that scrolls off the top, SCROLLBACK_SCREENS screens of it. Call once the heap is up; it
+   * allocates everything the scrollback needs right here.
+   */
+  pub fn enable_scrollback(&mut self) {
+    if self.scrollback.is_none() {
+      self.scrollback = Some(Scrollback {
+        rows: Vec::with_capacity(SCROLLBACK_SCREENS * BUFFER_HEIGHT),
+        next: 0,
+        offset: 0,
+      });
+    }
+  }
+
+  /* Scrolls the view `rows` rows back into the history (or forward, if negative), stopping at the oldest row and at
+   * the live screen. Does nothing without scrollback.
+   */
+  pub fn scroll_view(&mut self, rows: isize) {
+    let scrollback = match &mut self.scrollback {
+      Some(scrollback) => scrollback,
+      None => return,
+    };
+    let offset = (scrollback.offset as isize + rows).max(0).min(scrollback.rows.len() as isize) as usize;
+    if offset != scrollback.offset {
+      scrollback.offset = offset;
+      self.render();
+    }
+  }
+
+  // Scrolls the view a screen back or forward
+  pub fn page_up(&mut self) {
+    self.scroll_view(BUFFER_HEIGHT as isize);
+  }
+
+  pub fn page_down(&mut self) {
+    self.scroll_view(-(BUFFER_HEIGHT as isize));
+  }
+
+  pub fn scroll_to_live(&mut self) {
+    let offset = match &self.scrollback {
+      Some(scrollback) => scrollback.offset,
+      None => return,
     };
+    self.scroll_view(-(offset as isize));
+  }
+
+  // Sets the colors of everything written from now on
+  pub fn set_color(&mut self, foreground: Color, background: Color) {
+    self.color_code = ColorCode::new(foreground, background);
+  }
+
+  /* Makes everything written from now on blink, or stop blinking. Only shows while blinking is enabled (it is at
+   * boot); otherwise it's the bright version of the background instead.
+   */
+  pub fn set_blink(&mut self, blink: bool) {
+    self.color_code = self.color_code.with_blink(blink);
+  }
+
+  // Runs `f` with the writer set to these colors, then puts the previous ones back
+  pub fn with_color<R>(&mut self, foreground: Color, background: Color, f: impl FnOnce(&mut Self) -> R) -> R {
+    let saved = self.color_code;
+    self.set_color(foreground, background);
+    let result = f(self);
+    self.color_code = saved;
+    result
+  }
+
+  // The character shown at (row, col), as far as code page 437 can tell: '■' could also have been a placeholder
+  pub fn read_char_at(&self, row: usize, col: usize) -> char {
+    from_cp437(self.screen.rows()[row][col].ascii_char)
+  }
+
+  // Returns the (row, column) the next character will be written at
+  pub fn cursor(&self) -> (usize, usize) {
+    (self.row_position, self.column_position)
+  }
+
+  // Moves the output position; later writes continue (and wrap, and scroll) from there
+  pub fn set_cursor(&mut self, row: usize, col: usize) {
+    assert!(row < BUFFER_HEIGHT && col < BUFFER_WIDTH, "cursor ({}, {}) is off screen", row, col);
+    self.row_position = row;
+    self.column_position = col;
+  }
+
+  // Erases the character before the cursor and moves back onto it, up into the previous row if needed (line editing)
+  pub fn backspace(&mut self) {
+    let (row, col) = match self.cursor() {
+      (row, 0) if row <= SCROLL_TOP => return,
+      (row, 0) => (row - 1, BUFFER_WIDTH - 1),
+      (row, col) => (row, col - 1),
+    };
+    self.set_cursor(row, col);
+    self.write_byte(b' ');
+    self.set_cursor(row, col);
+  }
+
+  /* Remembers the current output position and puts it back when the returned guard is dropped. Full-screen UI
+   * (status bar, panic screen, menus) can move the cursor anywhere through the guard without breaking the log flow:
+   *   let mut writer = WRITER.lock();
+   *   let mut saved = writer.save_cursor();
+   *   saved.set_cursor(0, 0);
+   *   saved.write_string("...");
+   */
+  pub fn save_cursor(&mut self) -> CursorGuard<'_, B> {
+    let saved = self.cursor();
+    CursorGuard { writer: self, saved }
+  }
+
+  fn clear_row(&mut self, row: usize) {
+    let blank = self.blank();
     for col in 0..BUFFER_WIDTH {
-      self.buffer.chars[row][col].write(blank);
+      self.put(row, col, blank);
+    }
+  }
+
+  /* Writes `s` at (row, col) without touching the output position; whatever doesn't fit in the row is cut off, and a
+   * row below the screen is left out entirely. Returns the column after the last character written.
+   */
+  pub fn write_at(&mut self, row: usize, col: usize, s: &str) -> usize {
+    if row >= BUFFER_HEIGHT {
+      return col;
+    }
+    let mut col = col;
+    for c in s.chars() {
+      if col >= BUFFER_WIDTH {
+        break;
+      }
+      self.put(row, col, ScreenChar { ascii_char: to_cp437(c), color_code: self.color_code });
+      col += 1;
+    }
+    col
+  }
+
+  // Blanks the screen (all but the status bar) in the current colors and moves the output position to its top left
+  pub fn clear_screen(&mut self) {
+    self.scroll_to_live();
+    for row in SCROLL_TOP..BUFFER_HEIGHT {
+      self.clear_row(row);
     }
+    self.set_cursor(SCROLL_TOP, 0);
   }
 
   pub fn write_string(&mut self, s: &str) {
-    for byte in s.bytes() {
-      match byte {
-      0x20..=0x7e | b'\n' => self.write_byte(byte),
-      _ => self.write_byte(0xfe),
+    // One cell per character, not per UTF-8 byte
+    for c in s.chars() {
+      match c {
+      '\n' | '\r' | '\t' | '\x08' => self.write_byte(c as u8),
+      _ => self.write_byte(to_cp437(c)),
       }
     }
   }
@@ -121,7 +496,135 @@ impl Writer {
 // Implement the Write trait for Writer (only one reqd method)
 // So we can write integers/floats easily using core::fmt::Write
 use core::fmt;
-impl fmt::Write for Writer {
+impl<B: TextBuffer> fmt::Write for Writer<B> {
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    self.write_string(s);
+    Ok(())
+  }
+}
+
+// Restores the saved output position on drop; derefs to the Writer in the meantime
+pub struct CursorGuard<'a, B: TextBuffer = ConsoleScreen> {
+  writer: &'a mut Writer<B>,
+  saved: (usize, usize),
+}
+
+impl<B: TextBuffer> core::ops::Deref for CursorGuard<'_, B> {
+  type Target = Writer<B>;
+  fn deref(&self) -> &Writer<B> {
+    self.writer
+  }
+}
+
+impl<B: TextBuffer> core::ops::DerefMut for CursorGuard<'_, B> {
+  fn deref_mut(&mut self) -> &mut Writer<B> {
+    self.writer
+  }
+}
+
+impl<B: TextBuffer> Drop for CursorGuard<'_, B> {
+  fn drop(&mut self) {
+    let (row, col) = self.saved;
+    self.writer.row_position = row;
+    self.writer.column_position = col;
+  }
+}
+
+/* A rectangle of a console's screen with its own output position, colors and scrolling, so several subsystems (a log
+ * pane, a shell pane, a status pane) can share a console without writing over each other. Text wraps at the window's
+ * right edge and scrolls inside it; nothing outside the rectangle changes. The console's own print!-style output
+ * doesn't know about windows, so they're meant for consoles that are laid out in panes, not for tty1.
+ */
+pub struct Window {
+  vt: usize,
+  top: usize,
+  left: usize,
+  height: usize,
+  width: usize,
+  // Output position, relative to the window
+  row: usize,
+  col: usize,
+  color_code: ColorCode,
+}
+
+impl Window {
+  // A window on console `vt` (0 for tty1); panics unless it fits on the screen below the status bar
+  pub fn new(vt: usize, top: usize, left: usize, height: usize, width: usize) -> Window {
+    assert!(vt < VT_COUNT, "there is no tty{}", vt + 1);
+    let rows_fit = top >= SCROLL_TOP && height > 0 && top + height <= BUFFER_HEIGHT;
+    assert!(rows_fit, "rows {}..{} don't fit", top, top + height);
+    assert!(width > 0 && left + width <= BUFFER_WIDTH, "columns {}..{} don't fit", left, left + width);
+    Window { vt, top, left, height, width, row: 0, col: 0, color_code: DEFAULT_COLOR }
+  }
+
+  pub fn set_color(&mut self, foreground: Color, background: Color) {
+    self.color_code = ColorCode::new(foreground, background);
+  }
+
+  // Like Writer::set_blink
+  pub fn set_blink(&mut self, blink: bool) {
+    self.color_code = self.color_code.with_blink(blink);
+  }
+
+  // Blanks the window in its colors and moves the output position to its top left
+  pub fn clear(&mut self) {
+    let _guard = crate::sync::InterruptGuard::new();
+    let mut writer = VTS[self.vt].lock();
+    for row in 0..self.height {
+      self.clear_row(&mut writer, row);
+    }
+    self.row = 0;
+    self.col = 0;
+  }
+
+  pub fn write_string(&mut self, s: &str) {
+    let _guard = crate::sync::InterruptGuard::new();
+    let mut writer = VTS[self.vt].lock();
+    for c in s.chars() {
+      match c {
+        '\n' => self.write_byte(&mut writer, b'\n'),
+        _ => self.write_byte(&mut writer, to_cp437(c)),
+      }
+    }
+  }
+
+  fn write_byte(&mut self, writer: &mut Writer, byte: u8) {
+    if byte == b'\n' {
+      self.new_line(writer);
+    } else {
+      if self.col == self.width {
+        self.new_line(writer);
+      }
+      let c = ScreenChar { ascii_char: byte, color_code: self.color_code };
+      writer.put(self.top + self.row, self.left + self.col, c);
+      self.col += 1;
+    }
+  }
+
+  fn new_line(&mut self, writer: &mut Writer) {
+    self.col = 0;
+    if self.row + 1 < self.height {
+      self.row += 1;
+      return;
+    }
+    for row in self.top..self.top + self.height - 1 {
+      for col in self.left..self.left + self.width {
+        let below = writer.screen.rows()[row + 1][col];
+        writer.put(row, col, below);
+      }
+    }
+    self.clear_row(writer, self.height - 1);
+  }
+
+  fn clear_row(&self, writer: &mut Writer, row: usize) {
+    let blank = ScreenChar { ascii_char: b' ', color_code: self.color_code };
+    for col in self.left..self.left + self.width {
+      writer.put(self.top + row, col, blank);
+    }
+  }
+}
+
+impl fmt::Write for Window {
   fn write_str(&mut self, s: &str) -> fmt::Result {
     self.write_string(s);
     Ok(())
@@ -138,14 +641,136 @@ impl fmt::Write for Writer {
 */
 use spin::Mutex;
 lazy_static! {
-  pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-    column_position: 0,
-    color_code: ColorCode::new(Color::Yellow, Color::Black),
+  // One writer per virtual console; whenever two are locked at once, the lower index is locked first
+  pub static ref VTS: [Mutex<Writer>; VT_COUNT] = [new_vt(0), new_vt(1), new_vt(2), new_vt(3)];
+  // tty1, where the kernel log goes
+  pub static ref WRITER: &'static Mutex<Writer> = &VTS[0];
+}
+
+pub const VT_COUNT: usize = 4;
+
+const DEFAULT_COLOR: ColorCode = ColorCode::new(Color::Yellow, Color::Black);
+const BLANK: ScreenChar = ScreenChar { ascii_char: b' ', color_code: DEFAULT_COLOR };
+
+// The consoles' screens; static so there are screens before there is a heap
+static mut SCREENS: [[Row; BUFFER_HEIGHT]; VT_COUNT] = [[[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT]; VT_COUNT];
+// What's in video memory, as far as the Display knows; nothing we write is all zeros, so the first render draws it all
+static mut SHOWN: [Row; BUFFER_HEIGHT] =
+  [[ScreenChar { ascii_char: 0, color_code: ColorCode(0) }; BUFFER_WIDTH]; BUFFER_HEIGHT];
+// The console that is shown
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+// Only called once per index, by the VTS initializer, so each screen (and the display) is handed out once
+fn new_vt(index: usize) -> Mutex<Writer> {
+  // tty1 is shown at boot
+  let display = if index == 0 {
     // The bootloader `identity maps` 0xb8000 in physical memory to 0xb8000 in virtual memory here, as paging is enabled
-    buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-  });
+    Some(Display {
+      buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+      shown: unsafe { &mut SHOWN },
+    })
+  } else {
+    None
+  };
+  let mut writer = Writer::new(unsafe { &mut SCREENS[index] });
+  writer.display = display;
+  // Clears whatever the firmware left on the screen
+  writer.render();
+  Mutex::new(writer)
+}
+
+// Index of the console that is shown (0 for tty1)
+pub fn active_vt() -> usize {
+  ACTIVE.load(Ordering::Relaxed)
+}
+
+// The writer of the console that is shown
+pub fn active() -> &'static Mutex<Writer> {
+  &VTS[active_vt()]
+}
+
+// Shows console `index` (0 for tty1); only the cells that differ between the two are rewritten
+pub fn switch_to(index: usize) {
+  assert!(index < VT_COUNT, "there is no tty{}", index + 1);
+  let _guard = crate::sync::InterruptGuard::new();
+  let current = active_vt();
+  if index == current {
+    return;
+  }
+  let (mut shown, mut hidden) = if current < index {
+    let shown = VTS[current].lock();
+    (shown, VTS[index].lock())
+  } else {
+    let hidden = VTS[index].lock();
+    (VTS[current].lock(), hidden)
+  };
+  // Coming back to a console shows its live screen, not the page of history it was left on
+  shown.scroll_to_live();
+  hidden.display = shown.display.take();
+  hidden.render();
+  ACTIVE.store(index, Ordering::Relaxed);
 }
 
+/* Releases every console's lock, for the panic screen: a panic can hit while a print holds one, and it would never be
+ * released. Unsafe because whoever held a lock mustn't run again, so interrupts must be off for good.
+ */
+pub unsafe fn force_unlock() {
+  for vt in VTS.iter() {
+    vt.force_unlock();
+  }
+}
+
+// The attribute controller's ports, and its Attribute Mode Control register
+const ATTRIBUTE_INDEX: u16 = 0x3c0;
+const ATTRIBUTE_DATA_READ: u16 = 0x3c1;
+const ATTRIBUTE_MODE_CONTROL: u8 = 0x10;
+// Set along with an index, or the controller stops showing anything while it's being programmed
+const ATTRIBUTE_PALETTE_SOURCE: u8 = 1 << 5;
+const MODE_CONTROL_BLINK: u8 = 1 << 3;
+// Reading it resets the attribute controller to expect an index next
+const INPUT_STATUS_1: u16 = 0x3da;
+
+static BLINK_ENABLED: AtomicBool = AtomicBool::new(true);
+
+// Reads the Attribute Mode Control register, then writes back what `f` makes of it
+fn update_attribute_mode(f: impl FnOnce(u8) -> u8) -> u8 {
+  use x86_64::instructions::port::Port;
+  let _guard = crate::sync::InterruptGuard::new();
+  let mut index: Port<u8> = Port::new(ATTRIBUTE_INDEX);
+  let mut data_read: Port<u8> = Port::new(ATTRIBUTE_DATA_READ);
+  let mut input_status: Port<u8> = Port::new(INPUT_STATUS_1);
+  // Only touches the attribute controller, which changes how the screen looks and nothing else
+  unsafe {
+    input_status.read();
+    index.write(ATTRIBUTE_MODE_CONTROL | ATTRIBUTE_PALETTE_SOURCE);
+    let mode = f(data_read.read());
+    // Data goes to the index port too; the controller alternates between index and data
+    index.write(mode);
+    mode
+  }
+}
+
+/* Whether bit 7 of a character's colors makes it blink (the default), or brightens its background, which gives all
+ * 16 colors as backgrounds but no blinking. Applies to every console at once.
+ */
+pub fn set_blink_enabled(enabled: bool) {
+  update_attribute_mode(|mode| if enabled { mode | MODE_CONTROL_BLINK } else { mode & !MODE_CONTROL_BLINK });
+  BLINK_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn blink_enabled() -> bool {
+  BLINK_ENABLED.load(Ordering::Relaxed)
+}
+
+// Turns on scrollback for every console. Needs the heap.
+pub fn enable_scrollback() {
+  for vt in VTS.iter() {
+    let _guard = crate::sync::InterruptGuard::new();
+    vt.lock().enable_scrollback();
+  }
+}
+
+
 ///// Macros for printing
 
 #[macro_export]
@@ -160,7 +785,8 @@ macro_rules! print {
   ($($arg:tt)*) => (
     // The `crate` keyword allows the macro to be used both inside this file and outside
     // It expands to the current crate (vga_buffer) if used outside the file
-    $crate::vga_buffer::_print(format_args!($($arg)*))
+    // The console passes it on to the screen, serial and the kernel message buffer
+    $crate::console::_print(format_args!($($arg)*))
   );
 }
 
@@ -172,30 +798,366 @@ macro_rules! println {
   );
 }
 
+// Like print!/println!, in the given colors (e.g. println_color!(Color::LightRed, Color::Black, "failed: {}", e))
+#[macro_export]
+macro_rules! print_color {
+  ($fg:expr, $bg:expr, $($arg:tt)*) => (
+    $crate::console::_print_color($fg, $bg, format_args!($($arg)*))
+  );
+}
+
+#[macro_export]
+macro_rules! println_color {
+  ($fg:expr, $bg:expr) => ( $crate::print_color!($fg, $bg, "\n") );
+  ($fg:expr, $bg:expr, $($arg:tt)*) => (
+    $crate::print_color!($fg, $bg, "{}\n", format_args!($($arg)*))
+  );
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
   use core::fmt::Write;
-  use x86_64::instructions::interrupts;
   // Have to disable interrupts when printing; else, deadlock could occur if an interrupt is handled while WRITER is locked.
-  interrupts::without_interrupts (|| {
-    // This is a closure in Rust
-    WRITER.lock().write_fmt(args).unwrap();
-  });
+  let _guard = crate::sync::InterruptGuard::new();
+  WRITER.lock().write_fmt(args).unwrap();
 }
 
+#[doc(hidden)]
+pub fn _print_color(foreground: Color, background: Color, args: fmt::Arguments) {
+  use core::fmt::Write;
+  let _guard = crate::sync::InterruptGuard::new();
+  WRITER.lock().with_color(foreground, background, |writer| writer.write_fmt(args)).unwrap();
+}
+
+pub fn clear_screen() {
+  let _guard = crate::sync::InterruptGuard::new();
+  WRITER.lock().clear_screen();
+}
+
+// Moves the output position; later prints continue (and wrap, and scroll) from there
+pub fn set_cursor(row: usize, col: usize) {
+  let _guard = crate::sync::InterruptGuard::new();
+  WRITER.lock().set_cursor(row, col);
+}
+
+// Writes `s` at (row, col) without moving the output position, cut off at the edges of the screen
+pub fn write_at(row: usize, col: usize, s: &str) {
+  let _guard = crate::sync::InterruptGuard::new();
+  WRITER.lock().write_at(row, col, s);
+}
+
+// The character at (row, col) of tty1, for tests that check what ended up on the screen
+pub fn read_char_at(row: usize, col: usize) -> char {
+  let _guard = crate::sync::InterruptGuard::new();
+  WRITER.lock().read_char_at(row, col)
+}
+
+/* What the screen shows right now, read back from video memory (whichever console that is, and whatever page of its
+ * history), for tests to assert on exactly
+ */
+pub fn snapshot() -> [[char; BUFFER_WIDTH]; BUFFER_HEIGHT] {
+  let _guard = crate::sync::InterruptGuard::new();
+  let writer = active().lock();
+  let display = writer.display.as_ref().expect("the shown console has no display");
+  let mut snapshot = [[' '; BUFFER_WIDTH]; BUFFER_HEIGHT];
+  for (row, chars) in snapshot.iter_mut().enumerate() {
+    for (col, c) in chars.iter_mut().enumerate() {
+      *c = from_cp437(display.buffer.chars[row][col].read().ascii_char);
+    }
+  }
+  snapshot
+}
+
+// Sets the colors of everything printed from now on
+pub fn set_color(foreground: Color, background: Color) {
+  let _guard = crate::sync::InterruptGuard::new();
+  WRITER.lock().set_color(foreground, background);
+}
+
+/* Runs `f` with everything printed in these colors, then puts the previous ones back. WRITER isn't locked while `f`
+ * runs (so it can print), which means output from interrupt handlers meanwhile comes out in these colors too.
+ */
+pub fn with_color<R>(foreground: Color, background: Color, f: impl FnOnce() -> R) -> R {
+  let saved = {
+    let _guard = crate::sync::InterruptGuard::new();
+    let mut writer = WRITER.lock();
+    let saved = writer.color_code;
+    writer.set_color(foreground, background);
+    saved
+  };
+  let result = f();
+  let _guard = crate::sync::InterruptGuard::new();
+  WRITER.lock().color_code = saved;
+  result
+}
+
+
+// fmt::Write adapter that renders formatted text into the status bar
+struct StatusWriter<'a> {
+  writer: &'a mut Writer,
+  col: usize,
+}
+
+impl fmt::Write for StatusWriter<'_> {
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    self.col = self.writer.write_at(STATUS_ROW, self.col, s);
+    Ok(())
+  }
+}
+
+/* Redraws the status bar, the top row of every console (so switching consoles shows it up to date). Text that
+ * doesn't fit is cut off.
+ */
+pub fn draw_status_bar(args: fmt::Arguments) {
+  use core::fmt::Write;
+  for vt in VTS.iter() {
+    let _guard = crate::sync::InterruptGuard::new();
+    let mut writer = vt.lock();
+    writer.with_color(Color::Black, Color::LightGray, |writer| {
+      let mut status = StatusWriter { writer: &mut *writer, col: 0 };
+      status.write_fmt(args).unwrap();
+      // Blank whatever is left of the bar from a previous, longer message
+      let end = status.col;
+      for col in end..BUFFER_WIDTH {
+        writer.write_at(STATUS_ROW, col, " ");
+      }
+    });
+  }
+}
 
 #[test_case]
 fn test_println_output() {
-  use core::fmt::Write;
   use x86_64::instructions::interrupts;
   let s = "Test string...";
   interrupts::without_interrupts( || {
-    let mut writer = WRITER.lock();
     // print a newline so any dots printed by the timer don't mess up testing
-    writeln!(writer, "\n{}", s);
+    crate::println!("\n{}", s);
+    crate::println_color!(Color::Green, Color::Black, "in green");
+    let screen = snapshot();
     for (i, c) in s.chars().enumerate() {
-      let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-      assert_eq!(char::from(screen_char.ascii_char), c);
+      assert_eq!(screen[BUFFER_HEIGHT - 3][i], c);
+    }
+    assert_eq!(&screen[BUFFER_HEIGHT - 2][..8], &['i', 'n', ' ', 'g', 'r', 'e', 'e', 'n']);
+    assert_eq!(screen[BUFFER_HEIGHT - 2][8], ' ');
+  });
+}
+
+#[test_case]
+fn test_cursor_guard_restores_position() {
+  use x86_64::instructions::interrupts;
+  interrupts::without_interrupts( || {
+    let mut writer = WRITER.lock();
+    writer.write_string("\nabc");
+    let before = writer.cursor();
+    assert_eq!(before, (BUFFER_HEIGHT - 1, 3));
+    {
+      let mut saved = writer.save_cursor();
+      saved.set_cursor(5, 10);
+      saved.write_string("xy");
+      assert_eq!(saved.cursor(), (5, 12));
+    }
+    assert_eq!(writer.cursor(), before);
+    assert_eq!(writer.screen.rows()[5][10].ascii_char, b'x');
+    assert_eq!(writer.screen.rows()[5][11].ascii_char, b'y');
+  });
+}
+
+#[test_case]
+fn test_with_color_restores_the_color() {
+  use x86_64::instructions::interrupts;
+  interrupts::without_interrupts( || {
+    let mut writer = WRITER.lock();
+    let before = writer.color_code;
+    writer.write_string("\n");
+    writer.with_color(Color::LightRed, Color::Blue, |writer| writer.write_string("x"));
+    writer.write_string("y");
+    let x = writer.screen.rows()[BUFFER_HEIGHT - 1][0];
+    let y = writer.screen.rows()[BUFFER_HEIGHT - 1][1];
+    assert_eq!(x.color_code, ColorCode::new(Color::LightRed, Color::Blue));
+    assert_eq!(y.color_code, before);
+  });
+}
+
+#[test_case]
+fn test_write_at_is_cut_off_and_keeps_the_cursor() {
+  use x86_64::instructions::interrupts;
+  interrupts::without_interrupts( || {
+    let mut writer = WRITER.lock();
+    let before = writer.cursor();
+    writer.write_at(4, 0, " ");
+    assert_eq!(writer.write_at(3, BUFFER_WIDTH - 2, "abc"), BUFFER_WIDTH);
+    assert_eq!(writer.screen.rows()[3][BUFFER_WIDTH - 2].ascii_char, b'a');
+    assert_eq!(writer.screen.rows()[3][BUFFER_WIDTH - 1].ascii_char, b'b');
+    // Nothing wrapped into the next row
+    assert_eq!(writer.screen.rows()[4][0].ascii_char, b' ');
+    // Off screen altogether: nothing to write, and no panic
+    assert_eq!(writer.write_at(BUFFER_HEIGHT, 0, "abc"), 0);
+    assert_eq!(writer.cursor(), before);
+  });
+}
+
+#[test_case]
+fn test_hardware_matches_the_screen() {
+  use core::fmt::Write;
+  use x86_64::instructions::interrupts;
+  interrupts::without_interrupts( || {
+    let mut writer = WRITER.lock();
+    // Scrolls, which only writes the cells that changed
+    for i in 0..BUFFER_HEIGHT {
+      writeln!(writer, "line {}", i).unwrap();
+    }
+    let display = writer.display.as_ref().expect("tty1 isn't shown");
+    for row in 0..BUFFER_HEIGHT {
+      for col in 0..BUFFER_WIDTH {
+        assert_eq!(display.buffer.chars[row][col].read(), writer.screen.rows()[row][col]);
+      }
+    }
+  });
+}
+
+#[test_case]
+fn test_switching_consoles_keeps_their_contents() {
+  use x86_64::instructions::interrupts;
+  // The bottom row of the hardware
+  let shown = || {
+    let writer = active().lock();
+    let display = writer.display.as_ref().expect("the active console has no display");
+    let mut text = [0u8; 4];
+    for (col, c) in text.iter_mut().enumerate() {
+      *c = display.buffer.chars[BUFFER_HEIGHT - 1][col].read().ascii_char;
     }
+    text
+  };
+  interrupts::without_interrupts( || {
+    VTS[1].lock().write_string("\ntty2");
+    WRITER.lock().write_string("\ntty1");
+  });
+  switch_to(1);
+  interrupts::without_interrupts( || {
+    assert_eq!(active_vt(), 1);
+    assert!(WRITER.lock().display.is_none());
+    assert_eq!(&shown(), b"tty2");
   });
+  switch_to(0);
+  interrupts::without_interrupts( || assert_eq!(&shown(), b"tty1"));
+}
+
+#[test_case]
+fn test_status_bar_does_not_scroll() {
+  use core::fmt::Write;
+  use x86_64::instructions::interrupts;
+  interrupts::without_interrupts( || {
+    let mut writer = WRITER.lock();
+    writer.write_at(STATUS_ROW, 0, "status");
+    for i in 0..BUFFER_HEIGHT {
+      writeln!(writer, "line {}", i).unwrap();
+    }
+    assert_eq!(writer.screen.rows()[STATUS_ROW][0].ascii_char, b's');
+    // The last line printed is right above the (empty) bottom row
+    assert_eq!(writer.screen.rows()[BUFFER_HEIGHT - 2][5].ascii_char, b'2');
+    assert_eq!(writer.screen.rows()[BUFFER_HEIGHT - 2][6].ascii_char, b'4');
+  });
+}
+
+#[test_case]
+fn test_window_wraps_and_scrolls_inside_its_rectangle() {
+  use x86_64::instructions::interrupts;
+  // tty4, which the other tests leave alone
+  let mut window = Window::new(3, 10, 20, 2, 4);
+  window.clear();
+  interrupts::without_interrupts( || VTS[3].lock().write_at(10, 24, "|"));
+  // Wraps after 4 columns and scrolls after 2 rows: "abcd" scrolls out
+  window.write_string("abcdefgh\nij");
+  interrupts::without_interrupts( || {
+    let writer = VTS[3].lock();
+    let text = |row: usize| {
+      let mut text = [0u8; 5];
+      for (i, c) in text.iter_mut().enumerate() {
+        *c = writer.screen.rows()[row][20 + i].ascii_char;
+      }
+      text
+    };
+    // Column 24 is outside the window
+    assert_eq!(&text(10), b"efgh|");
+    assert_eq!(&text(11), b"ij   ");
+  });
+}
+
+#[test_case]
+fn test_utf8_is_shown_in_code_page_437() {
+  use x86_64::instructions::interrupts;
+  assert_eq!(CP437_HIGH.chars().count(), 128);
+  assert_eq!(to_cp437('é'), 0x82);
+  assert_eq!(to_cp437('─'), 0xc4);
+  assert_eq!(to_cp437('°'), 0xf8);
+  // Both micro sign and Greek mu
+  assert_eq!(to_cp437('µ'), 0xe6);
+  assert_eq!(to_cp437('μ'), 0xe6);
+  assert_eq!(to_cp437('€'), PLACEHOLDER);
+  interrupts::without_interrupts( || {
+    let mut writer = WRITER.lock();
+    // One cell per character, however many bytes it takes
+    assert_eq!(writer.write_at(5, 0, "25°C €"), 6);
+    assert_eq!(writer.screen.rows()[5][2].ascii_char, 0xf8);
+    assert_eq!(writer.screen.rows()[5][5].ascii_char, PLACEHOLDER);
+  });
+}
+
+#[test_case]
+fn test_writer_wraps_and_scrolls_in_memory() {
+  let mut writer = Writer::new(MemoryBuffer::new());
+  writer.write_at(STATUS_ROW, 0, "status");
+  // A full row, then one more character wraps onto a new one
+  for _ in 0..BUFFER_WIDTH {
+    writer.write_byte(b'a');
+  }
+  writer.write_string("é");
+  assert_eq!(writer.read_char_at(BUFFER_HEIGHT - 2, BUFFER_WIDTH - 1), 'a');
+  assert_eq!(writer.read_char_at(BUFFER_HEIGHT - 1, 0), 'é');
+  assert_eq!(writer.cursor(), (BUFFER_HEIGHT - 1, 1));
+  // Enough new lines to scroll the row of a's off the top, but never the status bar
+  for _ in SCROLL_TOP..BUFFER_HEIGHT - 1 {
+    writer.write_byte(b'\n');
+  }
+  assert_eq!(writer.read_char_at(SCROLL_TOP, 0), 'é');
+  assert_eq!(writer.read_char_at(STATUS_ROW, 0), 's');
+}
+
+#[test_case]
+fn test_blink_bit() {
+  let mut writer = Writer::new(MemoryBuffer::new());
+  writer.set_color(Color::Red, Color::Black);
+  writer.set_blink(true);
+  writer.write_byte(b'!');
+  writer.set_blink(false);
+  writer.write_byte(b'.');
+  assert_eq!(writer.screen.rows()[BUFFER_HEIGHT - 1][0].color_code, ColorCode(0x84));
+  assert_eq!(writer.screen.rows()[BUFFER_HEIGHT - 1][1].color_code, ColorCode(0x04));
+  // Turning blinking off in the attribute controller sticks, and can be undone
+  let blink_bit = || update_attribute_mode(|mode| mode) & MODE_CONTROL_BLINK;
+  set_blink_enabled(false);
+  assert_eq!(blink_bit(), 0);
+  set_blink_enabled(true);
+  assert_eq!(blink_bit(), MODE_CONTROL_BLINK);
+  assert!(blink_enabled());
+}
+
+#[test_case]
+fn test_tab_carriage_return_and_backspace() {
+  let mut writer = Writer::new(MemoryBuffer::new());
+  let row = BUFFER_HEIGHT - 1;
+  writer.write_string("ab\tc\td");
+  assert_eq!(writer.read_char_at(row, 8), 'c');
+  assert_eq!(writer.read_char_at(row, 16), 'd');
+  writer.write_string("\rX");
+  assert_eq!(writer.read_char_at(row, 0), 'X');
+  assert_eq!(writer.read_char_at(row, 1), 'b');
+  writer.write_string("\x08");
+  assert_eq!(writer.cursor(), (row, 0));
+  assert_eq!(writer.read_char_at(row, 0), ' ');
+  // A tab from the last stop goes to the end of the row, and the next character wraps
+  writer.set_cursor(row, BUFFER_WIDTH - 3);
+  writer.write_string("\ty");
+  assert_eq!(writer.read_char_at(row, 0), 'y');
+  assert_eq!(writer.read_char_at(row - 1, 8), 'c');
 }
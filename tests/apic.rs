@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use rust_os::{acpi, interrupts, memory, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // Needs the timer feature, so the test can watch it tick through the IOAPIC
+    rust_os::init();
+    memory::init(boot_info);
+    interrupts::apic::init().expect("APIC initialization failed");
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[test_case]
+fn test_madt_describes_the_interrupt_controllers() {
+    let madt = acpi::madt::madt().expect("no MADT");
+    assert!(madt.local_apics().count() >= 1);
+    assert!(madt.io_apics().count() >= 1);
+}
+
+#[test_case]
+fn test_timer_ticks_through_the_io_apic() {
+    assert!(interrupts::apic::is_enabled());
+    let start = time::ticks();
+    while time::ticks() < start + 2 {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_x2apic_is_used_when_supported() {
+    assert_eq!(interrupts::apic::is_x2apic(), interrupts::apic::supports_x2apic());
+}
+
+#[test_case]
+fn test_msi_message_targets_the_allocated_vector() {
+    fn handler() {}
+    let msi = interrupts::msi::allocate_msi(handler).expect("allocate_msi failed");
+    let message = msi.message();
+    assert_eq!(message.data, msi.vector() as u32);
+    assert_eq!(message.address & 0xfff0_0000, 0xfee0_0000);
+}
+
+#[test_case]
+fn test_apic_timer_takes_over_the_tick() {
+    let mode = interrupts::apic::timer::start(1000).expect("APIC timer failed to start");
+    assert_eq!(interrupts::apic::timer::mode(), Some(mode));
+    let start = time::ticks();
+    while time::ticks() < start + 5 {
+        x86_64::instructions::hlt();
+    }
+    interrupts::apic::timer::stop();
+    assert_eq!(interrupts::apic::timer::mode(), None);
+    // The PIT is back in charge
+    let start = time::ticks();
+    while time::ticks() < start + 2 {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_reset_for_test_restores_the_tick() {
+    // Leave a mess: the tick on the APIC timer at another rate, then every line masked with interrupts off
+    interrupts::apic::timer::start(500).expect("APIC timer failed to start");
+    x86_64::instructions::interrupts::disable();
+    for irq in 0..16 {
+        interrupts::mask(irq);
+    }
+    interrupts::reset_for_test();
+    assert!(x86_64::instructions::interrupts::are_enabled());
+    assert_eq!(interrupts::apic::timer::mode(), None);
+    // The PIT can't hit 100 Hz exactly
+    assert_eq!((time::frequency_millihz() + 500) / 1000, time::DEFAULT_FREQUENCY_HZ as u64);
+    let start = time::ticks();
+    while time::ticks() < start + 2 {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -26,6 +26,20 @@ fn test_println_500x() -> () {
     });
 }
 
+#[test_case]
+fn test_snapshot_after_scrolling() {
+    for i in 0..100 {
+        println!("Test snapshot {}", i);
+    }
+    // The last lines printed are at the bottom of the screen, above the empty row output continues on
+    let screen = rust_os::vga_buffer::snapshot();
+    let bottom = screen.len() - 1;
+    let shows = |row: usize, text: &str| screen[row].iter().zip(text.chars()).all(|(&on_screen, c)| on_screen == c);
+    assert!(shows(bottom - 2, "Test snapshot 98"));
+    assert!(shows(bottom - 1, "Test snapshot 99"));
+    assert!(screen[bottom].iter().all(|&c| c == ' '));
+}
+
 #[no_mangle] // Don't mangle the entrypoint of this integration test
 pub extern "C" fn _start() -> ! {
     test_main();
@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use rust_os::allocator::{self, HEAP_SIZE};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    rust_os::memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[test_case]
+fn simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+// Only works if freed memory is reused
+#[test_case]
+fn many_boxes() {
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+#[test_case]
+fn defragment_merges_adjacent_blocks() {
+    let boxes: Vec<Box<[u8; 64]>> = (0..32).map(|_| Box::new([0; 64])).collect();
+    drop(boxes);
+    let (before, after) = allocator::defragment();
+    assert_eq!(before.free_bytes, after.free_bytes);
+    // Everything is free again, so it must all be one block
+    assert_eq!(after.free_blocks, 1);
+    assert_eq!(after.fragmentation_percent(), 0);
+}
+
+#[test_case]
+fn heap_grows_on_demand() {
+    let size_before = allocator::heap_size();
+    let big: Vec<u8> = alloc::vec![7; HEAP_SIZE * 2];
+    assert!(allocator::heap_size() > size_before);
+    assert!(big.iter().all(|&b| b == 7));
+}
+
+#[test_case]
+fn heap_growth_stops_at_limit() {
+    allocator::set_heap_limit(allocator::heap_size());
+    assert!(matches!(allocator::grow_heap(4096), Err(allocator::GrowError::LimitReached)));
+    allocator::set_heap_limit(allocator::HEAP_MAX_SIZE);
+}
+
+// Runs last, after every other test has dropped what it allocated
+#[cfg(feature = "leak-detect")]
+#[test_case]
+fn no_leaks() {
+    assert!(allocator::report_leaks().is_balanced());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
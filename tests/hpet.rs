@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use bootloader::{BootInfo, entry_point};
+use rust_os::{interrupts, memory, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    // One-shot timers are delivered through the IOAPIC; the PIT is masked (even if the timer feature brought it up)
+    // so it can't wake the test instead
+    interrupts::mask(0);
+    memory::init(boot_info);
+    interrupts::apic::init().expect("APIC initialization failed");
+    time::hpet::init().expect("HPET initialization failed");
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[test_case]
+fn test_hpet_clock_advances() {
+    let start = time::hpet::nanos().expect("no HPET");
+    while time::hpet::nanos().unwrap() == start {}
+    assert!(time::monotonic_nanos() > start);
+}
+
+#[test_case]
+fn test_oneshot_fires_once() {
+    static FIRED: AtomicBool = AtomicBool::new(false);
+    fn handler() {
+        FIRED.store(true, Ordering::SeqCst);
+    }
+    let start = time::hpet::nanos().unwrap();
+    let oneshot = time::hpet::start_oneshot(1_000_000, handler).expect("start_oneshot failed");
+    // Spin rather than hlt: with the PIT masked, an interrupt that fired just before the hlt would leave us asleep
+    while !FIRED.load(Ordering::SeqCst) {}
+    assert!(time::hpet::nanos().unwrap() - start >= 1_000_000);
+    // Only one comparator is used for one-shots
+    assert!(matches!(time::hpet::start_oneshot(1, handler), Err(time::hpet::HpetError::Busy)));
+    drop(oneshot);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
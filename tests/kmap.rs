@@ -0,0 +1,98 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use rust_os::memory::{self, KmapError};
+use x86_64::PhysAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    memory::init(boot_info);
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[test_case]
+fn test_kmap_anonymous_memory_is_zeroed_and_writable() {
+    let len = 3 * 4096;
+    let start = memory::kmap(len, PageTableFlags::WRITABLE).expect("kmap failed");
+    let words = unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr::<u64>(), len as usize / 8) };
+    assert!(words.iter().all(|&w| w == 0));
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = i as u64;
+    }
+    assert_eq!(words[words.len() - 1], words.len() as u64 - 1);
+    memory::kunmap(start).expect("kunmap failed");
+    assert!(matches!(memory::kunmap(start), Err(KmapError::NotMapped)));
+}
+
+#[test_case]
+fn test_kmap_phys_maps_the_vga_buffer() {
+    // The VGA buffer is identity mapped too, so both views must show the same character
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    let vga = memory::kmap_phys(PhysAddr::new(0xb8000 + 2), 2, flags).expect("kmap_phys failed");
+    let identity = 0xb8002 as *const u16;
+    unsafe { assert_eq!(core::ptr::read_volatile(vga.as_ptr::<u16>()), core::ptr::read_volatile(identity)) };
+    memory::kunmap(vga).expect("kunmap failed");
+}
+
+#[test_case]
+fn test_map_mmio_reads_and_writes_through() {
+    // Use the last cell of the VGA buffer's first row as a stand-in register
+    let mut vga = memory::map_mmio::<u16>(PhysAddr::new(0xb8000), 160).expect("map_mmio failed");
+    let old = vga.read(158);
+    vga.write(158, 0x0f41);
+    assert_eq!(unsafe { core::ptr::read_volatile(0xb809e as *const u16) }, 0x0f41);
+    vga.update(158, |_| old);
+    assert_eq!(vga.read(158), old);
+}
+
+#[test_case]
+fn test_alloc_dma_is_contiguous_and_aligned() {
+    let mut buffer = memory::alloc_dma(3 * 4096, 16 * 4096).expect("alloc_dma failed");
+    assert_eq!(buffer.phys_addr().as_u64() % (16 * 4096), 0);
+    buffer.as_mut_slice()[3 * 4096 - 1] = 0xAB;
+    // The last byte must live exactly len - 1 bytes after the physical start
+    let last = buffer.virt_addr() + (3 * 4096 - 1u64);
+    assert_eq!(memory::translate_addr(last), Some(buffer.phys_addr() + (3 * 4096 - 1u64)));
+}
+
+#[test_case]
+fn test_kernel_stack_has_guard_page() {
+    let stack = memory::alloc_kernel_stack(2).expect("alloc_kernel_stack failed");
+    assert_eq!(stack.top() - stack.bottom(), 2 * 4096);
+    assert!(memory::translate_addr(stack.top() - 1u64).is_some());
+    assert!(memory::translate_addr(stack.bottom()).is_some());
+    assert_eq!(memory::translate_addr(stack.guard_page().start_address()), None);
+    unsafe { memory::free_kernel_stack(stack).expect("free_kernel_stack failed") };
+    assert_eq!(memory::translate_addr(stack.bottom()), None);
+}
+
+#[test_case]
+fn test_fault_stacks_replaced_by_guarded_stacks() {
+    use rust_os::gdt;
+    use rust_os::memory::RegionKind;
+    let (early, early_size) = gdt::early_fault_stack();
+    for &index in &[gdt::PAGE_FAULT_IST_INDEX, gdt::GENERAL_PROTECTION_FAULT_IST_INDEX] {
+        let top = gdt::ist_stack_top(index);
+        assert_ne!(top, early + early_size);
+        let vmm = memory::VMM.lock();
+        let region = vmm.find(top - 1u64).expect("fault stack isn't a registered region");
+        assert_eq!(region.kind, RegionKind::Stack);
+    }
+    // Each fault has its own stack
+    let page_fault = gdt::ist_stack_top(gdt::PAGE_FAULT_IST_INDEX);
+    assert_ne!(page_fault, gdt::ist_stack_top(gdt::GENERAL_PROTECTION_FAULT_IST_INDEX));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use rust_os::memory;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{Mapper, Page, Size4KiB};
+
+entry_point!(main);
+
+// Built with --no-default-features, a paging-only kernel: no timer or keyboard interrupts
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    memory::init(boot_info);
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[test_case]
+fn test_vga_buffer_is_identity_mapped() {
+    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(0xb8000));
+    let frame = memory::MAPPER.lock().as_ref().unwrap().translate_page(page).expect("VGA buffer not mapped");
+    assert_eq!(frame.start_address().as_u64(), 0xb8000);
+}
+
+#[test_case]
+fn test_translate_identity_mapping() {
+    assert_eq!(memory::translate_addr(VirtAddr::new(0xb8123)), Some(PhysAddr::new(0xb8123)));
+}
+
+#[test_case]
+fn test_translate_physical_memory_window() {
+    // The bootloader maps all of physical memory at an offset, so this address is not identity mapped
+    let virt = memory::phys_to_virt(PhysAddr::new(0xb8123));
+    assert_ne!(virt.as_u64(), 0xb8123);
+    assert_eq!(memory::translate_addr(virt), Some(PhysAddr::new(0xb8123)));
+}
+
+#[test_case]
+fn test_translate_unmapped_address() {
+    assert_eq!(memory::translate_addr(VirtAddr::new(0xdead_beef_000)), None);
+}
+
+#[test_case]
+fn test_address_space_user_region() {
+    use memory::AddressSpace;
+    use x86_64::structures::paging::PageTableFlags;
+    // L4 slot 224, which the bootloader leaves empty
+    let start = VirtAddr::new(0x7000_0000_0000);
+    let mut space = AddressSpace::new().expect("creating address space failed");
+    space.map_user_region("test", start, 2 * 4096, PageTableFlags::WRITABLE).expect("mapping failed");
+    // Not visible in the kernel's address space...
+    assert_eq!(memory::translate_addr(start), None);
+    // ...but usable after switching, with the kernel (this code, the stack, VGA) still mapped
+    space.activate();
+    unsafe {
+        assert_eq!(*start.as_ptr::<u64>(), 0);
+        *start.as_mut_ptr::<u64>() = 42;
+        assert_eq!(*start.as_ptr::<u64>(), 42);
+    }
+    AddressSpace::activate_kernel();
+    assert!(!space.is_active());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
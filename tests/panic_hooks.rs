@@ -0,0 +1,40 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rust_os::{QemuExitCode, exit_qemu, serial_print, serial_println};
+
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn first_hook(_info: &PanicInfo) {
+    // Hooks must run in registration order
+    assert_eq!(CALLS.fetch_add(1, Ordering::SeqCst), 0);
+}
+
+fn second_hook(_info: &PanicInfo) {
+    CALLS.fetch_add(1, Ordering::SeqCst);
+    // The nested panic must not re-run the hooks
+    panic!("panic inside a panic hook");
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("panic_hooks::hooks_run_once_in_order...\t");
+    rust_os::panic::add_hook(first_hook).unwrap();
+    rust_os::panic::add_hook(second_hook).unwrap();
+    panic!("test panic");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::panic::run_hooks(info);
+    if CALLS.load(Ordering::SeqCst) == 2 {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        exit_qemu(QemuExitCode::Failure);
+    }
+    loop {}
+}
@@ -0,0 +1,80 @@
+#!/usr/bin/env python3
+"""Embeds checksums of the kernel's read-only segments into the linked kernel ELF.
+
+src/integrity.rs verifies the loaded image against them at boot. The table they go into (IMAGE_CHECKSUMS) sits in a
+writable segment, which isn't checksummed, so patching it doesn't invalidate what it describes. Running this again on
+an image that already has its checksums just rewrites the same values.
+
+Usage: embed_checksum.py <kernel ELF>
+"""
+import struct
+import sys
+
+# Must match EMBEDDED_MAGIC and MAX_SEGMENTS in src/integrity.rs
+EMBEDDED_MAGIC = 0x314D_5553_474D_494B
+MAX_SEGMENTS = 8
+
+PT_LOAD = 1
+PF_W = 2
+
+
+# 64-bit FNV-1a, as in src/integrity.rs
+def fnv1a(data):
+    checksum = 0xCBF2_9CE4_8422_2325
+    for byte in data:
+        checksum = ((checksum ^ byte) * 0x0000_0100_0000_01B3) & 0xFFFF_FFFF_FFFF_FFFF
+    return checksum
+
+
+def load_segments(image):
+    if image[:4] != b"\x7fELF":
+        raise ValueError("not an ELF file")
+    (phoff,) = struct.unpack_from("<Q", image, 0x20)
+    phentsize, phnum = struct.unpack_from("<HH", image, 0x36)
+    for i in range(phnum):
+        p_type, p_flags, p_offset, p_vaddr, _, p_filesz = struct.unpack_from("<IIQQQQ", image, phoff + i * phentsize)
+        if p_type == PT_LOAD and p_filesz != 0:
+            yield p_flags, p_offset, p_vaddr, p_filesz
+
+
+def find_table(image, writable):
+    magic = struct.pack("<Q", EMBEDDED_MAGIC)
+    found = []
+    for offset, size in writable:
+        position = image.find(magic, offset, offset + size)
+        while position != -1:
+            found.append(position)
+            position = image.find(magic, position + 1, offset + size)
+    if len(found) != 1:
+        raise ValueError("expected one checksum table in the writable segments, found {}".format(len(found)))
+    return found[0]
+
+
+def embed(path):
+    with open(path, "rb") as f:
+        image = bytearray(f.read())
+    read_only, writable = [], []
+    for flags, offset, vaddr, size in load_segments(image):
+        if flags & PF_W:
+            writable.append((offset, size))
+        else:
+            read_only.append((vaddr, size, fnv1a(image[offset:offset + size])))
+    if len(read_only) > MAX_SEGMENTS:
+        raise ValueError("{} read-only segments, but the table only has room for {}".format(len(read_only), MAX_SEGMENTS))
+
+    table = struct.pack("<QQ", EMBEDDED_MAGIC, len(read_only))
+    for segment in read_only + [(0, 0, 0)] * (MAX_SEGMENTS - len(read_only)):
+        table += struct.pack("<QQQ", *segment)
+    offset = find_table(image, writable)
+    image[offset:offset + len(table)] = table
+    with open(path, "wb") as f:
+        f.write(image)
+
+
+if __name__ == "__main__":
+    if len(sys.argv) != 2:
+        sys.exit("usage: embed_checksum.py <kernel ELF>")
+    try:
+        embed(sys.argv[1])
+    except ValueError as e:
+        sys.exit("embed_checksum.py: {}: {}".format(sys.argv[1], e))
@@ -0,0 +1,6 @@
+#!/bin/sh
+# Cargo's runner for the kernel and its tests: embeds the image checksums (see src/integrity.rs), then boots the
+# image through bootimage
+set -e
+python3 "$(dirname "$0")/embed_checksum.py" "$1"
+exec bootimage runner "$@"